@@ -0,0 +1,48 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/*
+Record build details for --version: the git commit being built, when there is
+one, and the resolved versions of the HTTP and TLS crates from Cargo.lock
+*/
+fn main() {
+    let commit: Option<String> = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string());
+    if let Some(commit) = commit {
+        println!("cargo:rustc-env=GIT_COMMIT={}", commit);
+    }
+
+    let lock: String = fs::read_to_string("Cargo.lock").unwrap_or_default();
+    for (package, var) in [("hyper", "HYPER_VERSION"), ("native-tls", "NATIVE_TLS_VERSION")] {
+        if let Some(version) = locked_version(&lock, package) {
+            println!("cargo:rustc-env={}={}", var, version);
+        }
+    }
+
+    // Rebuild when the checked-out commit or the dependencies change
+    println!("cargo:rerun-if-changed=Cargo.lock");
+    if Path::new(".git/HEAD").exists() {
+        println!("cargo:rerun-if-changed=.git/HEAD");
+        if let Some(reference) = fs::read_to_string(".git/HEAD").ok().and_then(|head| head.strip_prefix("ref: ").map(|r| r.trim().to_string())) {
+            println!("cargo:rerun-if-changed=.git/{}", reference);
+        }
+    }
+}
+
+/*
+Find the version Cargo.lock resolved for a package
+*/
+fn locked_version(lock: &str, package: &str) -> Option<String> {
+    let name_line: String = format!("name = \"{}\"", package);
+    let mut lines = lock.lines();
+    lines.find(|line| *line == name_line)?;
+    lines.next()?
+        .strip_prefix("version = \"")?
+        .strip_suffix('"')
+        .map(|version| version.to_string())
+}
@@ -0,0 +1,227 @@
+use bytes::Bytes;
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use crate::error::SpeedTestError;
+use std::collections::BTreeMap;
+
+// How much out-of-order data may wait to be hashed before the ranges that
+// brought it are held back
+const BUFFER_LIMIT_BYTES: u64 = 32 * 1024 * 1024;
+
+#[derive(Clone)]
+enum Hasher {
+    Sha256(Sha256),
+    Md5(Md5),
+}
+
+/*
+Incrementally hashes the file in offset order while ranges arrive out of order.
+Chunks that arrive ahead of the next expected offset are held until the gap is
+filled; once they add up to the buffer limit the downloads ahead of the hash
+are expected to wait, as `is_full` tells them.
+*/
+#[derive(Clone)]
+pub struct ChecksumVerifier {
+    algorithm: String,
+    hasher: Hasher,
+    expected: String,
+    start_offset: u64,
+    next_offset: u64,
+    pending: BTreeMap<u64, Bytes>,
+    buffered_bytes: u64,
+    peak_buffered_bytes: u64,
+    buffer_limit: u64,
+}
+
+pub enum ChecksumResult {
+    Match,
+    Mismatch { expected: String, actual: String },
+    Incomplete { hashed_bytes: u64 },
+}
+
+impl ChecksumVerifier {
+    /*
+    Parse an `ALGO:HEX` or `ALGO=HEX` specification such as `sha256:9f86d0...`
+    */
+    pub fn parse(spec: &str) -> Result<ChecksumVerifier, SpeedTestError> {
+        let (algorithm, expected) = spec.split_once([':', '='])
+            .ok_or_else(|| SpeedTestError::InvalidChecksum(format!("Invalid checksum '{}': expected ALGO:HEX", spec)))?;
+        let algorithm: String = algorithm.to_ascii_lowercase();
+        let expected: String = expected.to_ascii_lowercase();
+
+        let (hasher, hex_len): (Hasher, usize) = match algorithm.as_str() {
+            "sha256" => (Hasher::Sha256(Sha256::new()), 64),
+            "md5" => (Hasher::Md5(Md5::new()), 32),
+            _ => return Err(SpeedTestError::InvalidChecksum(format!("Unsupported checksum algorithm '{}': expected sha256 or md5", algorithm))),
+        };
+        if expected.len() != hex_len || !expected.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(SpeedTestError::InvalidChecksum(format!("Invalid {} checksum '{}': expected {} hex digits", algorithm, expected, hex_len)));
+        }
+
+        Ok(ChecksumVerifier {
+            algorithm,
+            hasher,
+            expected,
+            start_offset: 0,
+            next_offset: 0,
+            pending: BTreeMap::new(),
+            buffered_bytes: 0,
+            peak_buffered_bytes: 0,
+            buffer_limit: BUFFER_LIMIT_BYTES,
+        })
+    }
+
+    /*
+    Take the expected digest from an ETag that is the hex MD5 or SHA-256 of the
+    file, as object stores send for files uploaded in one piece
+    */
+    pub fn from_etag(etag: &str) -> Result<ChecksumVerifier, SpeedTestError> {
+        if etag.starts_with("W/") {
+            return Err(SpeedTestError::InvalidChecksum(format!("Can't verify against the weak ETag {}, which is no digest of the content", etag)));
+        }
+        let digest: &str = etag.trim_matches('"');
+        let algorithm: &str = match digest.len() {
+            32 => "md5",
+            64 => "sha256",
+            _ => "",
+        };
+        if algorithm.is_empty() || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(SpeedTestError::InvalidChecksum(format!("Can't verify against the ETag {}, which is not an MD5 or SHA-256 digest (as for a multipart upload)", etag)));
+        }
+        ChecksumVerifier::parse(&format!("{}:{}", algorithm, digest))
+    }
+
+    /*
+    Hash from the given file offset instead of the start of the file
+    */
+    pub fn starting_at(mut self, offset: u64) -> ChecksumVerifier {
+        self.start_offset = offset;
+        self.next_offset = offset;
+        self
+    }
+
+    pub fn algorithm(&self) -> &str {
+        &self.algorithm
+    }
+
+    /*
+    The file offset the hash has reached, which the next chunk hashed must start at
+    */
+    pub fn next_offset(&self) -> u64 {
+        self.next_offset
+    }
+
+    /*
+    Whether the chunks waiting to be hashed have reached the buffer limit, so
+    nothing more should be read ahead of `next_offset`
+    */
+    pub fn is_full(&self) -> bool {
+        self.buffered_bytes >= self.buffer_limit
+    }
+
+    /*
+    Feed a chunk that starts at the given file offset
+    */
+    pub fn update(&mut self, offset: u64, chunk: Bytes) {
+        if offset != self.next_offset {
+            self.buffered_bytes += chunk.len() as u64;
+            self.peak_buffered_bytes = self.peak_buffered_bytes.max(self.buffered_bytes);
+            self.pending.insert(offset, chunk);
+            return;
+        }
+
+        self.hash(chunk);
+
+        // Drain any buffered chunks that are now contiguous
+        while let Some(chunk) = self.pending.remove(&self.next_offset) {
+            self.buffered_bytes -= chunk.len() as u64;
+            self.hash(chunk);
+        }
+    }
+
+    fn hash(&mut self, chunk: Bytes) {
+        match &mut self.hasher {
+            Hasher::Sha256(h) => h.update(&chunk),
+            Hasher::Md5(h) => h.update(&chunk),
+        }
+        self.next_offset += chunk.len() as u64;
+    }
+
+    /*
+    Compare the digest against the expected value, given the number of bytes that should have been hashed
+    */
+    pub fn finish(self, total_bytes: u64) -> ChecksumResult {
+        let hashed_bytes: u64 = self.next_offset - self.start_offset;
+        if hashed_bytes != total_bytes || !self.pending.is_empty() {
+            return ChecksumResult::Incomplete { hashed_bytes };
+        }
+
+        let digest: Vec<u8> = match self.hasher {
+            Hasher::Sha256(h) => h.finalize().to_vec(),
+            Hasher::Md5(h) => h.finalize().to_vec(),
+        };
+        let actual: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+
+        if actual == self.expected {
+            ChecksumResult::Match
+        } else {
+            ChecksumResult::Mismatch { expected: self.expected, actual }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sha256(data: &[u8]) -> String {
+        Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn hashes_chunks_that_arrive_out_of_order() {
+        let data: Vec<u8> = (0..4000u32).map(|i| (i % 251) as u8).collect();
+        let mut verifier: ChecksumVerifier = ChecksumVerifier::parse(&format!("sha256:{}", sha256(&data))).unwrap();
+        // Four ranges of 1000 bytes, each in two chunks, the first range last
+        for start in [3000, 1000, 2000, 0] {
+            verifier.update(start as u64, Bytes::copy_from_slice(&data[start..start + 400]));
+            verifier.update(start as u64 + 400, Bytes::copy_from_slice(&data[start + 400..start + 1000]));
+        }
+        assert_eq!(verifier.peak_buffered_bytes, 3000);
+        assert_eq!(verifier.buffered_bytes, 0);
+        assert!(matches!(verifier.finish(4000), ChecksumResult::Match));
+    }
+
+    #[test]
+    fn is_full_at_the_buffer_limit() {
+        let mut verifier: ChecksumVerifier = ChecksumVerifier::parse(&format!("sha256:{}", sha256(b"abcdef"))).unwrap();
+        verifier.buffer_limit = 4;
+        verifier.update(2, Bytes::from_static(b"cd"));
+        assert!(!verifier.is_full());
+        verifier.update(4, Bytes::from_static(b"ef"));
+        assert!(verifier.is_full());
+        // The gap filled, the buffer drains
+        verifier.update(0, Bytes::from_static(b"ab"));
+        assert!(!verifier.is_full());
+        assert_eq!((verifier.next_offset(), verifier.peak_buffered_bytes), (6, 4));
+        assert!(matches!(verifier.finish(6), ChecksumResult::Match));
+    }
+
+    #[test]
+    fn reports_a_gap_as_incomplete() {
+        let mut verifier: ChecksumVerifier = ChecksumVerifier::parse(&format!("md5:{}", "0".repeat(32))).unwrap().starting_at(10);
+        verifier.update(10, Bytes::from_static(b"ab"));
+        verifier.update(14, Bytes::from_static(b"ef"));
+        assert!(matches!(verifier.finish(6), ChecksumResult::Incomplete { hashed_bytes: 2 }));
+    }
+
+    #[test]
+    fn rejects_what_is_no_digest() {
+        for spec in ["sha256", "crc32:00000000", "md5:abc", &format!("sha256:{}", "g".repeat(64))] {
+            assert!(matches!(ChecksumVerifier::parse(spec), Err(SpeedTestError::InvalidChecksum(_))), "{} was accepted", spec);
+        }
+        for etag in ["W/\"abc\"", "\"0123456789abcdef0123456789abcdef-2\""] {
+            assert!(matches!(ChecksumVerifier::from_etag(etag), Err(SpeedTestError::InvalidChecksum(_))), "{} was accepted", etag);
+        }
+    }
+}
@@ -0,0 +1,358 @@
+use std::path::{Path, PathBuf};
+
+/*
+A value in a --config file: TOML strings, numbers and dates are all passed on
+as the text of a command-line argument, so only booleans and arrays are told apart
+*/
+#[derive(Debug, PartialEq)]
+pub enum Value {
+    Text(String),
+    Bool(bool),
+    Array(Vec<Value>),
+}
+
+/*
+One `key = value` line of a --config file, with the [table] it is in, if any
+*/
+pub struct Setting {
+    pub table: Option<String>,
+    pub key: String,
+    pub value: Value,
+    pub line: usize,
+}
+
+/*
+Where the config file is read from when --config isn't given:
+$XDG_CONFIG_HOME/httpbandwidthspeedtester/config.toml, or under ~/.config
+*/
+pub fn default_path() -> Option<PathBuf> {
+    let base: PathBuf = match std::env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join(env!("CARGO_PKG_NAME")).join("config.toml"))
+}
+
+/*
+Read the settings of a config file, written in the subset of TOML a flat set of
+options needs: tables of keys set to strings, numbers, booleans, dates or arrays
+of them, with comments anywhere a line may end
+*/
+pub fn read(path: &Path) -> Result<Vec<Setting>, String> {
+    let text: String = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read config file {}: {}", path.display(), e))?;
+    Parser { chars: text.chars().collect(), pos: 0, line: 1 }.settings()
+        .map_err(|(line, reason)| format!("Invalid config file {} at line {}: {}", path.display(), line, reason))
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+}
+
+type ParseResult<T> = Result<T, (usize, String)>;
+
+impl Parser {
+    fn settings(&mut self) -> ParseResult<Vec<Setting>> {
+        let mut settings: Vec<Setting> = Vec::new();
+        let mut table: Option<String> = None;
+        loop {
+            self.skip_blank(true);
+            match self.peek() {
+                None => return Ok(settings),
+                Some('[') => {
+                    self.pos += 1;
+                    self.skip_blank(false);
+                    let name: String = self.key()?;
+                    self.skip_blank(false);
+                    self.expect(']')?;
+                    table = Some(name);
+                }
+                Some(_) => {
+                    let line: usize = self.line;
+                    let key: String = self.key()?;
+                    self.skip_blank(false);
+                    self.expect('=')?;
+                    self.skip_blank(false);
+                    let value: Value = self.value()?;
+                    settings.push(Setting { table: table.clone(), key, value, line });
+                }
+            }
+            self.end_of_line()?;
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn fail<T>(&self, reason: impl Into<String>) -> ParseResult<T> {
+        Err((self.line, reason.into()))
+    }
+
+    fn expect(&mut self, wanted: char) -> ParseResult<()> {
+        match self.peek() {
+            Some(c) if c == wanted => {
+                self.pos += 1;
+                Ok(())
+            }
+            Some(c) => self.fail(format!("expected '{}' but found '{}'", wanted, c)),
+            None => self.fail(format!("expected '{}' but the file ended", wanted)),
+        }
+    }
+
+    /*
+    Skip spaces and comments, and line breaks too when `newlines` is set
+    */
+    fn skip_blank(&mut self, newlines: bool) {
+        while let Some(c) = self.peek() {
+            match c {
+                ' ' | '\t' | '\r' => self.pos += 1,
+                '\n' if newlines => {
+                    self.pos += 1;
+                    self.line += 1;
+                }
+                '#' => {
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.pos += 1;
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn end_of_line(&mut self) -> ParseResult<()> {
+        self.skip_blank(false);
+        match self.peek() {
+            None | Some('\n') => Ok(()),
+            Some(c) => self.fail(format!("unexpected '{}' after the value", c)),
+        }
+    }
+
+    fn key(&mut self) -> ParseResult<String> {
+        match self.peek() {
+            Some('"') => self.basic_string(),
+            Some('\'') => self.literal_string(),
+            _ => {
+                let key: String = self.take_while(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+                if key.is_empty() {
+                    return self.fail("expected a key");
+                }
+                Ok(key)
+            }
+        }
+    }
+
+    fn value(&mut self) -> ParseResult<Value> {
+        match self.peek() {
+            Some('"') => Ok(Value::Text(self.basic_string()?)),
+            Some('\'') => Ok(Value::Text(self.literal_string()?)),
+            Some('[') => self.array(),
+            Some('{') => self.fail("inline tables are not supported; use a [table] instead"),
+            _ => {
+                let token: String = self.take_while(|c| c.is_ascii_alphanumeric() || "_+-.:".contains(c));
+                match token.as_str() {
+                    "" => self.fail("expected a value"),
+                    "true" => Ok(Value::Bool(true)),
+                    "false" => Ok(Value::Bool(false)),
+                    // Digits may be grouped with underscores, as in 100_000_000
+                    _ if token.starts_with(|c: char| c.is_ascii_digit() || c == '+' || c == '-') => Ok(Value::Text(token.replace('_', ""))),
+                    _ => self.fail(format!("'{}' is not a value; quote strings", token)),
+                }
+            }
+        }
+    }
+
+    fn array(&mut self) -> ParseResult<Value> {
+        self.expect('[')?;
+        let mut values: Vec<Value> = Vec::new();
+        loop {
+            self.skip_blank(true);
+            if self.peek() == Some(']') {
+                self.pos += 1;
+                return Ok(Value::Array(values));
+            }
+            values.push(self.value()?);
+            self.skip_blank(true);
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some(']') => {}
+                _ => return self.fail("expected ',' or ']' in the array"),
+            }
+        }
+    }
+
+    fn basic_string(&mut self) -> ParseResult<String> {
+        self.expect('"')?;
+        if self.peek() == Some('"') && self.chars.get(self.pos + 1) == Some(&'"') {
+            return self.fail("multi-line strings are not supported");
+        }
+        let mut text: String = String::new();
+        loop {
+            let Some(c) = self.peek() else {
+                return self.fail("the string is never closed");
+            };
+            self.pos += 1;
+            match c {
+                '"' => return Ok(text),
+                '\n' => return self.fail("the string is never closed"),
+                '\\' => {
+                    let Some(escaped) = self.peek() else {
+                        return self.fail("the string is never closed");
+                    };
+                    self.pos += 1;
+                    match escaped {
+                        '"' | '\\' => text.push(escaped),
+                        'n' => text.push('\n'),
+                        't' => text.push('\t'),
+                        'r' => text.push('\r'),
+                        'u' | 'U' => {
+                            let digits: usize = if escaped == 'u' { 4 } else { 8 };
+                            let hex: String = self.chars.iter().skip(self.pos).take(digits).collect();
+                            let decoded: Option<char> = u32::from_str_radix(&hex, 16).ok().filter(|_| hex.len() == digits).and_then(char::from_u32);
+                            let Some(decoded) = decoded else {
+                                return self.fail(format!("invalid escape \\{}{}", escaped, hex));
+                            };
+                            text.push(decoded);
+                            self.pos += digits;
+                        }
+                        other => return self.fail(format!("invalid escape \\{}", other)),
+                    }
+                }
+                _ => text.push(c),
+            }
+        }
+    }
+
+    fn literal_string(&mut self) -> ParseResult<String> {
+        self.expect('\'')?;
+        let text: String = self.take_while(|c| c != '\'' && c != '\n');
+        self.expect('\'').or_else(|_| self.fail("the string is never closed"))?;
+        Ok(text)
+    }
+
+    fn take_while(&mut self, keep: impl Fn(char) -> bool) -> String {
+        let start: usize = self.pos;
+        while self.peek().is_some_and(&keep) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> ParseResult<Vec<Setting>> {
+        Parser { chars: text.chars().collect(), pos: 0, line: 1 }.settings()
+    }
+
+    /*
+    The one value a line of TOML sets
+    */
+    fn value(text: &str) -> Value {
+        let mut settings: Vec<Setting> = parse(&format!("key = {}", text)).unwrap_or_else(|e| panic!("{} failed: {:?}", text, e));
+        assert_eq!(settings.len(), 1);
+        settings.remove(0).value
+    }
+
+    fn error(text: &str) -> (usize, String) {
+        match parse(text) {
+            Ok(_) => panic!("{:?} parsed", text),
+            Err(error) => error,
+        }
+    }
+
+    fn text(value: &str) -> Value {
+        Value::Text(value.to_string())
+    }
+
+    #[test]
+    fn reads_every_kind_of_value() {
+        assert_eq!(value("\"a \\\"quoted\\\" \\\\ path\""), text("a \"quoted\" \\ path"));
+        assert_eq!(value("\"tab\\tnew\\nreturn\\r\""), text("tab\tnew\nreturn\r"));
+        assert_eq!(value("\"\\u00e9\\U0001F600\""), text("é😀"));
+        assert_eq!(value("'C:\\no\\escapes'"), text("C:\\no\\escapes"));
+        assert_eq!(value("100_000_000"), text("100000000"));
+        assert_eq!(value("-1.5"), text("-1.5"));
+        assert_eq!(value("+8"), text("+8"));
+        assert_eq!(value("2024-05-01T12:00:00"), text("2024-05-01T12:00:00"));
+        assert_eq!(value("true"), Value::Bool(true));
+        assert_eq!(value("false"), Value::Bool(false));
+        assert_eq!(value("[]"), Value::Array(Vec::new()));
+        assert_eq!(value("[1, \"two\", [true]]"), Value::Array(vec![text("1"), text("two"), Value::Array(vec![Value::Bool(true)])]));
+    }
+
+    #[test]
+    fn reads_arrays_over_several_lines() {
+        let settings: Vec<Setting> = parse("list = [\n  \"a\", # the first\n  \"b\",\n]\nafter = 1\n").unwrap();
+        assert_eq!(settings[0].value, Value::Array(vec![text("a"), text("b")]));
+        assert_eq!((settings[1].key.as_str(), settings[1].line), ("after", 5));
+    }
+
+    #[test]
+    fn keeps_the_table_and_line_of_each_setting() {
+        let settings: Vec<Setting> = parse("# a comment\nconnections = 4 # trailing\n\n[headers]\n\"X-Quoted\" = 'a'\n[ 'spaced' ]\nbare-key_1 = 2\n").unwrap();
+        let placed: Vec<(Option<&str>, &str, usize)> = settings.iter().map(|s| (s.table.as_deref(), s.key.as_str(), s.line)).collect();
+        assert_eq!(placed, [(None, "connections", 2), (Some("headers"), "X-Quoted", 5), (Some("spaced"), "bare-key_1", 7)]);
+    }
+
+    #[test]
+    fn reads_an_empty_file() {
+        assert!(parse("").unwrap().is_empty());
+        assert!(parse("\n# nothing\n\n").unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_malformed_keys_and_tables() {
+        assert_eq!(error("= 1"), (1, "expected a key".to_string()));
+        assert_eq!(error("[]"), (1, "expected a key".to_string()));
+        assert_eq!(error("[table"), (1, "expected ']' but the file ended".to_string()));
+        assert_eq!(error("[table x]"), (1, "expected ']' but found 'x'".to_string()));
+        assert_eq!(error("key 1"), (1, "expected '=' but found '1'".to_string()));
+        assert_eq!(error("key"), (1, "expected '=' but the file ended".to_string()));
+    }
+
+    #[test]
+    fn rejects_malformed_values() {
+        assert_eq!(error("key ="), (1, "expected a value".to_string()));
+        assert_eq!(error("key = {a = 1}"), (1, "inline tables are not supported; use a [table] instead".to_string()));
+        assert_eq!(error("key = yes"), (1, "'yes' is not a value; quote strings".to_string()));
+        assert_eq!(error("key = 1 2"), (1, "unexpected '2' after the value".to_string()));
+        assert_eq!(error("key = [1 2]"), (1, "expected ',' or ']' in the array".to_string()));
+        assert_eq!(error("key = [1,"), (1, "expected a value".to_string()));
+    }
+
+    #[test]
+    fn rejects_malformed_strings() {
+        assert_eq!(error("key = \"\"\"multi\"\"\""), (1, "multi-line strings are not supported".to_string()));
+        assert_eq!(error("key = \"open"), (1, "the string is never closed".to_string()));
+        assert_eq!(error("key = \"open\nnext = 1"), (1, "the string is never closed".to_string()));
+        assert_eq!(error("key = \"ends in \\"), (1, "the string is never closed".to_string()));
+        assert_eq!(error("key = \"\\x\""), (1, "invalid escape \\x".to_string()));
+        assert_eq!(error("key = \"\\u12\""), (1, "invalid escape \\u12\"".to_string()));
+        assert_eq!(error("key = \"\\uD800\""), (1, "invalid escape \\uD800".to_string()));
+        assert_eq!(error("key = 'open"), (1, "the string is never closed".to_string()));
+    }
+
+    #[test]
+    fn names_the_file_an_error_is_in() {
+        let path: PathBuf = std::env::temp_dir().join(format!("httpbandwidthspeedtester-config-{}.toml", std::process::id()));
+        std::fs::write(&path, "a = 1\nb = nope\n").unwrap();
+        let error: String = read(&path).err().expect("a bad file was read");
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(error, format!("Invalid config file {} at line 2: 'nope' is not a value; quote strings", path.display()));
+        let missing: String = read(&path).err().expect("a missing file was read");
+        assert!(missing.starts_with(&format!("Failed to read config file {}: ", path.display())), "unexpected error: {}", missing);
+    }
+
+    #[test]
+    fn reports_the_line_of_an_error() {
+        assert_eq!(error("a = 1\n\n# comment\nb = nope\n").0, 4);
+        assert_eq!(error("list = [\n1,\n2 3]\n").0, 3);
+        assert_eq!(error("[t]\nkey = 'x' y\n").0, 2);
+    }
+}
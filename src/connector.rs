@@ -0,0 +1,218 @@
+use crate::dns::Resolver;
+use crate::error::SpeedTestError;
+use crate::proxy::Proxy;
+use hyper::client::connect::{Connected, Connection};
+use hyper::client::HttpConnector;
+use hyper::service::Service;
+use hyper::Uri;
+use std::error::Error;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+#[cfg(target_os = "linux")]
+use hyper::client::connect::dns::Name;
+#[cfg(target_os = "linux")]
+use std::net::{IpAddr, SocketAddr};
+#[cfg(target_os = "linux")]
+use std::str::FromStr;
+#[cfg(target_os = "linux")]
+use tokio::net::TcpSocket;
+#[cfg(unix)]
+use std::path::PathBuf;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+
+/*
+The transport under TLS: TCP to the host in the URL, TCP through a --proxy, or
+a Unix domain socket that every request is sent over whatever the URL says
+*/
+#[derive(Clone)]
+pub enum Connector {
+    Tcp(Dialer),
+    Proxy(Dialer, Arc<Proxy>, Resolver),
+    #[cfg(unix)]
+    Unix(Arc<PathBuf>),
+}
+
+/*
+Opens the TCP connections, with hyper's connector or, for --interface, with
+sockets bound to that network device, which hyper's connector can't do
+*/
+#[derive(Clone)]
+pub enum Dialer {
+    Http(HttpConnector<Resolver>),
+    #[cfg(target_os = "linux")]
+    Device { interface: Arc<str>, local_address: Option<IpAddr>, resolver: Resolver },
+}
+
+pub enum Stream {
+    Tcp(TcpStream),
+    // A connection to an HTTP proxy that is sent the requests themselves
+    Forwarded(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+// The errors stay boxed, as hyper's connector hands them on, so those of its
+// own HttpConnector pass through unchanged
+impl Service<Uri> for Connector {
+    type Response = Stream;
+    type Error = Box<dyn Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        match self {
+            Connector::Tcp(dialer) | Connector::Proxy(dialer, _, _) => dialer.poll_ready(cx),
+            #[cfg(unix)]
+            Connector::Unix(_) => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        match self {
+            Connector::Tcp(dialer) => {
+                let connecting = dialer.call(uri);
+                Box::pin(async move { Ok(Stream::Tcp(connecting.await?)) })
+            }
+            Connector::Proxy(dialer, proxy, resolver) => {
+                let connecting = dialer.call(proxy.uri());
+                let (proxy, resolver): (Arc<Proxy>, Resolver) = (proxy.clone(), resolver.clone());
+                Box::pin(async move {
+                    let mut stream: TcpStream = connecting.await
+                        .map_err(|e| SpeedTestError::Unreachable { target: format!("proxy {}", proxy.describe()), reason: e.to_string() })?;
+                    if proxy.forwards(&uri) {
+                        return Ok(Stream::Forwarded(stream));
+                    }
+                    proxy.tunnel(&mut stream, &uri, &resolver).await?;
+                    Ok(Stream::Tcp(stream))
+                })
+            }
+            #[cfg(unix)]
+            Connector::Unix(path) => {
+                let path: Arc<PathBuf> = path.clone();
+                Box::pin(async move {
+                    let stream: UnixStream = UnixStream::connect(path.as_path()).await
+                        .map_err(|e| SpeedTestError::Unreachable { target: format!("Unix socket {}", path.display()), reason: e.to_string() })?;
+                    Ok(Stream::Unix(stream))
+                })
+            }
+        }
+    }
+}
+
+impl Service<Uri> for Dialer {
+    type Response = TcpStream;
+    type Error = Box<dyn Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        match self {
+            Dialer::Http(http) => http.poll_ready(cx).map_err(Into::into),
+            #[cfg(target_os = "linux")]
+            Dialer::Device { .. } => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        match self {
+            Dialer::Http(http) => {
+                let connecting = http.call(uri);
+                Box::pin(async move { Ok(connecting.await?) })
+            }
+            #[cfg(target_os = "linux")]
+            Dialer::Device { interface, local_address, resolver } => {
+                let (interface, local_address, resolver): (Arc<str>, Option<IpAddr>, Resolver) = (interface.clone(), *local_address, resolver.clone());
+                Box::pin(async move { Ok(connect_from_device(&uri, &interface, local_address, resolver).await?) })
+            }
+        }
+    }
+}
+
+/*
+Connect to the URL's host over a socket bound to the interface with
+SO_BINDTODEVICE, trying each of its addresses in turn like hyper's connector
+*/
+#[cfg(target_os = "linux")]
+async fn connect_from_device(uri: &Uri, interface: &str, local_address: Option<IpAddr>, mut resolver: Resolver) -> Result<TcpStream, SpeedTestError> {
+    let host: &str = uri.host().ok_or_else(|| SpeedTestError::InvalidUrl { url: uri.to_string(), reason: "it has no host".to_string() })?.trim_start_matches('[').trim_end_matches(']');
+    let port: u16 = uri.port_u16().unwrap_or(if uri.scheme_str() == Some("https") { 443 } else { 80 });
+    let ips: Vec<IpAddr> = match host.parse::<IpAddr>() {
+        Ok(ip) => vec![ip],
+        Err(_) => {
+            let unresolved = |reason: String| SpeedTestError::Resolve { host: host.to_string(), reason };
+            let name: Name = Name::from_str(host).map_err(|e| unresolved(e.to_string()))?;
+            resolver.call(name).await.map_err(|e| unresolved(e.to_string()))?.map(|addr| addr.ip()).collect()
+        }
+    };
+    let mut last_error: Option<io::Error> = None;
+    for ip in ips {
+        let socket: TcpSocket = if ip.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+        socket.bind_device(Some(interface.as_bytes()))
+            .map_err(|e| SpeedTestError::Bind { action: "bind to interface", address: interface.to_string(), reason: e.to_string() })?;
+        if let Some(local_address) = local_address {
+            socket.bind(SocketAddr::new(local_address, 0))
+                .map_err(|e| SpeedTestError::Bind { action: "bind to", address: local_address.to_string(), reason: e.to_string() })?;
+        }
+        match socket.connect(SocketAddr::new(ip, port)).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    match last_error {
+        Some(e) => Err(SpeedTestError::Unreachable { target: format!("{} over {}", host, interface), reason: e.to_string() }),
+        None => Err(SpeedTestError::Resolve { host: host.to_string(), reason: "it has no addresses".to_string() }),
+    }
+}
+
+impl Connection for Stream {
+    fn connected(&self) -> Connected {
+        match self {
+            // Carries the HttpInfo with the local and remote addresses
+            Stream::Tcp(stream) => stream.connected(),
+            // Makes hyper send the full URL, as a proxy expects
+            Stream::Forwarded(stream) => stream.connected().proxy(true),
+            #[cfg(unix)]
+            Stream::Unix(_) => Connected::new(),
+        }
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) | Stream::Forwarded(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) | Stream::Forwarded(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) | Stream::Forwarded(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) | Stream::Forwarded(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
@@ -0,0 +1,363 @@
+use hyper::client::connect::dns::Name;
+use hyper::service::Service;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+
+// How long to wait for a --dns-server to answer before asking again, and how
+// many times to ask
+const DNS_TIMEOUT: Duration = Duration::from_secs(2);
+const DNS_ATTEMPTS: u32 = 3;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+/*
+An address family that -4 or -6 restricts connections to
+*/
+#[derive(Clone, Copy, PartialEq)]
+pub enum AddressFamily {
+    V4,
+    V6,
+}
+
+impl AddressFamily {
+    pub fn includes(self, ip: &IpAddr) -> bool {
+        match self {
+            AddressFamily::V4 => ip.is_ipv4(),
+            AddressFamily::V6 => ip.is_ipv6(),
+        }
+    }
+}
+
+impl fmt::Display for AddressFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AddressFamily::V4 => "IPv4",
+            AddressFamily::V6 => "IPv6",
+        })
+    }
+}
+
+/*
+Resolver for the HTTP connector that answers from pre-resolved addresses,
+falling back to the system resolver for any other host name. With a family
+only the addresses of that family are handed out.
+*/
+#[derive(Clone, Default)]
+pub struct Resolver {
+    resolved: Arc<HashMap<String, Vec<IpAddr>>>,
+    family: Option<AddressFamily>,
+    // Asked instead of the system resolver, with --dns-server
+    server: Option<SocketAddr>,
+}
+
+impl Resolver {
+    pub fn new(resolved: HashMap<String, Vec<IpAddr>>, family: Option<AddressFamily>, server: Option<SocketAddr>) -> Resolver {
+        Resolver { resolved: Arc::new(resolved), family, server }
+    }
+}
+
+impl Service<Name> for Resolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = std::io::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let resolved: Arc<HashMap<String, Vec<IpAddr>>> = self.resolved.clone();
+        let (family, server): (Option<AddressFamily>, Option<SocketAddr>) = (self.family, self.server);
+        Box::pin(async move {
+            // The connector fills in the port, so any port will do here
+            let addrs: Vec<SocketAddr> = match (resolved.get(name.as_str()), server) {
+                (Some(ips), _) => ips.iter().map(|ip| SocketAddr::new(*ip, 0)).collect(),
+                (None, Some(server)) => query(server, name.as_str(), family).await?.into_iter().map(|ip| SocketAddr::new(ip, 0)).collect(),
+                (None, None) => tokio::net::lookup_host((name.as_str(), 0)).await?.collect(),
+            };
+            let Some(family) = family else {
+                return Ok(addrs.into_iter());
+            };
+            let addrs: Vec<SocketAddr> = addrs.into_iter().filter(|addr| family.includes(&addr.ip())).collect();
+            if addrs.is_empty() {
+                return Err(Error::new(ErrorKind::NotFound, format!("{} has no {} address", name, family)));
+            }
+            Ok(addrs.into_iter())
+        })
+    }
+}
+
+/*
+Resolve a host name with the system resolver, or by asking the DNS server when
+there is one, timing how long the lookup took
+*/
+pub async fn timed_lookup(host: &str, port: u16, server: Option<SocketAddr>, family: Option<AddressFamily>) -> std::io::Result<(Vec<IpAddr>, Duration)> {
+    let start: Instant = Instant::now();
+    let ips: Vec<IpAddr> = match server {
+        Some(server) => query(server, host, family).await?,
+        None => tokio::net::lookup_host((host, port)).await?.map(|addr| addr.ip()).collect(),
+    };
+    Ok((ips, start.elapsed()))
+}
+
+/*
+Look the host up over UDP at a DNS server: an A query, an AAAA query, or both
+unless the family rules one out
+*/
+async fn query(server: SocketAddr, host: &str, family: Option<AddressFamily>) -> std::io::Result<Vec<IpAddr>> {
+    let local: SocketAddr = if server.is_ipv4() { (Ipv4Addr::UNSPECIFIED, 0).into() } else { (Ipv6Addr::UNSPECIFIED, 0).into() };
+    let socket: UdpSocket = UdpSocket::bind(local).await?;
+    socket.connect(server).await?;
+    let types: &[u16] = match family {
+        Some(AddressFamily::V4) => &[TYPE_A],
+        Some(AddressFamily::V6) => &[TYPE_AAAA],
+        None => &[TYPE_A, TYPE_AAAA],
+    };
+    let mut ips: Vec<IpAddr> = Vec::new();
+    for qtype in types {
+        ips.extend(ask(&socket, server, host, *qtype).await?);
+    }
+    if ips.is_empty() {
+        return Err(Error::new(ErrorKind::NotFound, format!("the DNS server {} has no address for {}", server, host)));
+    }
+    Ok(ips)
+}
+
+/*
+Send one query, again after each timeout, and wait for the answer with its ID
+*/
+async fn ask(socket: &UdpSocket, server: SocketAddr, host: &str, qtype: u16) -> std::io::Result<Vec<IpAddr>> {
+    let id: u16 = rand::random();
+    let request: Vec<u8> = encode_query(id, host, qtype)?;
+    let mut response: [u8; 4096] = [0; 4096];
+    for _ in 0..DNS_ATTEMPTS {
+        socket.send(&request).await?;
+        let answer = tokio::time::timeout(DNS_TIMEOUT, async {
+            loop {
+                let len: usize = socket.recv(&mut response).await?;
+                // Anything else is a late answer to an earlier query
+                if len >= 2 && u16::from_be_bytes([response[0], response[1]]) == id {
+                    return parse_answer(&response[..len], qtype);
+                }
+            }
+        }).await;
+        if let Ok(answer) = answer {
+            return answer;
+        }
+    }
+    Err(Error::new(ErrorKind::TimedOut, format!("the DNS server {} did not answer", server)))
+}
+
+/*
+A recursive query for one name and type
+*/
+fn encode_query(id: u16, host: &str, qtype: u16) -> std::io::Result<Vec<u8>> {
+    let mut query: Vec<u8> = Vec::new();
+    query.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, and one question
+    query.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("{} is not a valid host name", host)));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+/*
+The addresses of the asked-for type among the answer's records. A recursive
+server puts any CNAME chain before them, which is skipped.
+*/
+fn parse_answer(message: &[u8], qtype: u16) -> std::io::Result<Vec<IpAddr>> {
+    let malformed = || Error::new(ErrorKind::InvalidData, "malformed DNS answer");
+    let field = |at: usize| -> std::io::Result<u16> {
+        message.get(at..at + 2).map(|b| u16::from_be_bytes([b[0], b[1]])).ok_or_else(malformed)
+    };
+    let flags: u16 = field(2)?;
+    match flags & 0x000f {
+        0 => {}
+        3 => return Ok(Vec::new()),
+        rcode => return Err(Error::other(format!("the DNS server failed the query (rcode {})", rcode))),
+    }
+    if flags & 0x0200 != 0 {
+        return Err(Error::other("the DNS answer was truncated"));
+    }
+    let (questions, answers): (u16, u16) = (field(4)?, field(6)?);
+
+    let mut at: usize = 12;
+    for _ in 0..questions {
+        at = skip_name(message, at).ok_or_else(malformed)? + 4;
+    }
+    let mut ips: Vec<IpAddr> = Vec::new();
+    for _ in 0..answers {
+        at = skip_name(message, at).ok_or_else(malformed)?;
+        let (rtype, length): (u16, usize) = (field(at)?, field(at + 8)? as usize);
+        let data: &[u8] = message.get(at + 10..at + 10 + length).ok_or_else(malformed)?;
+        match (rtype, <[u8; 4]>::try_from(data), <[u8; 16]>::try_from(data)) {
+            (TYPE_A, Ok(octets), _) if qtype == TYPE_A => ips.push(IpAddr::from(octets)),
+            (TYPE_AAAA, _, Ok(octets)) if qtype == TYPE_AAAA => ips.push(IpAddr::from(octets)),
+            _ => {}
+        }
+        at += 10 + length;
+    }
+    Ok(ips)
+}
+
+/*
+Where the record after a (possibly compressed) name starts
+*/
+fn skip_name(message: &[u8], mut at: usize) -> Option<usize> {
+    loop {
+        let len: u8 = *message.get(at)?;
+        match len {
+            0 => return Some(at + 1),
+            len if len & 0xc0 == 0xc0 => return Some(at + 2),
+            len => at += 1 + len as usize,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The question of a query for example.com, as encode_query writes it
+    const QUESTION: &[u8] = b"\x07example\x03com\x00";
+
+    /*
+    An answer with the given flags and records, after the question for
+    example.com of the given type
+    */
+    fn answer(flags: u16, qtype: u16, records: &[Vec<u8>]) -> Vec<u8> {
+        let mut message: Vec<u8> = vec![0x12, 0x34];
+        message.extend_from_slice(&flags.to_be_bytes());
+        message.extend_from_slice(&[0, 1, 0, records.len() as u8, 0, 0, 0, 0]);
+        message.extend_from_slice(QUESTION);
+        message.extend_from_slice(&qtype.to_be_bytes());
+        message.extend_from_slice(&CLASS_IN.to_be_bytes());
+        for record in records {
+            message.extend_from_slice(record);
+        }
+        message
+    }
+
+    /*
+    A record of class IN with a 60 second TTL under the given, already encoded, name
+    */
+    fn record(name: &[u8], rtype: u16, data: &[u8]) -> Vec<u8> {
+        let mut record: Vec<u8> = name.to_vec();
+        record.extend_from_slice(&rtype.to_be_bytes());
+        record.extend_from_slice(&CLASS_IN.to_be_bytes());
+        record.extend_from_slice(&[0, 0, 0, 60]);
+        record.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        record.extend_from_slice(data);
+        record
+    }
+
+    // A pointer to the question's name, 12 bytes in
+    const POINTER: &[u8] = &[0xc0, 12];
+    const TYPE_CNAME: u16 = 5;
+
+    #[test]
+    fn encodes_a_query() {
+        let query: Vec<u8> = encode_query(0x1234, "example.com.", TYPE_AAAA).unwrap();
+        let mut expected: Vec<u8> = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        expected.extend_from_slice(QUESTION);
+        expected.extend_from_slice(&[0, 28, 0, 1]);
+        assert_eq!(query, expected);
+    }
+
+    #[test]
+    fn rejects_an_invalid_host_name() {
+        for host in ["", "a..b", &"a".repeat(64)] {
+            let error: Error = encode_query(1, host, TYPE_A).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidInput, "{:?} was encoded", host);
+        }
+    }
+
+    #[test]
+    fn reads_the_addresses_of_a_compressed_name() {
+        let message: Vec<u8> = answer(0x8180, TYPE_A, &[record(POINTER, TYPE_A, &[192, 0, 2, 1]), record(POINTER, TYPE_A, &[192, 0, 2, 2])]);
+        let ips: Vec<IpAddr> = parse_answer(&message, TYPE_A).unwrap();
+        assert_eq!(ips, [IpAddr::from([192, 0, 2, 1]), IpAddr::from([192, 0, 2, 2])]);
+    }
+
+    #[test]
+    fn follows_a_cname_chain_to_the_addresses() {
+        // example.com is www.example.net, whose name is then written out in full
+        let target: &[u8] = b"\x03www\x07example\x03net\x00";
+        let message: Vec<u8> = answer(0x8180, TYPE_AAAA, &[
+            record(POINTER, TYPE_CNAME, target),
+            record(target, TYPE_AAAA, &Ipv6Addr::LOCALHOST.octets()),
+        ]);
+        assert_eq!(parse_answer(&message, TYPE_AAAA).unwrap(), [IpAddr::from(Ipv6Addr::LOCALHOST)]);
+    }
+
+    #[test]
+    fn keeps_only_the_asked_for_type() {
+        let message: Vec<u8> = answer(0x8180, TYPE_A, &[
+            record(POINTER, TYPE_AAAA, &Ipv6Addr::LOCALHOST.octets()),
+            record(POINTER, TYPE_A, &[127, 0, 0, 1]),
+        ]);
+        assert_eq!(parse_answer(&message, TYPE_A).unwrap(), [IpAddr::from([127, 0, 0, 1])]);
+        assert_eq!(parse_answer(&message, TYPE_AAAA).unwrap(), [IpAddr::from(Ipv6Addr::LOCALHOST)]);
+        // An A record of the wrong length is no address
+        let message: Vec<u8> = answer(0x8180, TYPE_A, &[record(POINTER, TYPE_A, &[127, 0, 0])]);
+        assert!(parse_answer(&message, TYPE_A).unwrap().is_empty());
+    }
+
+    #[test]
+    fn treats_nxdomain_as_no_addresses() {
+        assert!(parse_answer(&answer(0x8183, TYPE_A, &[]), TYPE_A).unwrap().is_empty());
+    }
+
+    #[test]
+    fn fails_on_a_server_failure() {
+        let error: Error = parse_answer(&answer(0x8182, TYPE_A, &[]), TYPE_A).unwrap_err();
+        assert_eq!(error.to_string(), "the DNS server failed the query (rcode 2)");
+    }
+
+    #[test]
+    fn fails_on_a_truncated_answer() {
+        let error: Error = parse_answer(&answer(0x8380, TYPE_A, &[]), TYPE_A).unwrap_err();
+        assert_eq!(error.to_string(), "the DNS answer was truncated");
+    }
+
+    #[test]
+    fn fails_on_a_short_or_malformed_message() {
+        let whole: Vec<u8> = answer(0x8180, TYPE_A, &[record(POINTER, TYPE_A, &[127, 0, 0, 1])]);
+        // Cut off in the header, the question, the record head and its data
+        for len in [3, 7, 20, whole.len() - 8, whole.len() - 1] {
+            let error: Error = parse_answer(&whole[..len], TYPE_A).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidData, "a message cut to {} bytes was read", len);
+        }
+        // A record claiming more data than the message holds
+        let mut overlong: Vec<u8> = whole.clone();
+        let length_at: usize = whole.len() - 6;
+        overlong[length_at..length_at + 2].copy_from_slice(&200u16.to_be_bytes());
+        assert_eq!(parse_answer(&overlong, TYPE_A).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn skips_plain_and_compressed_names() {
+        assert_eq!(skip_name(QUESTION, 0), Some(QUESTION.len()));
+        assert_eq!(skip_name(b"\x03www\xc0\x0c", 0), Some(6));
+        assert_eq!(skip_name(b"\x07exam", 0), None);
+        assert_eq!(skip_name(b"", 0), None);
+    }
+}
@@ -0,0 +1,38 @@
+use crate::config::HeaderDump;
+use hyper::header::{AUTHORIZATION, COOKIE, HOST, PROXY_AUTHORIZATION, SET_COOKIE};
+use hyper::{Body, HeaderMap, Request, Response};
+
+/*
+Print the request line and headers about to be sent to stderr, curl-style
+*/
+pub fn request(request: &Request<Body>, mode: HeaderDump) {
+    let uri = request.uri();
+    eprintln!("> {} {} {:?}", request.method(), uri.path_and_query().map(|p| p.as_str()).unwrap_or("/"), request.version());
+    // hyper fills in Host when the request is sent
+    if let (false, Some(authority)) = (request.headers().contains_key(HOST), uri.authority()) {
+        eprintln!("> {}: {}", HOST, authority);
+    }
+    headers('>', request.headers(), mode);
+    eprintln!(">");
+}
+
+/*
+Print the status line and headers received to stderr, curl-style
+*/
+pub fn response(response: &Response<Body>, mode: HeaderDump) {
+    eprintln!("< {:?} {}", response.version(), response.status());
+    headers('<', response.headers(), mode);
+    eprintln!("<");
+}
+
+fn headers(prefix: char, headers: &HeaderMap, mode: HeaderDump) {
+    for (name, value) in headers {
+        let sensitive: bool = [AUTHORIZATION, PROXY_AUTHORIZATION, COOKIE, SET_COOKIE].contains(name);
+        let value: &str = if sensitive && matches!(mode, HeaderDump::Redacted) {
+            "<redacted>"
+        } else {
+            value.to_str().unwrap_or("<binary>")
+        };
+        eprintln!("{} {}: {}", prefix, name, value);
+    }
+}
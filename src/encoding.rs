@@ -0,0 +1,49 @@
+use brotli::DecompressorWriter;
+use std::io::{self, Write};
+
+// Size of the decoder's internal output buffer
+const DECODE_BUFFER_BYTES: usize = 64 * 1024;
+
+/*
+A sink that throws decoded data away, keeping only how much there was
+*/
+struct ByteCounter {
+    bytes: u64,
+}
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.bytes += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/*
+Decodes a Brotli body as it streams in to measure its decompressed size
+without holding the decoded data in memory
+*/
+pub struct BrotliCounter {
+    decoder: DecompressorWriter<ByteCounter>,
+}
+
+impl BrotliCounter {
+    pub fn new() -> BrotliCounter {
+        BrotliCounter { decoder: DecompressorWriter::new(ByteCounter { bytes: 0 }, DECODE_BUFFER_BYTES) }
+    }
+
+    pub fn update(&mut self, chunk: &[u8]) -> io::Result<()> {
+        self.decoder.write_all(chunk)
+    }
+
+    /*
+    Finish decoding, failing if the stream was cut short, and return the decoded size
+    */
+    pub fn finish(mut self) -> io::Result<u64> {
+        self.decoder.close()?;
+        Ok(self.decoder.get_ref().bytes)
+    }
+}
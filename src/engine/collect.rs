@@ -0,0 +1,463 @@
+use crate::checksum::{ChecksumResult, ChecksumVerifier};
+use chrono::{DateTime, Local};
+use crate::error::SpeedTestError;
+use crate::mem::MemoryUsage;
+use crate::metrics::LiveMetrics;
+use crate::output::OutputFile;
+use crate::config::{redact_url, SpeedTestConfig, SummaryFormat};
+use hyper::Uri;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use crate::report::{format_speed, BufferbloatSummary, DuplexSummary, SpeedTestResult, WarmupSummary};
+use crate::stats::{LatencySummary, Sample};
+use crate::{pushgateway, shutdown, stats, version, webhook};
+use crate::template::Template;
+use std::cmp::max;
+use std::convert::Infallible;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+use super::{StreamRecord, emit_json_line, print_line};
+use super::transport::{HttpClient, RequestOptions};
+use super::download::{StreamCounter, Transfer, compute_chunks, compute_ranges, record_edge_nodes, run_speed_test, weighted_boundary, worker_count};
+use super::probe::{ProbedFile, probe_latency, probe_loaded_latency};
+use super::uploads::{UploadState, duplex_uploads};
+
+// How many --loaded-latency probes are timed before the download for the idle figure
+const IDLE_LATENCY_PROBES: u32 = 5;
+
+/*
+Decide how many workers to use and which range each one downloads
+*/
+pub(super) fn plan(file: &ProbedFile, config: &SpeedTestConfig, connections: u64, seed: u64, dns_time: Option<Duration>) -> Result<Plan, SpeedTestError> {
+    // --duplex uploads to the file itself unless given somewhere else
+    let upload_url: Option<Uri> = match (config.duplex, &config.upload_url) {
+        (false, _) => None,
+        (true, Some(upload_url)) => Some(upload_url.parse::<Uri>()
+            .map_err(|e| SpeedTestError::InvalidUrl { url: redact_url(upload_url), reason: e.to_string() })?),
+        (true, None) => Some(file.url.clone()),
+    };
+    let weights: Option<&[u64]> = Some(config.weights.as_slice()).filter(|w| !w.is_empty());
+    let workers: u64 = if file.encoding.as_deref() == Some("br") {
+        info!("Using 1 worker because a Brotli-encoded response can only be decoded as a single stream");
+        1
+    } else if !file.length_known {
+        1
+    } else if !file.ranges_supported {
+        info!("Using 1 worker because {} does not accept range requests", file.host);
+        1
+    } else if config.no_range {
+        1
+    } else if let Some(weights) = weights {
+        weights.len() as u64
+    } else {
+        worker_count(connections, file.length, config.connections.is_some())
+    };
+    // Weights only apply while the file is actually split between workers
+    let weights: Option<&[u64]> = weights.filter(|w| w.len() as u64 == workers);
+    // A share that rounds down to nothing would leave a worker with an empty range
+    if let Some(weights) = weights {
+        if let Some(index) = (0..workers).position(|i| weighted_boundary(file.length, weights, i + 1) == weighted_boundary(file.length, weights, i)) {
+            return Err(SpeedTestError::Config(format!("Weight {} of --weights is too small to give worker {} any of the {} bytes", weights[index], index, file.length)));
+        }
+    }
+    // Chunks only help while there are several connections to share them
+    let chunk_size: Option<u64> = config.chunk_size.filter(|_| workers > 1);
+    let mut ranges: Vec<(u64, Option<u64>)> = match chunk_size {
+        Some(chunk_size) => compute_chunks(config.offset, file.length, file.content_length, chunk_size),
+        None => compute_ranges(config.offset, file.length, file.content_length, workers, weights),
+    };
+    let workers: u64 = match chunk_size {
+        Some(chunk_size) if (ranges.len() as u64) < workers => {
+            info!("Using {} worker(s) because the {} byte download makes only {} chunk(s) of {} bytes", ranges.len(), file.length, ranges.len(), chunk_size);
+            ranges.len() as u64
+        }
+        _ => workers,
+    };
+    let output: Option<Arc<OutputFile>> = match &config.output_file {
+        Some(path) => Some(Arc::new(OutputFile::create(path, config.offset, Some(file.length).filter(|_| file.length_known))?)),
+        None => None,
+    };
+    if config.shuffle_ranges {
+        let mut rng: StdRng = StdRng::seed_from_u64(seed);
+        ranges.shuffle(&mut rng);
+    }
+
+    Ok(Plan {
+        workers,
+        transfer: Transfer {
+            ranges,
+            // Without range support the only option is a plain GET of the whole file
+            ranged: file.ranges_supported && !config.no_range,
+            length: Some(file.length).filter(|_| file.length_known),
+            shared_by: chunk_size.map(|_| workers),
+            output,
+        },
+        seed,
+        dns_time,
+        encoding: file.encoding.clone(),
+        redirected_to: file.redirected_to.clone(),
+        tls_version: file.tls_version.clone(),
+        edge_nodes: file.edge_nodes.clone(),
+        latency: None,
+        connect_time: None,
+        metrics: None,
+        upload_url,
+    })
+}
+
+/*
+Append a result to a CSV log, writing the header first if the file is new
+*/
+fn append_csv_row(path: &Path, result: &SpeedTestResult, timestamp: &DateTime<Local>) -> std::io::Result<()> {
+    let mut file: File = OpenOptions::new().create(true).append(true).open(path)?;
+    if file.metadata()?.len() == 0 {
+        writeln!(file, "{}", SpeedTestResult::csv_header())?;
+    }
+    writeln!(file, "{}", result.to_csv_row(timestamp))
+}
+
+/*
+What the probe worked out about the download, shared by every measurement
+*/
+pub(super) struct Plan {
+    workers: u64,
+    transfer: Transfer,
+    seed: u64,
+    dns_time: Option<Duration>,
+    encoding: Option<String>,
+    redirected_to: Option<String>,
+    tls_version: Option<String>,
+    edge_nodes: Vec<String>,
+    pub(super) latency: Option<LatencySummary>,
+    pub(super) connect_time: Option<LatencySummary>,
+    // Where --metrics-listen scrapes read from
+    pub(super) metrics: Option<Arc<LiveMetrics>>,
+    // Where --duplex uploads to
+    upload_url: Option<Uri>,
+}
+
+/*
+A finished measurement, with the checksum verdict kept apart from the result
+so it can fail the run once the result has been reported
+*/
+pub(super) struct Measurement {
+    pub(super) result: SpeedTestResult,
+    checksum: Option<(String, ChecksumResult)>,
+    length: u64,
+}
+
+/*
+Run one complete measurement and report it
+*/
+pub(super) async fn measure(client: &Arc<HttpClient>, url: &Uri, config: &SpeedTestConfig, plan: &Plan, checksum: Option<ChecksumVerifier>, template: Option<&Template>) -> Result<SpeedTestResult, SpeedTestError> {
+    let measurement: Measurement = collect(client, url, config, plan, checksum, false).await?;
+    publish(config, plan, &measurement, template).await?;
+    Ok(measurement.result)
+}
+
+/*
+Print a measurement's summary and send it wherever else the options say, then
+fail the run if it didn't pass
+*/
+pub(super) async fn publish(config: &SpeedTestConfig, plan: &Plan, measurement: &Measurement, template: Option<&Template>) -> Result<(), SpeedTestError> {
+    let result: &SpeedTestResult = &measurement.result;
+
+    let timestamp: DateTime<Local> = Local::now();
+    if config.json_stream {
+        emit_json_line(&StreamRecord::Summary {
+            timestamp: timestamp.to_rfc3339(),
+            result,
+        })?;
+    } else if let Some(template) = template {
+        print_line(&template.render(result))?;
+    } else {
+        match config.format {
+            SummaryFormat::Table => print_line(&result.to_table(config.speed_format()))?,
+            SummaryFormat::Kv => print_line(&result.to_kv())?,
+        }
+    }
+
+    if result.rate_limited > 0 {
+        warn!("the server rate-limited {} of {} download(s) with 429 Too Many Requests, for {:.1} s in total; parallel throughput was capped by the server", result.rate_limited, plan.transfer.ranges.len(), result.rate_limited_seconds);
+    }
+
+    if let Some(path) = &config.csv_log {
+        append_csv_row(path, result, &timestamp)
+            .map_err(|source| SpeedTestError::File { action: "write to", path: path.clone(), source })?;
+    }
+
+    if let Some(webhook_url) = &config.webhook {
+        webhook::send(webhook_url, result, Duration::from_secs_f64(config.webhook_timeout)).await;
+    }
+
+    if let Some(pushgateway_url) = &config.pushgateway {
+        pushgateway::push(pushgateway_url, &config.job, result).await;
+    }
+
+    check_measurement(config, measurement)
+}
+
+/*
+Run the downloads of one measurement and work out its result. Quiet skips the
+per-sample output.
+*/
+pub(super) async fn collect(client: &Arc<HttpClient>, url: &Uri, config: &SpeedTestConfig, plan: &Plan, checksum: Option<ChecksumVerifier>, quiet: bool) -> Result<Measurement, SpeedTestError> {
+    let (workers, length) = (plan.workers, plan.transfer.length);
+
+    // Measure what one connection achieves on one worker's share of the file, so
+    // the parallel run can be compared against it
+    let baseline_bps: Option<u64> = match length {
+        Some(length) if config.efficiency && workers > 1 => {
+            if !config.json_stream && !quiet && !config.quiet {
+                print_line("Measuring single-connection baseline...")?;
+            }
+            let baseline: Transfer = Transfer {
+                ranges: vec![(config.offset, Some(config.offset + length / workers - 1))],
+                ranged: true,
+                length: Some(length / workers),
+                shared_by: None,
+                output: None,
+            };
+            let (baseline, elapsed) = run_speed_test(client.clone(), url, config, baseline, None, true, None).await?;
+            Some((baseline.total_bytes_downloaded as f64 / elapsed.as_secs_f64()) as u64)
+        }
+        _ => None,
+    };
+
+    // --loaded-latency probes over a connection of its own, first with the link
+    // idle and then for as long as the downloads run
+    let probe_client: Option<HttpClient> = config.loaded_latency.then(|| client.isolated());
+    let mut idle_latency: Vec<Duration> = Vec::new();
+    if let Some(probe_client) = &probe_client {
+        let options: RequestOptions = RequestOptions::from_config(config);
+        // The first probe opens the connection, so it isn't counted
+        probe_latency(probe_client, url, &options).await.map_err(SpeedTestError::Connect)?;
+        for _ in 0..IDLE_LATENCY_PROBES {
+            idle_latency.push(probe_latency(probe_client, url, &options).await.map_err(SpeedTestError::Connect)?);
+        }
+    }
+    let (mut loaded_latency, mut lost_probes): (Vec<Duration>, u64) = (Vec::new(), 0);
+
+    // --duplex uploads for as long as the downloads run. A failed upload fails
+    // the measurement, though only once the downloads it was loading are done.
+    let upload_state: Arc<Mutex<UploadState>> = Arc::new(Mutex::new(UploadState::new(config)));
+    let download = async {
+        let probes = async {
+            match &probe_client {
+                Some(probe_client) => probe_loaded_latency(probe_client, url, config, &mut loaded_latency, &mut lost_probes).await,
+                None => std::future::pending::<Infallible>().await,
+            }
+        };
+        tokio::select! {
+            finished = run_speed_test(client.clone(), url, config, plan.transfer.clone(), checksum, quiet, plan.metrics.clone()) => finished,
+            never = probes => match never {},
+        }
+    };
+    let (mut state, elapsed) = match &plan.upload_url {
+        Some(upload_url) => {
+            tokio::pin!(download);
+            let (finished, failed) = tokio::select! {
+                finished = &mut download => (finished, None),
+                Err(e) = duplex_uploads(client, upload_url, config, workers, plan.seed, upload_state.clone()) => (download.await, Some(e)),
+            };
+            if let Some(e) = failed {
+                return Err(SpeedTestError::DuplexUpload { url: redact_url(&upload_url.to_string()), source: Box::new(e) });
+            }
+            finished?
+        }
+        None => download.await?,
+    };
+    // A file of unknown length is complete once the server ends the body
+    let length: u64 = length.unwrap_or(state.total_bytes_downloaded);
+    if let Some(output) = &plan.transfer.output {
+        if state.total_bytes_downloaded < length {
+            warn!("only {} of {} bytes were received, so {} is incomplete", state.total_bytes_downloaded, length, output.path().display());
+        }
+    }
+
+    // Print out the total bytes downloaded and the average speed
+    let checksum: Option<(String, ChecksumResult)> = state.checksum.take()
+        .map(|c| (c.algorithm().to_string(), c.finish(length)));
+    // The average over the final --window of the download
+    let avg_speed: u64 = state.window.final_bps();
+    let ttfb_ms: Option<f64> = if state.ttfbs.is_empty() {
+        None
+    } else {
+        Some(state.ttfbs.iter().sum::<Duration>().as_secs_f64() * 1000.0 / state.ttfbs.len() as f64)
+    };
+    let throughput: f64 = state.total_bytes_downloaded as f64 / elapsed.as_secs_f64();
+    // The speed figures leave the --warmup out, unless it took up the whole test
+    let raw: Vec<u64> = state.samples.iter().map(|s| s.bps).collect();
+    let trimmed: Vec<u64> = state.samples.iter().filter(|s| !s.warmup).map(|s| s.bps).collect();
+    let warmup: Option<WarmupSummary> = match (config.warmup, state.warmup_bytes) {
+        (Some(seconds), Some(bytes)) => Some(WarmupSummary {
+            seconds,
+            bytes,
+            trimmed_avg_bps: ((state.total_bytes_downloaded - bytes) as f64 / (elapsed.as_secs_f64() - seconds).max(f64::EPSILON)) as u64,
+            raw_avg_bps: throughput as u64,
+            raw_peak_bps: raw.iter().copied().max(),
+            raw_p50_bps: stats::percentile(&raw, 50.0),
+            raw_p95_bps: stats::percentile(&raw, 95.0),
+        }),
+        (Some(_), None) => {
+            warn!("the test ended within the --warmup; its speed figures include the warm-up");
+            None
+        }
+        _ => None,
+    };
+    let bufferbloat: Option<BufferbloatSummary> = match (stats::latency_summary(&idle_latency), stats::latency_summary(&loaded_latency)) {
+        (Some(idle_ms), Some(loaded_ms)) => {
+            let increase_ms: f64 = loaded_ms.avg - idle_ms.avg;
+            Some(BufferbloatSummary { idle_ms, loaded_ms, increase_ms, grade: stats::bufferbloat_grade(increase_ms).to_string(), lost: lost_probes })
+        }
+        (Some(_), None) => {
+            warn!("no --loaded-latency probe was answered during the download, so there is no loaded latency to report");
+            None
+        }
+        _ => None,
+    };
+    let uploads = upload_state.lock().await;
+    let duplex: Option<DuplexSummary> = plan.upload_url.as_ref().map(|_| {
+        let upload_avg_bps: u64 = (uploads.total_bytes_sent as f64 / elapsed.as_secs_f64()) as u64;
+        DuplexSummary {
+            upload_bytes: uploads.total_bytes_sent,
+            upload_connections: workers,
+            upload_avg_bps,
+            upload_peak_bps: uploads.peak_bps(upload_avg_bps),
+            download_avg_bps: throughput as u64,
+            combined_bps: throughput as u64 + upload_avg_bps,
+        }
+    });
+    drop(uploads);
+    let speeds: &[u64] = if warmup.is_some() && !trimmed.is_empty() { &trimmed } else { &raw };
+    let spread: Option<(f64, f64)> = stats::mean_and_stddev(speeds);
+    // When the slowest and the fastest of those samples were taken
+    let counted: Vec<&Sample> = state.samples.iter().filter(|s| speeds.len() == raw.len() || !s.warmup).collect();
+    let min_at: Option<f64> = counted.iter().min_by_key(|s| s.bps).map(|s| s.elapsed);
+    let peak_at: Option<f64> = counted.iter().max_by_key(|s| s.bps).map(|s| s.elapsed);
+    // The probe's edge comes first, then any others the downloads landed on
+    let mut edges: Vec<String> = plan.edge_nodes.clone();
+    record_edge_nodes(&mut edges, std::mem::take(&mut state.edge_nodes));
+
+    let result: SpeedTestResult = SpeedTestResult {
+        workers,
+        protocol: state.protocol.clone(),
+        tls_version: plan.tls_version.clone(),
+        content_encoding: plan.encoding.clone(),
+        redirected_to: plan.redirected_to.clone(),
+        edge_nodes: if edges.is_empty() { None } else { Some(edges.join(", ")) },
+        // What the download used, which --no-range may have ruled out even
+        // though the server accepts ranges
+        range_requests: plan.transfer.ranged,
+        total_bytes: state.total_bytes_downloaded,
+        decoded_bytes: state.decoded_bytes,
+        elapsed: elapsed.as_secs_f64(),
+        avg_bps: avg_speed,
+        min_bps: speeds.iter().copied().min(),
+        min_at,
+        peak_bps: speeds.iter().copied().max(),
+        peak_at,
+        p50_bps: stats::percentile(speeds, 50.0),
+        p90_bps: stats::percentile(speeds, 90.0),
+        p95_bps: stats::percentile(speeds, 95.0),
+        p99_bps: stats::percentile(speeds, 99.0),
+        stddev_bps: spread.map(|(_, stddev)| stddev as u64),
+        cv: spread.filter(|(mean, _)| *mean > 0.0).map(|(mean, stddev)| stddev / mean),
+        ramp_percent: config.ramp_percent,
+        ramp_seconds: stats::time_to_peak_fraction(&state.samples, config.ramp_percent),
+        ttfb_ms,
+        latency_ms: plan.latency,
+        connect_ms: plan.connect_time,
+        stall_count: state.stall_count,
+        stall_seconds: state.stall_duration.as_secs_f64(),
+        chunk_count: state.chunk_count,
+        avg_chunk_bytes: state.total_bytes_downloaded / max(state.chunk_count, 1),
+        chunk_sizes: state.chunk_sizes.summary(),
+        header_bytes: state.header_bytes,
+        requests: state.requests,
+        connections: if state.local_addrs.is_empty() { None } else { Some(state.local_addrs.len() as u64) },
+        reconnects: config.reconnect_below.map(|_| state.reconnects),
+        retries: (config.max_retries > 0).then_some(state.retries),
+        rate_limited: state.rate_limited,
+        rate_limited_seconds: state.rate_limited_wait.as_secs_f64(),
+        stopped_early: config.max_runtime.is_some().then_some(state.stopped_early),
+        interrupted_by: state.interrupted_by.map(str::to_string),
+        seed: plan.seed,
+        dns_ms: plan.dns_time.map(|d| d.as_secs_f64() * 1000.0),
+        dns_server: plan.dns_time.and(config.dns_server).map(|server| server.to_string()),
+        checksum_algorithm: checksum.as_ref().map(|(algorithm, _)| algorithm.clone()),
+        checksum_ok: checksum.as_ref().map(|(_, result)| matches!(result, ChecksumResult::Match)),
+        baseline_bps,
+        efficiency: baseline_bps.filter(|b| *b > 0).map(|b| throughput / (b as f64 * workers as f64)),
+        // Ranges fetched one after another never compete, so fairness only means
+        // something for parallel workers
+        fairness: if workers > 1 && !config.single_connection { stats::jain_fairness(&state.streams.iter().map(StreamCounter::rate).collect::<Vec<f64>>()) } else { None },
+        baseline_deviation_percent: config.baseline.map(|baseline| (avg_speed as f64 - baseline as f64) * 100.0 / baseline as f64),
+        rss_avg_bytes: state.memory.as_ref().and_then(MemoryUsage::average),
+        rss_peak_bytes: state.memory.as_ref().and_then(MemoryUsage::peak),
+        warmup,
+        duplex,
+        bufferbloat,
+        streams: if config.per_connection { Some(state.streams.iter().map(StreamCounter::result).collect()) } else { None },
+        tool_version: version::tool_version(),
+    };
+
+    Ok(Measurement { result, checksum, length })
+}
+
+/*
+Fail a measurement whose data did not match the expected digest or whose speed
+fell below the --baseline
+*/
+pub(super) fn check_measurement(config: &SpeedTestConfig, measurement: &Measurement) -> Result<(), SpeedTestError> {
+    let Measurement { result, checksum, length } = measurement;
+
+    // Nothing can be judged on a download cut short by a signal
+    if let Some(signal) = shutdown::signal().filter(|_| result.interrupted_by.is_some()) {
+        return Err(SpeedTestError::Interrupted { signal });
+    }
+
+    // Fail the run if the data did not match the expected digest
+    match checksum {
+        Some((algorithm, ChecksumResult::Mismatch { expected, actual })) => {
+            return Err(SpeedTestError::ChecksumMismatch { algorithm: algorithm.clone(), expected: expected.clone(), actual: actual.clone() });
+        }
+        Some((algorithm, ChecksumResult::Incomplete { hashed_bytes })) => {
+            return Err(SpeedTestError::ChecksumIncomplete { algorithm: algorithm.clone(), hashed_bytes: *hashed_bytes, length: *length });
+        }
+        _ => {}
+    }
+
+    // Fail a regression gate only after the result has been reported
+    if let (Some(baseline), Some(deviation)) = (config.baseline, result.baseline_deviation_percent) {
+        if deviation < -config.tolerance {
+            return Err(SpeedTestError::BelowBaseline { avg_bps: result.avg_bps, baseline, deviation, tolerance: config.tolerance });
+        }
+    }
+
+    // CI assertions, each one that fails listed in the error
+    let mut failures: Vec<String> = Vec::new();
+    if let Some(min_speed) = config.min_speed.filter(|min_speed| result.avg_bps < *min_speed) {
+        failures.push(format!("the average speed of {} is below --min-speed {}", format_speed(result.avg_bps, config.speed_format()), format_speed(min_speed, config.speed_format())));
+    }
+    if let Some(max_latency) = config.max_latency {
+        let max_ms: f64 = max_latency * 1000.0;
+        match (&result.latency_ms, result.ttfb_ms) {
+            (Some(latency), _) if latency.avg > max_ms => failures.push(format!("the average latency of {:.1} ms is above --max-latency {:.1} ms", latency.avg, max_ms)),
+            (None, Some(ttfb_ms)) if ttfb_ms > max_ms => failures.push(format!("the average time to first byte of {:.1} ms is above --max-latency {:.1} ms", ttfb_ms, max_ms)),
+            (None, None) => failures.push("--max-latency can't be checked, since no latency was measured".to_string()),
+            _ => {}
+        }
+    }
+    if !failures.is_empty() {
+        return Err(SpeedTestError::ThresholdsFailed { failures });
+    }
+
+    Ok(())
+}
@@ -0,0 +1,1132 @@
+use bytes::Bytes;
+use crate::checksum::ChecksumVerifier;
+use chrono::{DateTime, Local};
+use crate::encoding::BrotliCounter;
+use crate::error::{DownloadFailure, SpeedTestError};
+use crate::mem::MemoryUsage;
+use crate::metrics::{LiveMetrics, MetricsRecorder};
+use crate::output::OutputFile;
+use crate::config::{HeaderDump, SpeedTestConfig};
+use futures_util::stream::FuturesUnordered;
+use futures_util::StreamExt;
+use hyper::{Body, Request, StatusCode, Uri, header::{CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, RANGE, RETRY_AFTER, USER_AGENT}, http::HeaderValue};
+use hyper::body::HttpBody;
+use hyper::client::connect::HttpInfo;
+use crate::report::{format_speed, SpeedFormat, StreamResult};
+use crate::sink::{CsvSink, MetricsSink};
+use crate::stats::{ChunkHistogram, Sample};
+use crate::progress::LiveDisplay;
+use crate::{dump, progress, shutdown};
+use crate::throttle::RateLimiter;
+use crate::window::SpeedWindow;
+use std::cmp::max;
+use std::collections::{HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Instant, Duration};
+use tokio::sync::{Mutex, Notify, OwnedSemaphorePermit, Semaphore};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, debug_span, info, trace, warn, Instrument};
+use super::{StreamRecord, emit_json_line, print_line};
+use super::transport::{RequestOptions, Transport};
+use super::sampling::{StdoutSink, print_loop};
+use super::probe::content_encoding;
+
+pub(super) struct DownloadState {
+    pub(super) sample_interval: Duration,
+    pub(super) window: SpeedWindow,
+    // When --warmup ends, and what had arrived by then once it has
+    warmup_end: Option<Instant>,
+    pub(super) warmup_bytes: Option<u64>,
+    pub(super) total_bytes_downloaded: u64,
+    pub(super) stall_count: u64,
+    pub(super) stall_duration: Duration,
+    pub(super) chunk_count: u64,
+    pub(super) chunk_sizes: ChunkHistogram,
+    pub(super) checksum: Option<ChecksumVerifier>,
+    // Woken when the hash moves on or a download ends, for the ranges held
+    // back while the checksum's buffer is full
+    checksum_progress: Arc<Notify>,
+    pub(super) header_bytes: u64,
+    pub(super) samples: Vec<Sample>,
+    pub(super) sampled_bytes: u64,
+    pub(super) sampled_at: Instant,
+    pub(super) stopped_early: bool,
+    pub(super) interrupted_by: Option<&'static str>,
+    pub(super) active_downloads: u64,
+    pub(super) ttfbs: Vec<Duration>,
+    pub(super) protocol: Option<String>,
+    pub(super) requests: u64,
+    pub(super) local_addrs: HashSet<SocketAddr>,
+    pub(super) decoded_bytes: Option<u64>,
+    pub(super) reconnects: u64,
+    pub(super) retries: u64,
+    pub(super) rate_limited: u64,
+    pub(super) rate_limited_wait: Duration,
+    pub(super) edge_nodes: Vec<String>,
+    pub(super) memory: Option<MemoryUsage>,
+    pub(super) expected_bytes: Option<u64>,
+    progress: Option<Progress>,
+    pub(super) streams: Vec<StreamCounter>,
+    pub(super) per_connection: bool,
+}
+
+/*
+What one download has received, for --per-connection and the fairness index.
+With --chunk-size it is what one connection received over all the chunks it
+took from the queue.
+*/
+pub(super) struct StreamCounter {
+    range: String,
+    // How many chunks of the queue the connection has taken
+    chunks: Option<u64>,
+    pub(super) bytes: u64,
+    pub(super) sampled_bytes: u64,
+    // The file offset of the next byte it will receive
+    offset: u64,
+    started: Instant,
+    // Set once the download has finished, failed or been stopped
+    pub(super) elapsed: Option<Duration>,
+}
+
+impl StreamCounter {
+    fn new(range: String, offset: u64) -> StreamCounter {
+        StreamCounter { range, chunks: None, bytes: 0, sampled_bytes: 0, offset, started: Instant::now(), elapsed: None }
+    }
+
+    fn seconds(&self) -> f64 {
+        self.elapsed.unwrap_or_else(|| self.started.elapsed()).as_secs_f64()
+    }
+
+    /*
+    The stream's own average rate, in bytes per second
+    */
+    pub(super) fn rate(&self) -> f64 {
+        let seconds: f64 = self.seconds();
+        if seconds > 0.0 { self.bytes as f64 / seconds } else { 0.0 }
+    }
+
+    pub(super) fn result(&self) -> StreamResult {
+        StreamResult {
+            range: self.range.clone(),
+            chunks: self.chunks,
+            bytes: self.bytes,
+            seconds: self.seconds(),
+            avg_bps: self.rate() as u64,
+        }
+    }
+}
+
+impl DownloadState {
+    /*
+    The state of a run that hasn't received anything yet
+    */
+    pub(super) fn new(config: &SpeedTestConfig, checksum: Option<ChecksumVerifier>, expected_bytes: Option<u64>, quiet: bool) -> DownloadState {
+        DownloadState {
+            sample_interval: Duration::from_millis(config.sample_interval),
+            window: SpeedWindow::new(Duration::from_millis(config.sample_interval), config.window),
+            warmup_end: config.warmup.map(|seconds| Instant::now() + Duration::from_secs_f64(seconds)),
+            warmup_bytes: None,
+            total_bytes_downloaded: 0,
+            stall_count: 0,
+            stall_duration: Duration::ZERO,
+            chunk_count: 0,
+            chunk_sizes: ChunkHistogram::new(),
+            checksum,
+            checksum_progress: Arc::new(Notify::new()),
+            header_bytes: 0,
+            samples: Vec::new(),
+            sampled_bytes: 0,
+            sampled_at: Instant::now(),
+            stopped_early: false,
+            interrupted_by: None,
+            active_downloads: 0,
+            ttfbs: Vec::new(),
+            protocol: None,
+            requests: 0,
+            local_addrs: HashSet::new(),
+            decoded_bytes: None,
+            reconnects: 0,
+            retries: 0,
+            rate_limited: 0,
+            rate_limited_wait: Duration::ZERO,
+            edge_nodes: Vec::new(),
+            memory: if config.report_mem { Some(MemoryUsage::default()) } else { None },
+            expected_bytes,
+            progress: config.progress_bytes.filter(|_| !quiet).map(|step| Progress {
+                step,
+                next: step,
+                json_stream: config.json_stream,
+                started: Instant::now(),
+                speed_format: config.speed_format(),
+            }),
+            streams: Vec::new(),
+            // The live display shows every connection's rate
+            per_connection: config.per_connection || (!quiet && progress::live(config)),
+        }
+    }
+
+    /*
+    Whether a sample starting at the last tick began within the --warmup
+    */
+    pub(super) fn in_warmup(&self) -> bool {
+        self.warmup_end.is_some_and(|end| self.sampled_at < end)
+    }
+}
+
+/*
+Where --progress-bytes has got to: the milestone spacing and the next one to report
+*/
+struct Progress {
+    step: u64,
+    next: u64,
+    json_stream: bool,
+    started: Instant,
+    speed_format: SpeedFormat,
+}
+
+// How long a connection's rate is averaged over before --reconnect-below judges it
+const RECONNECT_WINDOW: Duration = Duration::from_secs(3);
+
+// How often a download retries after 429 Too Many Requests before failing
+const MAX_RATE_LIMIT_RETRIES: u32 = 5;
+
+// The wait after a 429 without a usable Retry-After, and the most ever waited
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+// The wait before the first --max-retries retry of a failed download, doubling
+// with each further one up to the cap
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(30);
+
+// Don't split the file so finely that a worker gets less than this
+const MIN_BYTES_PER_WORKER: u64 = 64 * 1024;
+
+/*
+Update the state with a new chunk of data
+*/
+async fn update_state(chunk: Bytes, offset: u64, stream: usize, download_state: &Arc<Mutex<DownloadState>>) -> Result<(), SpeedTestError> {
+    let mut state = download_state.lock().await;
+    let bytes = chunk.len() as u64;
+    state.streams[stream].bytes += bytes;
+    state.streams[stream].offset = offset + bytes;
+
+    // The first interval of the rolling average and the first sample both start
+    // with the first byte of the run, so neither counts the wait for it
+    let now: Instant = Instant::now();
+    if state.total_bytes_downloaded == 0 {
+        state.window.restart(now);
+        state.sampled_at = now;
+    }
+
+    // The averages start over once the warm-up is done, with this chunk
+    if state.warmup_bytes.is_none() && state.warmup_end.is_some_and(|end| now >= end) {
+        state.warmup_bytes = Some(state.total_bytes_downloaded);
+        state.window.reset(now);
+    }
+
+    // Add the bytes to the rolling average
+    state.window.record(bytes, now);
+
+    // Add the bytes to the total_bytes_downloaded
+    state.total_bytes_downloaded += bytes;
+    state.chunk_count += 1;
+    state.chunk_sizes.record(bytes);
+
+    // Hash the chunk in file order when verifying the download
+    if let Some(checksum) = state.checksum.as_mut() {
+        let hashed: bool = offset == checksum.next_offset();
+        checksum.update(offset, chunk);
+        if hashed {
+            state.checksum_progress.notify_waiters();
+        }
+    }
+
+    report_progress(&mut state)
+}
+
+/*
+Hold a download that has run ahead of the checksum while the checksum's buffer
+is full, so a verified download doesn't pile up in memory waiting for its first
+range. The range the hash has reached is never held. Nothing waits unless some
+download is on that range, so a range still queued for a connection can't be
+starved by the ones holding them.
+*/
+async fn wait_for_checksum(worker: &Worker, offset: u64) {
+    loop {
+        let state = worker.download_state.lock().await;
+        let held: bool = state.checksum.as_ref().is_some_and(|checksum| {
+            let next: u64 = checksum.next_offset();
+            offset != next && checksum.is_full() && state.streams.iter().any(|s| s.elapsed.is_none() && s.offset == next)
+        });
+        if !held {
+            return;
+        }
+        let progress: Arc<Notify> = state.checksum_progress.clone();
+        let hashed = progress.notified();
+        drop(state);
+        tokio::select! {
+            _ = hashed => {}
+            _ = worker.stop.cancelled() => return,
+        }
+    }
+}
+
+/*
+Print a line for every --progress-bytes milestone the download has just passed,
+with the speed over the recent intervals (or the whole run so far, early on)
+*/
+fn report_progress(state: &mut DownloadState) -> Result<(), SpeedTestError> {
+    let total: u64 = state.total_bytes_downloaded;
+    let recent_bps: Option<u64> = state.window.rolling_bps();
+    let Some(progress) = state.progress.as_mut() else {
+        return Ok(());
+    };
+    if total < progress.next {
+        return Ok(());
+    }
+
+    let elapsed: Duration = progress.started.elapsed();
+    let bps: u64 = recent_bps.unwrap_or_else(|| (total as f64 / elapsed.as_secs_f64()) as u64);
+    while total >= progress.next {
+        if progress.json_stream {
+            emit_json_line(&StreamRecord::Progress {
+                elapsed: elapsed.as_secs_f64(),
+                timestamp: Local::now().to_rfc3339(),
+                cumulative: progress.next,
+                current_bps: bps,
+            })?;
+        } else {
+            print_line(&format!("[{}] Reached {} bytes after {:.1} s at {}", Local::now().format("%Y-%m-%d %H:%M:%S"), progress.next, elapsed.as_secs_f64(), format_speed(bps, progress.speed_format)))?;
+        }
+        progress.next += progress.step;
+    }
+    Ok(())
+}
+
+/*
+Estimate the number of bytes the response head took on the wire
+*/
+fn response_header_bytes(res: &hyper::Response<Body>) -> u64 {
+    // Status line, e.g. "HTTP/1.1 206 Partial Content\r\n"
+    let reason: &str = res.status().canonical_reason().unwrap_or("");
+    let mut bytes: usize = format!("{:?}", res.version()).len() + 1 + 3 + 1 + reason.len() + 2;
+
+    // Each "Name: value\r\n" line plus the blank line ending the head
+    for (name, value) in res.headers() {
+        bytes += name.as_str().len() + 2 + value.len() + 2;
+    }
+    bytes += 2;
+
+    bytes as u64
+}
+
+/*
+Everything a download task needs apart from the range it fetches
+*/
+pub(super) struct Worker {
+    transport: Arc<dyn Transport>,
+    url: Uri,
+    download_state: Arc<Mutex<DownloadState>>,
+    connect_limit: Option<Arc<Semaphore>>,
+    max_ttfb: Option<Duration>,
+    read_timeout: Option<Duration>,
+    request_options: RequestOptions,
+    dump_headers: Option<HeaderDump>,
+    headers_dumped: AtomicBool,
+    interception_checked: AtomicBool,
+    ranged: bool,
+    user_agents: Vec<HeaderValue>,
+    next_user_agent: AtomicUsize,
+    reconnect_below: Option<u64>,
+    max_retries: u32,
+    rate_limit: Option<RateLimiter>,
+    pop_headers: Vec<String>,
+    output: Option<Arc<OutputFile>>,
+    // Whether the downloads are hashed, and so may have to wait for the checksum
+    verifying: bool,
+    stop: CancellationToken,
+}
+
+impl Worker {
+    /*
+    Set up the downloads of one run from the command-line options
+    */
+    pub(super) fn new(transport: Arc<dyn Transport>, url: &Uri, config: &SpeedTestConfig, download_state: Arc<Mutex<DownloadState>>, ranged: bool, output: Option<Arc<OutputFile>>) -> Worker {
+        Worker {
+            transport,
+            url: url.clone(),
+            download_state,
+            connect_limit: config.max_concurrent_connects.map(|n| Arc::new(Semaphore::new(n as usize))),
+            max_ttfb: config.max_ttfb.map(Duration::from_millis),
+            read_timeout: config.timeout.map(Duration::from_secs_f64),
+            request_options: RequestOptions::from_config(config),
+            dump_headers: config.dump_headers,
+            headers_dumped: AtomicBool::new(false),
+            interception_checked: AtomicBool::new(false),
+            ranged,
+            // Checked to be valid header values when the arguments were parsed
+            user_agents: config.user_agent_list.iter()
+                .flat_map(|list| list.0.iter())
+                .filter_map(|agent| HeaderValue::from_str(agent).ok())
+                .collect(),
+            next_user_agent: AtomicUsize::new(0),
+            // Resuming needs a Range request, so a plain GET is never reconnected
+            reconnect_below: config.reconnect_below.filter(|_| ranged),
+            max_retries: config.max_retries,
+            rate_limit: config.limit_rate.map(RateLimiter::new),
+            pop_headers: config.pop_header.clone(),
+            output,
+            verifying: false,
+            stop: shutdown::token(),
+        }
+    }
+
+    /*
+    How a download is named in messages: its byte range, or the whole body when
+    it is fetched without a Range header
+    */
+    fn label(&self, start: u64, end: Option<u64>) -> String {
+        if self.ranged { range_label(start, end) } else { "full body".to_string() }
+    }
+
+    /*
+    Decide whether a failed download is tried again and, if so, wait out the
+    backoff before the retry. A plain GET can only start over, so it is retried
+    only while nothing of its body has been counted. Returns false when the
+    download should fail with the error instead; a stop during the wait is
+    reported through the token.
+    */
+    async fn retry(&self, retries: &mut u32, error: &SpeedTestError, range: &str, offset: u64, resuming: bool) -> bool {
+        if *retries >= self.max_retries || !error.is_transient() || (resuming && !self.ranged) {
+            return false;
+        }
+        *retries += 1;
+        let wait: Duration = RETRY_BACKOFF.saturating_mul(1 << (*retries - 1).min(16)).min(MAX_RETRY_BACKOFF);
+        let from: String = if self.ranged { format!(" from byte {}", offset) } else { String::new() };
+        warn!("{} failed ({}); retrying{} in {:.1} s ({} of {})", range, error, from, wait.as_secs_f64(), retries, self.max_retries);
+        self.download_state.lock().await.retries += 1;
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = self.stop.cancelled() => {}
+        }
+        true
+    }
+}
+
+fn range_label(start: u64, end: Option<u64>) -> String {
+    match end {
+        Some(end) => format!("bytes={}-{}", start, end),
+        None => format!("bytes={}-", start),
+    }
+}
+
+/*
+The first and last byte positions of a "bytes first-last/total" Content-Range
+*/
+fn parse_content_range(value: &str) -> Option<(u64, u64)> {
+    let positions: &str = value.trim().strip_prefix("bytes ")?.split('/').next()?;
+    let (first, last) = positions.split_once('-')?;
+    Some((first.trim().parse().ok()?, last.trim().parse().ok()?))
+}
+
+/*
+Check that a ranged response covers the bytes that were asked for. A server that
+answers from a shifted offset would otherwise corrupt the data silently.
+*/
+fn check_content_range(res: &hyper::Response<Body>, range: &str, start: u64, end: Option<u64>) -> Result<(), SpeedTestError> {
+    let mismatch = |content_range: String| SpeedTestError::ContentRangeMismatch { range: range.to_string(), content_range };
+    match res.status() {
+        StatusCode::PARTIAL_CONTENT => {
+            let value: &str = res.headers().get(CONTENT_RANGE).and_then(|v| v.to_str().ok())
+                .ok_or_else(|| mismatch("no Content-Range".to_string()))?;
+            match parse_content_range(value) {
+                Some((first, last)) if first == start && end.is_none_or(|end| last == end) => Ok(()),
+                _ => Err(mismatch(format!("Content-Range '{}'", value))),
+            }
+        }
+        // The whole file only lines up with a range that starts at its beginning
+        StatusCode::OK if start > 0 => Err(mismatch("the whole file (200 OK)".to_string())),
+        _ => Ok(()),
+    }
+}
+
+/*
+The edge nodes a response names in the --pop-header headers, as "name: value"
+*/
+pub(super) fn edge_nodes(res: &hyper::Response<Body>, names: &[String]) -> Vec<String> {
+    names.iter()
+        .flat_map(|name| res.headers().get_all(name.as_str()).iter()
+            .filter_map(|v| v.to_str().ok())
+            .map(move |v| format!("{}: {}", name, v)))
+        .collect()
+}
+
+/*
+Add edge nodes not seen before, keeping the order they were first seen in
+*/
+pub(super) fn record_edge_nodes(seen: &mut Vec<String>, nodes: Vec<String>) {
+    for node in nodes {
+        if !seen.contains(&node) {
+            seen.push(node);
+        }
+    }
+}
+
+/*
+How long a 429 response asks the client to wait, from a Retry-After of either
+seconds or an HTTP date
+*/
+fn retry_after(res: &hyper::Response<Body>) -> Option<Duration> {
+    let value: &str = res.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date: DateTime<chrono::FixedOffset> = DateTime::parse_from_rfc2822(value).ok()?;
+    // A date already in the past means the client may retry straight away
+    Some((date.with_timezone(&Local) - Local::now()).to_std().unwrap_or(Duration::ZERO))
+}
+
+/*
+Signs that a ranged request was answered by something other than the file, such
+as a transparent proxy or captive portal serving its own page: anything but 206
+Partial Content, or an HTML body
+*/
+fn interception_signs(res: &hyper::Response<Body>) -> Vec<String> {
+    let mut signs: Vec<String> = Vec::new();
+    if res.status() != StatusCode::PARTIAL_CONTENT {
+        signs.push(format!("answered {} instead of 206 Partial Content", res.status()));
+    }
+    if let Some(content_type) = res.headers().get(CONTENT_TYPE).and_then(|v| v.to_str().ok()) {
+        if content_type.trim_start().to_ascii_lowercase().starts_with("text/html") {
+            signs.push(format!("Content-Type is {}", content_type));
+        }
+    }
+    signs
+}
+
+/*
+Whether the first bytes of a body look like the start of an HTML page
+*/
+fn looks_like_html(chunk: &[u8]) -> bool {
+    let head: String = String::from_utf8_lossy(&chunk[..chunk.len().min(512)]).trim_start().to_ascii_lowercase();
+    head.starts_with("<!doctype html") || head.starts_with("<html") || head.starts_with("<head")
+}
+
+/*
+Download a range of bytes from the file, counted as an active download for
+the samples from the first request to the last byte. It is a stream of its own
+unless it is a chunk of the queue, which counts towards the stream of the
+connection that took it.
+*/
+pub(super) async fn start_download(worker: Arc<Worker>, start: u64, end: Option<u64>, stream: Option<usize>) -> Result<(), SpeedTestError> {
+    let download_state: Arc<Mutex<DownloadState>> = worker.download_state.clone();
+    let stream: usize = {
+        let mut state = download_state.lock().await;
+        state.active_downloads += 1;
+        match stream {
+            Some(stream) => {
+                let counter: &mut StreamCounter = &mut state.streams[stream];
+                counter.chunks = Some(counter.chunks.unwrap_or(0) + 1);
+                counter.offset = start;
+                counter.elapsed = None;
+                stream
+            }
+            None => {
+                state.streams.push(StreamCounter::new(worker.label(start, end), start));
+                state.streams.len() - 1
+            }
+        }
+    };
+    let span: tracing::Span = debug_span!("download", stream, range = %worker.label(start, end));
+    let result: Result<(), SpeedTestError> = download_range(worker, start, end, stream).instrument(span).await;
+    let mut state = download_state.lock().await;
+    state.active_downloads -= 1;
+    let counter: &mut StreamCounter = &mut state.streams[stream];
+    counter.elapsed = Some(counter.started.elapsed());
+    state.checksum_progress.notify_waiters();
+    result
+}
+
+/*
+The download itself, over as many connections as reconnects and rate limits take
+*/
+async fn download_range(worker: Arc<Worker>, start: u64, end: Option<u64>, stream: usize) -> Result<(), SpeedTestError> {
+    let download_state: &Arc<Mutex<DownloadState>> = &worker.download_state;
+
+    // Each pass of this loop is one connection; after a reconnect the next one
+    // resumes from the first byte not yet received
+    let range: String = worker.label(start, end);
+    let download_started: Instant = Instant::now();
+    let mut offset: u64 = start;
+    let mut decoder: Option<BrotliCounter> = None;
+    let mut rate_limits: u32 = 0;
+    let mut retries: u32 = 0;
+    // Set when --max-runtime stops the download part way through a body
+    let mut stopped: bool = false;
+    let body: Option<Body> = loop {
+        let resuming: bool = offset > start;
+
+        // Prepare the request
+        let request_range: String = worker.label(offset, end);
+        let mut request = Request::new(Body::empty());
+        *request.method_mut() = hyper::Method::GET;
+        *request.uri_mut() = worker.url.clone();
+        if worker.ranged {
+            request.headers_mut().insert(RANGE, HeaderValue::from_str(&request_range).map_err(|e| SpeedTestError::Other(format!("Invalid range {}: {}", request_range, e)))?);
+        }
+        worker.request_options.apply(request.headers_mut());
+        if !worker.user_agents.is_empty() {
+            let user_agent: &HeaderValue = &worker.user_agents[worker.next_user_agent.fetch_add(1, Ordering::Relaxed) % worker.user_agents.len()];
+            debug!("Requesting {} with User-Agent: {}", request_range, user_agent.to_str().unwrap_or("<binary>"));
+            request.headers_mut().insert(USER_AGENT, user_agent.clone());
+        }
+
+        // Wait for a connect slot; it is held until the first byte arrives
+        let mut connect_permit: Option<OwnedSemaphorePermit> = match &worker.connect_limit {
+            Some(limit) => Some(limit.clone().acquire_owned().await.map_err(|e| SpeedTestError::Other(e.to_string()))?),
+            None => None,
+        };
+
+        // Only the first request of the run has its headers dumped
+        let dump_headers: Option<HeaderDump> = worker.dump_headers.filter(|_| !worker.headers_dumped.swap(true, Ordering::Relaxed));
+        if let Some(mode) = dump_headers {
+            dump::request(&request, mode);
+        }
+
+        // Send the request
+        debug!("Request sent for {}", request_range);
+        let request_sent: Instant = Instant::now();
+        let sending = async {
+            match worker.max_ttfb {
+                Some(max_ttfb) => tokio::time::timeout(max_ttfb, worker.transport.download(request)).await
+                    .map_err(|_| SpeedTestError::TtfbExceeded {
+                        message: format!("No response for {} within the --max-ttfb limit of {} ms", request_range, max_ttfb.as_millis()),
+                    })?,
+                None => worker.transport.download(request).await,
+            }
+        };
+        let res: Result<hyper::Response<Body>, SpeedTestError> = tokio::select! {
+            res = sending => res,
+            _ = worker.stop.cancelled() => break None,
+        };
+        // A 5xx is a failure of the server rather than an answer to the request
+        let res: Result<hyper::Response<Body>, SpeedTestError> = res.and_then(|res| match res.status() {
+            status if status.is_server_error() => Err(SpeedTestError::BadStatus {
+                status,
+                message: format!("{} failed with {}", request_range, status),
+            }),
+            _ => Ok(res),
+        });
+        let res: hyper::Response<Body> = match res {
+            Ok(res) => res,
+            Err(e) => {
+                connect_permit.take();
+                if !worker.retry(&mut retries, &e, &request_range, offset, resuming).await {
+                    return Err(e);
+                }
+                if worker.stop.is_cancelled() {
+                    break None;
+                }
+                continue;
+            }
+        };
+        let ttfb: Duration = request_sent.elapsed();
+        debug!("Response {} after {:.1} ms", res.status(), ttfb.as_secs_f64() * 1000.0);
+        if let Some(mode) = dump_headers {
+            dump::response(&res, mode);
+        }
+        // Wait out a rate limit and ask again, keeping count so the summary can
+        // show the server capped the parallel downloads
+        if res.status() == StatusCode::TOO_MANY_REQUESTS {
+            rate_limits += 1;
+            if rate_limits > MAX_RATE_LIMIT_RETRIES {
+                return Err(SpeedTestError::BadStatus {
+                    status: res.status(),
+                    message: format!("{} was still rate-limited (429 Too Many Requests) after {} retries", request_range, MAX_RATE_LIMIT_RETRIES),
+                });
+            }
+            let wait: Duration = retry_after(&res).unwrap_or(DEFAULT_RETRY_AFTER).min(MAX_RETRY_AFTER);
+            debug!("{} was rate-limited (429 Too Many Requests); retrying in {:.1} s", request_range, wait.as_secs_f64());
+            let mut state: tokio::sync::MutexGuard<'_, DownloadState> = download_state.lock().await;
+            if rate_limits == 1 {
+                state.rate_limited += 1;
+            }
+            state.rate_limited_wait += wait;
+            drop(state);
+            drop(res);
+            connect_permit.take();
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => continue,
+                _ = worker.stop.cancelled() => break None,
+            }
+        }
+        if worker.ranged {
+            check_content_range(&res, &request_range, offset, end)?;
+        }
+        // The first ranged response of the run is checked for interception, so a
+        // portal page isn't reported as a fast download
+        let mut sniff_body: bool = worker.ranged && !worker.interception_checked.swap(true, Ordering::Relaxed);
+        if sniff_body {
+            let signs: Vec<String> = interception_signs(&res);
+            if !signs.is_empty() {
+                warn!("the response to {} doesn't look like partial content of the file ({}); a transparent proxy or captive portal may be intercepting the download and the measured speed may be bogus", range, signs.join(", "));
+                sniff_body = false;
+            }
+        }
+        let header_bytes: u64 = response_header_bytes(&res);
+        let nodes: Vec<String> = edge_nodes(&res, &worker.pop_headers);
+        let protocol: String = format!("{:?}", res.version());
+        let local_addr: Option<SocketAddr> = res.extensions().get::<HttpInfo>().map(|info| info.local_addr());
+        let expected_bytes: Option<u64> = res.headers().get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok());
+
+        // A Brotli stream can only be decoded from its first byte; a resumed
+        // connection carries on feeding the same decoder
+        if !resuming {
+            decoder = match content_encoding(&res).as_deref() {
+                Some("br") if start == 0 => Some(BrotliCounter::new()),
+                Some("br") => return Err(SpeedTestError::Decode(format!("Cannot decode a Brotli-encoded response for {}: the range does not start at the beginning of the stream", range))),
+                _ => None,
+            };
+        }
+        let mut body: Body = res.into_body();
+
+        let mut state: tokio::sync::MutexGuard<'_, DownloadState> = download_state.lock().await;
+        state.header_bytes += header_bytes;
+        state.ttfbs.push(ttfb);
+        state.protocol.get_or_insert(protocol);
+        record_edge_nodes(&mut state.edge_nodes, nodes);
+        state.requests += 1;
+        // Each TCP connection has its own local port, so distinct local addresses
+        // count distinct connections
+        if let Some(local_addr) = local_addr {
+            state.local_addrs.insert(local_addr);
+        }
+        drop(state);
+
+        // Process each chunk of data as it arrives, watching this connection's
+        // rate over successive windows when --reconnect-below is set
+        let connection_start: u64 = offset;
+        let mut window: (Instant, u64) = (Instant::now(), offset);
+        let ended: Result<bool, SpeedTestError> = loop {
+            let reading = async {
+                match worker.read_timeout {
+                    Some(read_timeout) => match tokio::time::timeout(read_timeout, body.next()).await {
+                        Ok(next) => Ok(next),
+                        Err(_) => {
+                            // Tell a server that stopped short of its Content-Length apart
+                            // from one that simply went quiet
+                            let received: u64 = offset - connection_start;
+                            Err(SpeedTestError::Timeout(match expected_bytes {
+                                Some(expected) if received < expected => format!("{} incomplete: expected {} bytes, got {} before the server stopped sending for {:.1} s", request_range, expected, received, read_timeout.as_secs_f64()),
+                                _ => format!("{} timed out: no data for {:.1} s", request_range, read_timeout.as_secs_f64()),
+                            }))
+                        }
+                    },
+                    None => Ok(body.next().await),
+                }
+            };
+            let next: Option<Result<Bytes, hyper::Error>> = tokio::select! {
+                next = reading => match next {
+                    Ok(next) => next,
+                    Err(e) => break Err(e),
+                },
+                _ = worker.stop.cancelled() => {
+                    stopped = true;
+                    break Ok(false);
+                }
+            };
+            let chunk: Bytes = match next {
+                Some(Ok(chunk)) => chunk,
+                Some(Err(e)) => break Err(e.into()),
+                None => break Ok(false),
+            };
+            connect_permit.take();
+            if offset == connection_start {
+                debug!("First byte after {:.1} ms", request_sent.elapsed().as_secs_f64() * 1000.0);
+            }
+            trace!("Received {} bytes at byte {}", chunk.len(), offset);
+            if std::mem::take(&mut sniff_body) && looks_like_html(&chunk) {
+                warn!("the body of {} starts like an HTML page; a transparent proxy or captive portal may be intercepting the download and the measured speed may be bogus", range);
+            }
+            let len: u64 = chunk.len() as u64;
+            if let Some(decoder) = decoder.as_mut() {
+                decoder.update(&chunk)?;
+            }
+            if let Some(output) = &worker.output {
+                output.write(offset, &chunk)?;
+            }
+            update_state(chunk, offset, stream, download_state).await?;
+            offset += len;
+            if worker.verifying {
+                wait_for_checksum(&worker, offset).await;
+            }
+            if let Some(limiter) = &worker.rate_limit {
+                tokio::select! {
+                    _ = limiter.take(len) => {}
+                    _ = worker.stop.cancelled() => {
+                        stopped = true;
+                        break Ok(false);
+                    }
+                }
+            }
+
+            if let Some(threshold) = worker.reconnect_below {
+                let elapsed: Duration = window.0.elapsed();
+                if elapsed >= RECONNECT_WINDOW {
+                    let rate: f64 = (offset - window.1) as f64 / elapsed.as_secs_f64();
+                    // Only resume when there is something left to ask for
+                    let remaining: bool = match (expected_bytes, end) {
+                        (Some(expected), _) => offset - connection_start < expected,
+                        (None, Some(end)) => offset <= end,
+                        (None, None) => false,
+                    };
+                    if rate < threshold as f64 && remaining {
+                        debug!("Reconnecting {} at byte {}: {:.0} B/s over the last {:.1} s is below --reconnect-below", range, offset, rate, elapsed.as_secs_f64());
+                        break Ok(true);
+                    }
+                    window = (Instant::now(), offset);
+                }
+            }
+        };
+        let slow: bool = match ended {
+            Ok(slow) => slow,
+            // Whatever arrived before the failure is kept, and a ranged retry
+            // asks only for the rest
+            Err(e) => {
+                drop(body);
+                if !worker.retry(&mut retries, &e, &request_range, offset, offset > start).await {
+                    return Err(e);
+                }
+                if worker.stop.is_cancelled() {
+                    break None;
+                }
+                continue;
+            }
+        };
+        if !slow {
+            break Some(body);
+        }
+        // Dropping the unfinished body closes its connection
+        drop(body);
+        download_state.lock().await.reconnects += 1;
+    };
+
+    debug!("Finished with {} bytes in {:.3} s{}", offset - start, download_started.elapsed().as_secs_f64(), if stopped { ", stopped early" } else { "" });
+
+    // What arrived before a stop still counts, but the rest of the body, and with
+    // it the decoded size and any trailers, never will
+    let Some(mut body) = body.filter(|_| !stopped) else {
+        return Ok(());
+    };
+
+    if let Some(decoder) = decoder {
+        let decoded: u64 = decoder.finish()
+            .map_err(|e| SpeedTestError::Decode(format!("Failed to decode the Brotli response for {}: {}", range, e)))?;
+        *download_state.lock().await.decoded_bytes.get_or_insert(0) += decoded;
+    }
+
+    // Servers may send trailers after the body (HTTP/2 in particular), which can
+    // carry completion status, e.g. grpc-status
+    if let Some(trailers) = body.trailers().await? {
+        for (name, value) in trailers.iter() {
+            debug!("Trailer for {}: {}: {}", range, name, value.to_str().unwrap_or("<binary>"));
+        }
+        if let Some(status) = trailers.get("grpc-status") {
+            if status != "0" {
+                warn!("{} ended with grpc-status {}", range, status.to_str().unwrap_or("<binary>"));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/*
+Pick a sane worker count from --connections or the detected CPU count: at least
+one, and no more than the download can be usefully split between
+*/
+pub(super) fn worker_count(wanted: u64, length: u64, requested: bool) -> u64 {
+    let max_workers: u64 = max(length / MIN_BYTES_PER_WORKER, 1);
+    let workers: u64 = wanted.clamp(1, max_workers);
+    if workers != wanted {
+        let source: &str = if requested { "the --connections count" } else { "the detected CPU count" };
+        info!("Using {} worker(s) instead of {} of {} for a {} byte download", workers, source, wanted, length);
+    }
+    workers
+}
+
+/*
+Split the window [offset, offset + length) into one contiguous range per worker,
+evenly or in proportion to the given weights (one per worker). The last range is
+open-ended when the window runs to the end of the file.
+*/
+pub(super) fn compute_ranges(offset: u64, length: u64, content_length: u64, workers: u64, weights: Option<&[u64]>) -> Vec<(u64, Option<u64>)> {
+    // Where the share of worker i starts within the window
+    let boundary = |i: u64| -> u64 {
+        match weights {
+            Some(weights) => weighted_boundary(length, weights, i),
+            None => i * (length / workers),
+        }
+    };
+    let window_end: u64 = offset + length;
+    (0..workers)
+        .map(|i| {
+            let start: u64 = offset + boundary(i);
+            let end: Option<u64> = if i < workers - 1 {
+                Some(offset + boundary(i + 1) - 1)
+            } else if window_end < content_length {
+                Some(window_end - 1)
+            } else {
+                None
+            };
+            (start, end)
+        })
+        .collect()
+}
+
+/*
+Split the window [offset, offset + length) into chunks of chunk_size bytes, the
+last one shorter and open-ended when the window runs to the end of the file
+*/
+pub(super) fn compute_chunks(offset: u64, length: u64, content_length: u64, chunk_size: u64) -> Vec<(u64, Option<u64>)> {
+    let window_end: u64 = offset + length;
+    (offset..window_end)
+        .step_by(chunk_size as usize)
+        .map(|start| {
+            let end: u64 = start.saturating_add(chunk_size).min(window_end);
+            (start, Some(end - 1).filter(|_| end < content_length))
+        })
+        .collect()
+}
+
+/*
+Where the share of worker i starts within a window of the given length when it
+is split in proportion to the weights
+*/
+pub(super) fn weighted_boundary(length: u64, weights: &[u64], i: u64) -> u64 {
+    let total: u128 = weights.iter().map(|w| *w as u128).sum();
+    let before: u128 = weights[..i as usize].iter().map(|w| *w as u128).sum();
+    (length as u128 * before / total) as u64
+}
+
+// The --chunk-size chunks not yet taken by a connection
+type ChunkQueue = std::sync::Mutex<VecDeque<(u64, Option<u64>)>>;
+
+/*
+Download the given ranges in parallel while sampling progress, returning the
+final state once every range has finished along with how long it took
+*/
+pub(super) async fn run_speed_test(transport: Arc<dyn Transport>, url: &Uri, config: &SpeedTestConfig, transfer: Transfer, checksum: Option<ChecksumVerifier>, quiet: bool, metrics: Option<Arc<LiveMetrics>>) -> Result<(DownloadState, Duration), SpeedTestError> {
+    // Create the shared download state
+    let verifying: bool = checksum.is_some();
+    let download_state: Arc<Mutex<DownloadState>> = Arc::new(Mutex::new(DownloadState::new(config, checksum, transfer.length, quiet || config.quiet)));
+
+    // Start the print loop, which writes each sample to stdout, as a live display
+    // on a terminal, to any --csv file and to the --metrics-listen endpoint.
+    // --quiet leaves stdout to the summary but still fills the --csv file.
+    let mut sinks: Vec<Box<dyn MetricsSink>> = Vec::new();
+    if !quiet {
+        if !config.quiet && progress::live(config) {
+            sinks.push(Box::new(LiveDisplay::new(config)));
+        } else if !config.quiet {
+            sinks.push(Box::new(StdoutSink::new(config)));
+        }
+        if let Some(path) = &config.csv {
+            sinks.push(Box::new(CsvSink::open(path)?));
+        }
+    }
+    if let Some(metrics) = metrics {
+        sinks.push(Box::new(MetricsRecorder::new(metrics)));
+    }
+    let worker: Arc<Worker> = Arc::new(Worker { verifying, ..Worker::new(transport, url, config, download_state.clone(), transfer.ranged, transfer.output) });
+    let test_start: Instant = Instant::now();
+    let mut print_handle = tokio::spawn(print_loop(download_state.clone(), sinks, test_start, worker.stop.clone()));
+
+    // Start the downloads
+    // Each task returns the downloads that failed in it
+    let ranges: Vec<(u64, Option<u64>)> = transfer.ranges;
+    let downloads: usize = ranges.len();
+    let fail_fast: bool = config.fail_fast;
+    let mut handles: Vec<tokio::task::JoinHandle<Vec<DownloadFailure>>> = Vec::new();
+    if config.single_connection {
+        // hyper never pipelines, so each request waits for the previous response
+        // and then reuses its connection from the pool
+        let worker: Arc<Worker> = worker.clone();
+        handles.push(tokio::spawn(async move {
+            let mut failures: Vec<DownloadFailure> = Vec::new();
+            for (start, end) in ranges {
+                if let Err(error) = start_download(worker.clone(), start, end, None).await {
+                    failures.push(DownloadFailure { worker: 0, range: worker.label(start, end), error });
+                    if fail_fast {
+                        break;
+                    }
+                }
+            }
+            failures
+        }));
+    } else if let Some(connections) = transfer.shared_by {
+        // Each connection takes the next chunk as soon as it has finished one, so
+        // a slow path holds up no more than the chunk it is on. Its chunks are
+        // counted as one stream, so fairness compares connections, not chunks.
+        let queue: Arc<ChunkQueue> = Arc::new(std::sync::Mutex::new(ranges.into()));
+        let first: usize = {
+            let mut state = download_state.lock().await;
+            let first: usize = state.streams.len();
+            state.streams.extend((0..connections).map(|_| StreamCounter::new("chunks".to_string(), 0)));
+            first
+        };
+        for index in 0..connections as usize {
+            let (worker, queue) = (worker.clone(), queue.clone());
+            handles.push(tokio::spawn(async move {
+                let mut failures: Vec<DownloadFailure> = Vec::new();
+                while !worker.stop.is_cancelled() {
+                    let Some((start, end)) = queue.lock().unwrap().pop_front() else {
+                        break;
+                    };
+                    if let Err(error) = start_download(worker.clone(), start, end, Some(first + index)).await {
+                        failures.push(DownloadFailure { worker: index, range: worker.label(start, end), error });
+                        if fail_fast {
+                            break;
+                        }
+                    }
+                }
+                failures
+            }));
+        }
+    } else {
+        // A timed test of a file that can't be split keeps fetching the whole file
+        // until the time is up; a checksum covers only one copy, so not then
+        let repeat: bool = config.max_runtime.is_some() && !transfer.ranged && config.validate_checksum.is_none() && !config.verify_etag;
+        for (index, (start, end)) in ranges.into_iter().enumerate() {
+            let worker: Arc<Worker> = worker.clone();
+            handles.push(tokio::spawn(async move {
+                let range: String = worker.label(start, end);
+                loop {
+                    match start_download(worker.clone(), start, end, None).await {
+                        Ok(()) if repeat && !worker.stop.is_cancelled() => continue,
+                        Ok(()) => break Vec::new(),
+                        Err(error) => break vec![DownloadFailure { worker: index, range, error }],
+                    }
+                }
+            }));
+        }
+    }
+    // --max-runtime and a Ctrl-C stop the downloads rather than aborting them, so
+    // each one still records what it received
+    let stop: CancellationToken = worker.stop.clone();
+    drop(worker);
+    let watchdog: Option<tokio::task::JoinHandle<()>> = config.max_runtime.map(|seconds| {
+        let stop: CancellationToken = stop.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs_f64(seconds)).await;
+            stop.cancel();
+        })
+    });
+
+    // Wait for the downloads to finish, giving up early if the print loop fails
+    // or, with --fail-fast, as soon as any download fails
+    let abort_handles: Vec<tokio::task::AbortHandle> = handles.iter().map(|h| h.abort_handle()).collect();
+    let downloads = async {
+        let mut pending: FuturesUnordered<tokio::task::JoinHandle<Vec<DownloadFailure>>> = handles.into_iter().collect();
+        let mut failures: Vec<DownloadFailure> = Vec::new();
+        while let Some(finished) = pending.next().await {
+            failures.extend(finished.map_err(|e| SpeedTestError::Other(format!("A download task failed: {}", e)))?);
+            if fail_fast && !failures.is_empty() {
+                abort_handles.iter().for_each(|h| h.abort());
+                break;
+            }
+        }
+        if failures.is_empty() {
+            Ok(())
+        } else if fail_fast {
+            // Keep the original error, and with it any exit code it carries
+            let failure: DownloadFailure = failures.swap_remove(0);
+            warn!("Worker {} ({}) failed; stopping the remaining downloads", failure.worker, failure.range);
+            Err(failure.error)
+        } else {
+            failures.sort_by_key(|f| f.worker);
+            Err(SpeedTestError::DownloadsFailed { failures, downloads })
+        }
+    };
+    tokio::pin!(downloads);
+    let mut printing: bool = true;
+    loop {
+        tokio::select! {
+            result = &mut downloads => break result?,
+            result = &mut print_handle, if printing => {
+                let result: Result<(), SpeedTestError> = result.map_err(|e| SpeedTestError::Other(format!("The print loop failed: {}", e)))?;
+                // It ends of its own accord when the downloads are stopped, and
+                // they are given the time to wind down
+                if result.is_ok() && stop.is_cancelled() {
+                    printing = false;
+                    continue;
+                }
+                abort_handles.iter().for_each(|h| h.abort());
+                return Err(result.err().unwrap_or_else(|| SpeedTestError::Other("the print loop stopped unexpectedly".to_string())));
+            }
+        }
+    }
+    let elapsed: Duration = test_start.elapsed();
+    if let Some(watchdog) = watchdog {
+        watchdog.abort();
+    }
+
+    // Note how far into the final interval the download got, so the bytes received
+    // since the last rollup still count towards the average
+    {
+        let mut state = download_state.lock().await;
+        // The final sample and the final average end at the same instant
+        let now: Instant = Instant::now();
+        state.window.finish(now);
+        // A signal stops the downloads through the same token as --max-runtime
+        state.interrupted_by = shutdown::signal().filter(|_| stop.is_cancelled());
+        state.stopped_early = stop.is_cancelled() && state.interrupted_by.is_none();
+        // Sample the time since the last tick too when it is long enough to mean
+        // something, or when a short or stopped run has no other samples
+        let unsampled: Duration = now.duration_since(state.sampled_at);
+        let unsampled_bytes: u64 = state.total_bytes_downloaded - state.sampled_bytes;
+        if unsampled_bytes > 0 && (unsampled * 2 >= state.sample_interval || state.samples.is_empty()) {
+            let bps: u64 = (unsampled_bytes as f64 / unsampled.as_secs_f64()) as u64;
+            let warmup: bool = state.in_warmup();
+            state.samples.push(Sample { elapsed: elapsed.as_secs_f64(), bps, warmup });
+        }
+        // A final sample so even a run shorter than one interval reports its memory use
+        if let Some(memory) = &mut state.memory {
+            memory.sample();
+        }
+    }
+
+    // Stop the print loop and wait for it to release the state
+    if printing {
+        print_handle.abort();
+        let _ = print_handle.await;
+    }
+
+    let state: DownloadState = Arc::try_unwrap(download_state)
+        .map_err(|_| SpeedTestError::Other("download state is still in use".to_string()))?
+        .into_inner();
+    Ok((state, elapsed))
+}
+
+/*
+The downloads making up one run: the ranges to fetch, whether to send them as
+Range requests, and how many bytes they add up to
+*/
+#[derive(Clone)]
+pub(super) struct Transfer {
+    pub(super) ranges: Vec<(u64, Option<u64>)>,
+    pub(super) ranged: bool,
+    // None when the server didn't say how long the file is
+    pub(super) length: Option<u64>,
+    // With --chunk-size, the ranges are chunks queued for this many
+    // connections to take in turn rather than one range per connection
+    pub(super) shared_by: Option<u64>,
+    pub(super) output: Option<Arc<OutputFile>>,
+}
@@ -0,0 +1,414 @@
+mod collect;
+mod download;
+mod modes;
+mod probe;
+mod sampling;
+mod transport;
+mod uploads;
+
+use crate::checksum::ChecksumVerifier;
+use chrono::Local;
+use crate::error::SpeedTestError;
+use crate::connector::Connector;
+use crate::config::{HttpVersion, PayloadPattern, ResolveOverride, SpeedTestConfig};
+use hyper::{Uri, http::HeaderValue};
+use hyper_tls::HttpsConnector;
+use crate::report::{InterfaceResult, SpeedTestResult, StackResult, TargetResult};
+use serde::Serialize;
+use crate::sink::StreamRate;
+use crate::{dns, logging, mem, metrics, serve, shutdown, stats};
+use crate::proxy::Proxy;
+use crate::template::Template;
+use std::collections::HashMap;
+use std::io::{ErrorKind, Write};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Instant, Duration};
+use tracing::warn;
+use transport::{build_client, build_connector, resolver};
+use probe::{ProbedFile, file_checksum, measure_latency, probe_file, select_server};
+use modes::{compare_interfaces, compare_stacks, read_url_file, repeat_range, scale_test, test_targets};
+use collect::{Measurement, Plan, check_measurement, collect, measure, plan};
+use uploads::upload;
+
+pub(crate) use sampling::{format_average, format_eta};
+pub(crate) use transport::{DeliveryClient, HttpClient, delivery_client};
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum StreamRecord<'a> {
+    Sample {
+        elapsed: f64,
+        timestamp: String,
+        bytes_this_second: u64,
+        cumulative: u64,
+        current_bps: u64,
+        avg_bps: Option<u64>,
+        connections: u64,
+        eta_seconds: Option<f64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        streams: Option<&'a [StreamRate]>,
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        warmup: bool,
+    },
+    Progress {
+        elapsed: f64,
+        timestamp: String,
+        cumulative: u64,
+        current_bps: u64,
+    },
+    Summary {
+        timestamp: String,
+        #[serde(flatten)]
+        result: &'a SpeedTestResult,
+    },
+    Upload {
+        timestamp: String,
+        bytes: u64,
+        seconds: f64,
+        bps: u64,
+        peak_bps: u64,
+        connections: u64,
+        payload_pattern: PayloadPattern,
+        stopped_early: bool,
+        interrupted_by: Option<&'static str>,
+    },
+    Comparison {
+        timestamp: String,
+        interfaces: &'a [InterfaceResult],
+    },
+    Stacks {
+        timestamp: String,
+        stacks: &'a [StackResult],
+    },
+    Targets {
+        timestamp: String,
+        targets: &'a [TargetResult],
+        aggregate_bps: u64,
+    },
+    ScaleLevel {
+        timestamp: String,
+        connections: u64,
+        bps: u64,
+    },
+    ScaleKnee {
+        timestamp: String,
+        connections: u64,
+        bps: u64,
+    },
+    Iteration {
+        iteration: u32,
+        bytes: u64,
+        seconds: f64,
+        bps: u64,
+        ttfb_ms: f64,
+        cache_status: Option<String>,
+    },
+}
+
+/*
+Write a line to stdout and flush it so consumers see it immediately. Unlike
+println! this doesn't panic when stdout is closed, e.g. when piped into head;
+that becomes SpeedTestError::BrokenPipe so the test can stop cleanly.
+*/
+pub(crate) fn print_line(line: &str) -> Result<(), SpeedTestError> {
+    let mut stdout = std::io::stdout().lock();
+    writeln!(stdout, "{}", line).and_then(|_| stdout.flush()).map_err(|e| {
+        if e.kind() == ErrorKind::BrokenPipe {
+            SpeedTestError::BrokenPipe
+        } else {
+            e.into()
+        }
+    })
+}
+
+/*
+Write a single JSON line to stdout
+*/
+fn emit_json_line(record: &StreamRecord) -> Result<(), SpeedTestError> {
+    let line: String = serde_json::to_string(record).map_err(|e| SpeedTestError::Other(format!("Failed to serialize output: {}", e)))?;
+    print_line(&line)
+}
+
+/*
+Run the command-line tool with its parsed configuration, printing progress and
+the summary to stdout
+*/
+pub async fn run(mut config: SpeedTestConfig) -> Result<(), SpeedTestError> {
+    logging::init(&config);
+
+    // Serve mode answers requests rather than making them
+    if let Some(listen) = config.serve {
+        let seed: u64 = *config.seed.get_or_insert_with(rand::random);
+        return serve::serve(listen, config.payload_pattern, seed).await;
+    }
+    shutdown::listen();
+
+    let template: Option<Template> = config.template.as_deref().map(Template::parse).transpose()?;
+    if let Some(path) = &config.url_file {
+        let listed: Vec<String> = read_url_file(path)?;
+        config.urls.extend(listed);
+        config.url = config.urls.first().cloned().ok_or_else(|| SpeedTestError::Config(format!("{} lists no URLs", path.display())))?;
+    }
+    if let Some(provider) = config.provider.clone() {
+        config.url = select_server(&config, &provider).await?;
+        config.urls = vec![config.url.clone()];
+    }
+    if config.urls.len() > 1 {
+        return test_targets(config, template.as_ref()).await;
+    }
+
+    // A --seed is already known, so only a chosen one is reported
+    let seed_chosen: bool = config.seed.is_none();
+    let Target { url, checksum, resolved, dns_time, seed, connections } = prepare(&mut config).await?;
+
+    // Report the seed so a randomized run can be repeated
+    let random_payload: bool = config.upload && matches!(config.payload_pattern, PayloadPattern::Random);
+    if seed_chosen && (config.shuffle_ranges || random_payload) && !config.json_stream && !config.quiet {
+        print_line(&format!("Using random seed {}", seed))?;
+    }
+
+    if config.report_mem && mem::rss_bytes().is_none() {
+        warn!("--report-mem is not supported on this platform; memory use will not be reported");
+    }
+
+    if config.print_config || config.verbose > 0 {
+        config.print(connections);
+    }
+
+    // Comparing interfaces repeats everything below once per local address
+    if !config.compare_interfaces.is_empty() {
+        return compare_interfaces(&config, &url, &resolved, connections, dns_time, checksum, template.as_ref()).await;
+    }
+
+    if config.compare_stacks {
+        return compare_stacks(&config, &url, &resolved, connections, dns_time, checksum, template.as_ref()).await;
+    }
+
+    let client: Arc<HttpClient> = build_client(&config, &resolved, None)?;
+
+    // Upload mode sends data to the URL rather than probing it for a file
+    if config.upload {
+        return upload(client, url, &config, seed).await;
+    }
+
+    let file: ProbedFile = probe_file(&client, &url, &config).await?;
+    let checksum: Option<ChecksumVerifier> = file_checksum(&file, &config, checksum)?;
+
+    // Like cache probing, the scale test replaces the parallel test entirely
+    if let Some(max_connections) = config.scale_test {
+        return scale_test(&client, &file, &config, max_connections).await;
+    }
+
+    // Cache probing replaces the parallel test entirely
+    if let Some(iterations) = config.repeat_range {
+        return repeat_range(client, file.url, config.offset, config.offset + file.length - 1, iterations, &config).await;
+    }
+
+    let mut plan: Plan = plan(&file, &config, connections, seed, dns_time)?;
+    let url: Uri = file.url;
+    if let Some(count) = config.latency {
+        let connector: HttpsConnector<Connector> = build_connector(&config, resolver(&config, &resolved), None)?;
+        let (ttfbs, connects) = measure_latency(&client, connector, &url, &config, count).await?;
+        plan.latency = stats::latency_summary(&ttfbs);
+        plan.connect_time = stats::latency_summary(&connects);
+    }
+    plan.metrics = config.metrics_listen.map(metrics::listen).transpose()?;
+
+    // In monitor mode, with --interval or --repeat, a Ctrl-C ends the loop with
+    // the summary of the measurement it cut short
+    let looping: bool = config.monitor.is_some() || config.repeat.is_some();
+
+    let mut measurement: u64 = 0;
+    loop {
+        let measurement_start: Instant = Instant::now();
+        measurement += 1;
+        if looping && !config.json_stream && !config.quiet {
+            let of: String = config.repeat.map(|n| format!(" of {}", n)).unwrap_or_default();
+            print_line(&format!("[{}] Measurement {}{}", Local::now().format("%Y-%m-%d %H:%M:%S"), measurement, of))?;
+        }
+
+        match measure(&client, &url, &config, &plan, checksum.clone(), template.as_ref()).await {
+            Ok(result) => {
+                if let Some(metrics) = &plan.metrics {
+                    metrics.finished(&result);
+                }
+            }
+            Err(e) => {
+                if let Some(metrics) = &plan.metrics {
+                    metrics.failed();
+                }
+                // A monitor keeps going through failed measurements, but not once
+                // nobody is reading the output or it has been interrupted
+                if !looping || matches!(e, SpeedTestError::BrokenPipe) || shutdown::signal().is_some() {
+                    return Err(e);
+                }
+                warn!("Measurement {} failed: {}", measurement, e);
+            }
+        }
+
+        if !looping || config.repeat == Some(measurement) {
+            break;
+        }
+        let interval: Duration = Duration::from_secs_f64(config.monitor.unwrap_or(0.0));
+        tokio::select! {
+            _ = tokio::time::sleep_until((measurement_start + interval).into()) => {}
+            _ = shutdown::requested() => break,
+        }
+    }
+
+    Ok(())
+}
+
+/*
+Run a single download test without printing anything but warnings, for the
+library API
+*/
+pub(crate) async fn run_report(mut config: SpeedTestConfig) -> Result<SpeedTestResult, SpeedTestError> {
+    let Target { url, checksum, resolved, dns_time, seed, connections } = prepare(&mut config).await?;
+    let client: Arc<HttpClient> = build_client(&config, &resolved, None)?;
+    let file: ProbedFile = probe_file(&client, &url, &config).await?;
+    let checksum: Option<ChecksumVerifier> = file_checksum(&file, &config, checksum)?;
+    let plan: Plan = plan(&file, &config, connections, seed, dns_time)?;
+    let measurement: Measurement = collect(&client, &file.url, &config, &plan, checksum, true).await?;
+    check_measurement(&config, &measurement)?;
+    Ok(measurement.result)
+}
+
+/*
+The parsed URL and everything worked out from the configuration before the
+first request: the checksum to verify, the addresses found for the host, the
+seed and the connection count
+*/
+struct Target {
+    url: Uri,
+    checksum: Option<ChecksumVerifier>,
+    resolved: HashMap<String, Vec<IpAddr>>,
+    dns_time: Option<Duration>,
+    seed: u64,
+    connections: u64,
+}
+
+/*
+Validate the configuration and resolve the host, filling in the seed and any
+Host header the options imply
+*/
+async fn prepare(config: &mut SpeedTestConfig) -> Result<Target, SpeedTestError> {
+    let url: Uri = config.url.parse::<Uri>()
+        .map_err(|e| SpeedTestError::InvalidUrl { url: config.url.clone(), reason: e.to_string() })?;
+    let checksum: Option<ChecksumVerifier> = config.validate_checksum.as_deref().map(ChecksumVerifier::parse).transpose()?
+        .map(|c| c.starting_at(config.offset));
+    // Checked here rather than by clap, which can't tie the subcommand-wide
+    // --connections to download-only options
+    if config.connections.is_some() && (config.no_range || !config.weights.is_empty()) {
+        return Err(SpeedTestError::Config("--connections can't be combined with --no-range or --weights, which set the connection count themselves".to_string()));
+    }
+    if config.sni.is_some() && url.scheme_str() != Some("https") {
+        return Err(SpeedTestError::Config("--sni needs an https:// URL".to_string()));
+    }
+    if config.http_version == Some(HttpVersion::Http2) && config.connection_close {
+        return Err(SpeedTestError::Config("--connection-close can't be used with --http-version 2, which has no Connection header".to_string()));
+    }
+    if config.h2_single_connection && config.http_version != Some(HttpVersion::Http2) {
+        return Err(SpeedTestError::Config("--h2-single-connection needs --http-version 2".to_string()));
+    }
+    let tls_limited: bool = config.tls_min_version.is_some() || config.tls_max_version.is_some();
+    if tls_limited && url.scheme_str() != Some("https") {
+        return Err(SpeedTestError::Config("--tls-min-version and --tls-max-version need an https:// URL".to_string()));
+    }
+    if let (Some(min), Some(max)) = (config.tls_min_version, config.tls_max_version) {
+        if min > max {
+            return Err(SpeedTestError::Config(format!("--tls-min-version {} is newer than --tls-max-version {}", min.name(), max.name())));
+        }
+    }
+    if let Some(host) = &config.host_header {
+        HeaderValue::from_str(host).map_err(|e| SpeedTestError::Config(format!("Invalid --host-header '{}': {}", host, e)))?;
+    }
+    // Overriding SNI alone must not change the Host header, so keep the URL's unless told otherwise
+    if config.sni.is_some() && config.host_header.is_none() {
+        config.host_header = url.authority().map(|a| a.to_string());
+    }
+
+    let seed: u64 = *config.seed.get_or_insert_with(rand::random);
+    let connections: u64 = config.connections.unwrap_or(num_cpus::get() as u64);
+
+    // Resolve the host up front so DNS time is measured separately from the transfer;
+    // the connections then reuse the addresses found here
+    let host: &str = url.host()
+        .ok_or_else(|| SpeedTestError::InvalidUrl { url: config.url.clone(), reason: "it has no host".to_string() })?;
+    let host: &str = host.trim_start_matches('[').trim_end_matches(']');
+    let port: u16 = url.port_u16().unwrap_or(if url.scheme_str() == Some("https") { 443 } else { 80 });
+    if let Some(ip) = config.source_ip {
+        if let Some(family) = config.address_family().filter(|family| !family.includes(&ip)) {
+            return Err(SpeedTestError::Config(format!("--source-ip {} is not an {} address", ip, family)));
+        }
+    }
+    // The connector dials an address in the URL without asking the resolver,
+    // which is what keeps to the -4 or -6 family or that of --source-ip
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        if let Some(family) = config.address_family().filter(|family| !family.includes(&ip)) {
+            return Err(SpeedTestError::Config(match config.source_ip {
+                Some(source) if !config.ipv4 && !config.ipv6 => format!("{} can't be reached from --source-ip {}, which is an {} address", ip, source, family),
+                _ => format!("{} is not an {} address", ip, family),
+            }));
+        }
+        if config.compare_stacks {
+            return Err(SpeedTestError::Config("--compare-stacks needs a host name rather than an address, to connect to over both IPv4 and IPv6".to_string()));
+        }
+    }
+    // A --resolve address for the URL's host and port is used as if looked up.
+    // The resolver only sees host names, so for any other host, such as one a
+    // redirect leads to, the port isn't checked.
+    let mut resolved: HashMap<String, Vec<IpAddr>> = HashMap::new();
+    for entry in &config.resolve {
+        resolved.entry(entry.host.clone()).or_insert_with(|| entry.addresses.clone());
+    }
+    let overridden: Option<&ResolveOverride> = config.resolve.iter().find(|entry| entry.host.eq_ignore_ascii_case(host) && entry.port == port);
+    // Over a Unix socket the host only names the Host header, and most proxies
+    // look it up themselves, so then nothing is resolved
+    let proxy: Option<Proxy> = config.proxy.as_deref().map(|url| Proxy::parse(url, config.proxy_user.as_deref())).transpose().map_err(SpeedTestError::Config)?;
+    let dns_time: Option<Duration> = if let Some(entry) = overridden {
+        resolved.insert(host.to_string(), entry.addresses.clone());
+        None
+    } else if host.parse::<IpAddr>().is_ok() || config.unix_socket.is_some() || proxy.is_some_and(|p| p.resolves_remotely()) {
+        None
+    } else {
+        let (ips, elapsed) = dns::timed_lookup(host, port, config.dns_server, config.address_family()).await
+            .map_err(|e| SpeedTestError::Resolve { host: host.to_string(), reason: e.to_string() })?;
+        resolved.insert(host.to_string(), ips);
+        Some(elapsed)
+    };
+
+    // hyper-tls takes the SNI name from the URL, so put the --sni name there and
+    // point it at the addresses of the original host
+    let url: Uri = match &config.sni {
+        Some(sni) => {
+            let target: Vec<IpAddr> = match host.parse::<IpAddr>() {
+                Ok(ip) => vec![ip],
+                Err(_) => resolved[host].clone(),
+            };
+            resolved.insert(sni.clone(), target);
+            let authority: String = match url.port_u16() {
+                Some(port) => format!("{}:{}", sni, port),
+                None => sni.clone(),
+            };
+            let mut parts = url.clone().into_parts();
+            parts.authority = Some(authority.parse().map_err(|e| SpeedTestError::Config(format!("Invalid --sni '{}': {}", sni, e)))?);
+            Uri::from_parts(parts).map_err(|e| SpeedTestError::InvalidUrl { url: config.url.clone(), reason: e.to_string() })?
+        }
+        None => url,
+    };
+
+    Ok(Target { url, checksum, resolved, dns_time, seed, connections })
+}
+
+/*
+Fail with the signal that interrupted the run, if one has. The modes that run
+several tests stop between them and report on those finished first.
+*/
+fn interrupted() -> Result<(), SpeedTestError> {
+    match shutdown::signal() {
+        Some(signal) => Err(SpeedTestError::Interrupted { signal }),
+        None => Ok(()),
+    }
+}
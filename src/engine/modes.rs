@@ -0,0 +1,433 @@
+use crate::checksum::ChecksumVerifier;
+use chrono::Local;
+use crate::dns::AddressFamily;
+use crate::error::SpeedTestError;
+use crate::connector::Connector;
+use crate::config::{redact_url, SpeedTestConfig};
+use futures_util::StreamExt;
+use hyper::{Body, Request, Uri, header::RANGE, http::HeaderValue};
+use hyper_tls::HttpsConnector;
+use crate::report::{format_speed, InterfaceResult, SpeedFormat, SpeedTestResult, StackResult, TargetResult};
+use crate::{report, shutdown, stats};
+use crate::template::Template;
+use std::cmp::min;
+use std::collections::HashMap;
+use std::net::{IpAddr, TcpListener};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Instant, Duration};
+use tokio::sync::Mutex;
+use tracing::warn;
+use super::{StreamRecord, Target, emit_json_line, interrupted, prepare, print_line};
+use super::transport::{HttpClient, RequestOptions, build_client, build_connector, resolver};
+use super::download::{DownloadState, Worker, start_download};
+use super::probe::{ProbedFile, file_checksum, measure_latency, probe_file};
+use super::collect::{Measurement, Plan, collect, measure, plan, publish};
+
+// --scale-test hands out the file in pieces of this size
+const SCALE_PIECE_BYTES: u64 = 4 * 1024 * 1024;
+
+// The knee of a --scale-test curve is the first level within this percentage of the best
+const KNEE_PERCENT: f64 = 95.0;
+
+// Response headers CDNs commonly use to report cache hits and misses
+const CACHE_STATUS_HEADERS: [&str; 4] = ["x-cache", "cf-cache-status", "x-cache-status", "age"];
+
+/*
+Fetch the same range repeatedly on one client so later iterations reuse the
+connection and, if the server caches, hit a warm cache
+*/
+pub(super) async fn repeat_range(client: Arc<HttpClient>, url: Uri, start: u64, end: u64, iterations: u32, config: &SpeedTestConfig) -> Result<(), SpeedTestError> {
+    let range: String = format!("bytes={}-{}", start, end);
+    let request_options: RequestOptions = RequestOptions::from_config(config);
+
+    for iteration in 1..=iterations {
+        let mut request = Request::new(Body::empty());
+        *request.uri_mut() = url.clone();
+        request.headers_mut().insert(RANGE, HeaderValue::from_str(&range).map_err(|e| SpeedTestError::Other(format!("Invalid range {}: {}", range, e)))?);
+        request_options.apply(request.headers_mut());
+
+        let request_sent: Instant = Instant::now();
+        let res: hyper::Response<Body> = client.request(request).await?;
+        let ttfb: Duration = request_sent.elapsed();
+        let cache_status: Option<String> = CACHE_STATUS_HEADERS.iter()
+            .filter_map(|name| res.headers().get(*name).and_then(|v| v.to_str().ok()).map(|v| format!("{}: {}", name, v)))
+            .reduce(|a, b| format!("{}, {}", a, b));
+
+        // Read the whole body so the connection can go back to the pool
+        let mut body: Body = res.into_body();
+        let mut bytes: u64 = 0;
+        while let Some(chunk) = body.next().await {
+            bytes += chunk?.len() as u64;
+        }
+        let elapsed: Duration = request_sent.elapsed();
+        let bps: u64 = (bytes as f64 / elapsed.as_secs_f64()) as u64;
+
+        if config.json_stream {
+            emit_json_line(&StreamRecord::Iteration {
+                iteration,
+                bytes,
+                seconds: elapsed.as_secs_f64(),
+                bps,
+                ttfb_ms: ttfb.as_secs_f64() * 1000.0,
+                cache_status,
+            })?;
+        } else {
+            print_line(&format!("Iteration {}: {} bytes in {:.3} s at {} (TTFB {:.1} ms){}",
+                iteration, bytes, elapsed.as_secs_f64(), format_speed(bps, config.speed_format()), ttfb.as_secs_f64() * 1000.0,
+                cache_status.map(|c| format!(" [{}]", c)).unwrap_or_default()))?;
+        }
+    }
+
+    Ok(())
+}
+
+/*
+Measure throughput against connection count in one continuous run. A connection
+is added every --scale-step seconds, each one fetching the next piece of the file
+(wrapping round to its start) until every level up to max_connections has been
+measured. Each level's throughput is taken over the last two thirds of its step,
+once the new connection has ramped up.
+*/
+pub(super) async fn scale_test(client: &Arc<HttpClient>, file: &ProbedFile, config: &SpeedTestConfig, max_connections: u64) -> Result<(), SpeedTestError> {
+    if !file.ranges_supported {
+        return Err(SpeedTestError::Config(format!("{} needs range requests, which {} does not accept", config.scale_test_flag(), file.host)));
+    }
+    if file.encoding.as_deref() == Some("br") {
+        return Err(SpeedTestError::Config(format!("{} cannot split a Brotli-encoded response, which can only be decoded from its start", config.scale_test_flag())));
+    }
+
+    let piece: u64 = min(SCALE_PIECE_BYTES, file.length);
+    let window_end: u64 = config.offset + file.length;
+    let pieces: Arc<Vec<(u64, Option<u64>)>> = Arc::new((0..file.length.div_ceil(piece))
+        .map(|i| {
+            let start: u64 = config.offset + i * piece;
+            (start, Some(min(start + piece, window_end) - 1))
+        })
+        .collect());
+    let next_piece: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+    let download_state: Arc<Mutex<DownloadState>> = Arc::new(Mutex::new(DownloadState::new(config, None, Some(file.length), true)));
+    let worker: Arc<Worker> = Arc::new(Worker::new(client.clone(), &file.url, config, download_state.clone(), true, None));
+    let step: Duration = Duration::from_secs_f64(config.scale_step);
+    let speed_format: SpeedFormat = config.speed_format();
+
+    if !config.json_stream {
+        print_line("connections,bps")?;
+    }
+    // Each connection runs until it fails or the test is over, returning its error
+    let mut connections: Vec<tokio::task::JoinHandle<SpeedTestError>> = Vec::new();
+    let mut levels: Vec<(u64, u64)> = Vec::new();
+    let measured: Result<(), SpeedTestError> = async {
+        for level in 1..=max_connections {
+            let (worker, pieces, next_piece) = (worker.clone(), pieces.clone(), next_piece.clone());
+            connections.push(tokio::spawn(async move {
+                loop {
+                    let (start, end) = pieces[next_piece.fetch_add(1, Ordering::Relaxed) % pieces.len()];
+                    if let Err(error) = start_download(worker.clone(), start, end, None).await {
+                        return error;
+                    }
+                }
+            }));
+
+            // A Ctrl-C ends the test with the levels measured so far
+            tokio::select! {
+                _ = tokio::time::sleep(step / 3) => {}
+                _ = shutdown::requested() => break,
+            }
+            let measure_start: Instant = Instant::now();
+            let bytes_before: u64 = download_state.lock().await.total_bytes_downloaded;
+            tokio::select! {
+                _ = tokio::time::sleep(step - step / 3) => {}
+                _ = shutdown::requested() => break,
+            }
+            let bytes: u64 = download_state.lock().await.total_bytes_downloaded - bytes_before;
+            let bps: u64 = (bytes as f64 / measure_start.elapsed().as_secs_f64()) as u64;
+
+            // A level missing a connection would understate it, so the first failure ends the test
+            if let Some(index) = connections.iter().position(|connection| connection.is_finished()) {
+                let error: SpeedTestError = connections.swap_remove(index).await
+                    .map_err(|e| SpeedTestError::Other(format!("A scale test connection failed: {}", e)))?;
+                warn!("Connection {} failed at {} connection(s)", index + 1, level);
+                return Err(error);
+            }
+
+            levels.push((level, bps));
+            if config.json_stream {
+                emit_json_line(&StreamRecord::ScaleLevel { timestamp: Local::now().to_rfc3339(), connections: level, bps })?;
+            } else {
+                print_line(&format!("{},{}", level, bps))?;
+            }
+        }
+        Ok(())
+    }.await;
+    connections.iter().for_each(|connection| connection.abort());
+    measured?;
+
+    if let Some((connections, bps)) = stats::knee(&levels, KNEE_PERCENT) {
+        if config.json_stream {
+            emit_json_line(&StreamRecord::ScaleKnee { timestamp: Local::now().to_rfc3339(), connections, bps })?;
+        } else {
+            print_line(&format!("# knee: {} connection(s) at {}, within {}% of the best level", connections, format_speed(bps, speed_format), KNEE_PERCENT))?;
+        }
+    }
+    interrupted()?;
+    Ok(())
+}
+
+/*
+Run the test once from each --compare-interfaces address in turn, each with
+its own client so no connection carries over, then rank the addresses by speed
+*/
+pub(super) async fn compare_interfaces(config: &SpeedTestConfig, url: &Uri, resolved: &HashMap<String, Vec<IpAddr>>, connections: u64, dns_time: Option<Duration>, checksum: Option<ChecksumVerifier>, template: Option<&Template>) -> Result<(), SpeedTestError> {
+    let seed: u64 = config.seed.unwrap_or_default();
+    let count: usize = config.compare_interfaces.len();
+    let mut results: Vec<InterfaceResult> = Vec::with_capacity(count);
+
+    for (i, address) in config.compare_interfaces.iter().enumerate() {
+        if shutdown::signal().is_some() {
+            break;
+        }
+        if !config.json_stream && !config.quiet {
+            print_line(&format!("Testing from {} ({} of {})", address, i + 1, count))?;
+        }
+
+        let attempt: Result<(String, u64), SpeedTestError> = async {
+            // Connections bind lazily, so check up front that the address is one of ours
+            TcpListener::bind((*address, 0))
+                .map_err(|e| SpeedTestError::Bind { action: "bind to", address: address.to_string(), reason: e.to_string() })?;
+            let client: Arc<HttpClient> = build_client(config, resolved, Some(*address))?;
+            let file: ProbedFile = probe_file(&client, url, config).await?;
+            let path: String = match file.remote_addr {
+                Some(remote) => format!("{} ({})", file.host, remote.ip()),
+                None => file.host.clone(),
+            };
+            let plan: Plan = plan(&file, config, connections, seed, dns_time)?;
+            let checksum: Option<ChecksumVerifier> = file_checksum(&file, config, checksum.clone())?;
+            let result: SpeedTestResult = measure(&client, &file.url, config, &plan, checksum, template).await?;
+            Ok((path, result.avg_bps))
+        }.await;
+
+        results.push(match attempt {
+            Ok((path, avg_bps)) => InterfaceResult { address: *address, path: Some(path), avg_bps: Some(avg_bps), error: None },
+            Err(SpeedTestError::BrokenPipe) => return Err(SpeedTestError::BrokenPipe),
+            Err(e) => {
+                warn!("Test from {} failed: {}", address, e);
+                InterfaceResult { address: *address, path: None, avg_bps: None, error: Some(e.to_string()) }
+            }
+        });
+    }
+
+    // Fastest first, with the addresses that failed at the bottom
+    results.sort_by_key(|result| std::cmp::Reverse(result.avg_bps));
+    if config.json_stream {
+        emit_json_line(&StreamRecord::Comparison {
+            timestamp: Local::now().to_rfc3339(),
+            interfaces: &results,
+        })?;
+    } else {
+        print_line(&report::comparison_table(&results, config.speed_format()))?;
+    }
+    interrupted()?;
+
+    let failed: usize = results.iter().filter(|result| result.error.is_some()).count();
+    if failed > 0 {
+        return Err(SpeedTestError::TestsFailed { failed, count, what: "interface(s)" });
+    }
+    Ok(())
+}
+
+/*
+Run the test over IPv4 and then over IPv6, each with a client of its own that
+only connects over that family, then print their summaries side by side
+*/
+pub(super) async fn compare_stacks(config: &SpeedTestConfig, url: &Uri, resolved: &HashMap<String, Vec<IpAddr>>, connections: u64, dns_time: Option<Duration>, checksum: Option<ChecksumVerifier>, template: Option<&Template>) -> Result<(), SpeedTestError> {
+    let seed: u64 = config.seed.unwrap_or_default();
+    let mut results: Vec<StackResult> = Vec::new();
+
+    for (family, flag) in [(AddressFamily::V4, "-4"), (AddressFamily::V6, "-6")] {
+        if shutdown::signal().is_some() {
+            break;
+        }
+        if !config.json_stream && !config.quiet {
+            print_line(&format!("Testing over {}", family))?;
+        }
+
+        let attempt: Result<SpeedTestResult, SpeedTestError> = async {
+            let mut stack_config: SpeedTestConfig = config.clone();
+            stack_config.ipv4 = family == AddressFamily::V4;
+            stack_config.ipv6 = family == AddressFamily::V6;
+            let client: Arc<HttpClient> = build_client(&stack_config, resolved, None)?;
+            let file: ProbedFile = probe_file(&client, url, &stack_config).await?;
+            let plan: Plan = plan(&file, &stack_config, connections, seed, dns_time)?;
+            let checksum: Option<ChecksumVerifier> = file_checksum(&file, &stack_config, checksum.clone())?;
+            measure(&client, &file.url, &stack_config, &plan, checksum, template).await
+        }.await;
+
+        results.push(match attempt {
+            Ok(result) => StackResult { family: family.to_string(), result: Some(result), error: None },
+            Err(SpeedTestError::BrokenPipe) => return Err(SpeedTestError::BrokenPipe),
+            Err(e) => {
+                warn!("Test over {} ({}) failed: {}", family, flag, e);
+                StackResult { family: family.to_string(), result: None, error: Some(e.to_string()) }
+            }
+        });
+    }
+
+    if config.json_stream {
+        emit_json_line(&StreamRecord::Stacks {
+            timestamp: Local::now().to_rfc3339(),
+            stacks: &results,
+        })?;
+    } else {
+        print_line(&report::stacks_table(&results, config.speed_format()))?;
+    }
+    interrupted()?;
+
+    if results.iter().all(|result| result.error.is_some()) {
+        return Err(SpeedTestError::TestsFailed { failed: results.len(), count: 2, what: "IP stack(s)" });
+    }
+    Ok(())
+}
+
+/*
+Read the URLs listed in a --url-file, skipping blank lines and # comments
+*/
+pub(super) fn read_url_file(path: &Path) -> Result<Vec<String>, SpeedTestError> {
+    let text: String = std::fs::read_to_string(path)
+        .map_err(|source| SpeedTestError::File { action: "read", path: path.to_path_buf(), source })?;
+    Ok(text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+/*
+One URL of a multi-target run once its downloads are done, with what is needed
+to publish its summary
+*/
+struct TestedTarget {
+    config: SpeedTestConfig,
+    plan: Plan,
+    measurement: Measurement,
+}
+
+/*
+Test each of several URLs, one after another or with --simultaneous all at once.
+Each gets its own summary, followed by a table of them all with the aggregate
+throughput.
+*/
+pub(super) async fn test_targets(mut config: SpeedTestConfig, template: Option<&Template>) -> Result<(), SpeedTestError> {
+    if config.upload || !config.compare_interfaces.is_empty() || config.compare_stacks || config.monitor.is_some() || config.repeat.is_some() || config.scale_test.is_some() || config.repeat_range.is_some() || config.metrics_listen.is_some() || config.output_file.is_some() {
+        return Err(SpeedTestError::Config("Several URLs can only be downloaded, not combined with upload, --compare-interfaces, --compare-stacks, --monitor, --repeat, --scale-test, --ramp, --repeat-range, --metrics-listen or --output-file".to_string()));
+    }
+    // One seed for every target, so the whole run can be repeated
+    let seed_chosen: bool = config.seed.is_none();
+    let seed: u64 = *config.seed.get_or_insert_with(rand::random);
+    if seed_chosen && config.shuffle_ranges && !config.json_stream && !config.quiet {
+        print_line(&format!("Using random seed {}", seed))?;
+    }
+
+    let urls: Vec<String> = config.urls.clone();
+    let count: usize = urls.len();
+    let mut results: Vec<TargetResult> = Vec::with_capacity(count);
+    if config.simultaneous {
+        // Progress from several downloads at once would be unreadable, so only
+        // the summaries are printed, once they have all finished
+        if !config.json_stream && !config.quiet {
+            print_line(&format!("Testing {} URLs at once", count))?;
+        }
+        let tested: Vec<Result<TestedTarget, SpeedTestError>> =
+            futures_util::future::join_all(urls.iter().map(|url| test_target(&config, url, true))).await;
+        for (i, (url, tested)) in urls.iter().zip(tested).enumerate() {
+            if !config.json_stream {
+                print_line(&format!("Results for {} ({} of {})", redact_url(url), i + 1, count))?;
+            }
+            let outcome: Result<SpeedTestResult, SpeedTestError> = match tested {
+                Ok(tested) => publish_target(tested, template).await,
+                Err(e) => Err(e),
+            };
+            results.push(target_result(url, outcome)?);
+        }
+    } else {
+        for (i, url) in urls.iter().enumerate() {
+            if shutdown::signal().is_some() {
+                break;
+            }
+            if !config.json_stream && !config.quiet {
+                print_line(&format!("Testing {} ({} of {})", redact_url(url), i + 1, count))?;
+            }
+            let outcome: Result<SpeedTestResult, SpeedTestError> = match test_target(&config, url, false).await {
+                Ok(tested) => publish_target(tested, template).await,
+                Err(e) => Err(e),
+            };
+            results.push(target_result(url, outcome)?);
+        }
+    }
+
+    // Targets tested at once share the same time, while one after another each adds its own
+    let bytes: u64 = results.iter().filter_map(|result| result.total_bytes).sum();
+    let elapsed = results.iter().filter_map(|result| result.elapsed);
+    let seconds: f64 = if config.simultaneous { elapsed.fold(0.0, f64::max) } else { elapsed.sum() };
+    let aggregate_bps: u64 = if seconds > 0.0 { (bytes as f64 / seconds) as u64 } else { 0 };
+    if config.json_stream {
+        emit_json_line(&StreamRecord::Targets {
+            timestamp: Local::now().to_rfc3339(),
+            targets: &results,
+            aggregate_bps,
+        })?;
+    } else {
+        print_line(&report::targets_table(&results, aggregate_bps, config.speed_format()))?;
+    }
+    interrupted()?;
+
+    let failed: usize = results.iter().filter(|result| result.error.is_some()).count();
+    if failed > 0 {
+        return Err(SpeedTestError::TestsFailed { failed, count, what: "URL(s)" });
+    }
+    Ok(())
+}
+
+/*
+Probe and download one URL of a multi-target run
+*/
+async fn test_target(config: &SpeedTestConfig, url: &str, quiet: bool) -> Result<TestedTarget, SpeedTestError> {
+    let mut config: SpeedTestConfig = config.clone();
+    config.url = url.to_string();
+    let Target { url, checksum, resolved, dns_time, seed, connections } = prepare(&mut config).await?;
+    let client: Arc<HttpClient> = build_client(&config, &resolved, None)?;
+    let file: ProbedFile = probe_file(&client, &url, &config).await?;
+    let checksum: Option<ChecksumVerifier> = file_checksum(&file, &config, checksum)?;
+    let mut plan: Plan = plan(&file, &config, connections, seed, dns_time)?;
+    if let Some(count) = config.latency {
+        let connector: HttpsConnector<Connector> = build_connector(&config, resolver(&config, &resolved), None)?;
+        let (ttfbs, connects) = measure_latency(&client, connector, &file.url, &config, count).await?;
+        plan.latency = stats::latency_summary(&ttfbs);
+        plan.connect_time = stats::latency_summary(&connects);
+    }
+    let measurement: Measurement = collect(&client, &file.url, &config, &plan, checksum, quiet).await?;
+    Ok(TestedTarget { config, plan, measurement })
+}
+
+async fn publish_target(tested: TestedTarget, template: Option<&Template>) -> Result<SpeedTestResult, SpeedTestError> {
+    let TestedTarget { config, plan, measurement } = tested;
+    publish(&config, &plan, &measurement, template).await?;
+    Ok(measurement.result)
+}
+
+/*
+A target's row in the multi-target table. A failed target is reported and the
+run goes on, unless nobody is reading the output any more.
+*/
+fn target_result(url: &str, outcome: Result<SpeedTestResult, SpeedTestError>) -> Result<TargetResult, SpeedTestError> {
+    let url: String = redact_url(url);
+    match outcome {
+        Ok(result) => Ok(TargetResult { url, total_bytes: Some(result.total_bytes), elapsed: Some(result.elapsed), avg_bps: Some(result.avg_bps), error: None }),
+        Err(SpeedTestError::BrokenPipe) => Err(SpeedTestError::BrokenPipe),
+        Err(e) => {
+            warn!("Test of {} failed: {}", url, e);
+            Ok(TargetResult { url, total_bytes: None, elapsed: None, avg_bps: None, error: Some(e.to_string()) })
+        }
+    }
+}
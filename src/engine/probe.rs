@@ -0,0 +1,384 @@
+use crate::checksum::ChecksumVerifier;
+use crate::error::SpeedTestError;
+use crate::connector::Connector;
+use crate::config::{HeaderDump, HttpVersion, SpeedTestConfig};
+use hyper::{Body, Method, Request, StatusCode, Uri, header::{ACCEPT_RANGES, CONTENT_ENCODING, CONTENT_LENGTH, ETAG, LOCATION, RANGE}, http::HeaderValue};
+use hyper::client::connect::HttpInfo;
+use hyper::service::Service;
+use hyper_tls::HttpsConnector;
+use crate::{dump, tls};
+use crate::provider::{self, Server};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Instant, Duration};
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, warn};
+use super::print_line;
+use super::transport::{HttpClient, RequestOptions, build_client};
+use super::download::edge_nodes;
+
+// How many latency probes each --provider server is sent, and how long it has
+// to answer them all before it is passed over
+const PROVIDER_PROBES: u32 = 3;
+
+const PROVIDER_PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+// Give up on redirect chains longer than this
+const MAX_REDIRECTS: u32 = 10;
+
+/*
+The response's Content-Encoding, lowercased, if it is anything other than identity
+*/
+pub(super) fn content_encoding(res: &hyper::Response<Body>) -> Option<String> {
+    res.headers().get(CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim().to_ascii_lowercase())
+        .filter(|v| !v.is_empty() && v != "identity")
+}
+
+/*
+Send the probe request, following redirects, and return the final URL along
+with its response
+*/
+pub(super) async fn probe(client: &HttpClient, url: &Uri, request_options: &RequestOptions, dump_headers: Option<HeaderDump>) -> Result<(Uri, hyper::Response<Body>), SpeedTestError> {
+    let mut url: Uri = url.clone();
+    for _ in 0..=MAX_REDIRECTS {
+        let mut request = Request::new(Body::empty());
+        *request.uri_mut() = url.clone();
+        request_options.apply(request.headers_mut());
+        if let Some(mode) = dump_headers {
+            dump::request(&request, mode);
+        }
+        let res: hyper::Response<Body> = client.request(request).await?;
+        if let Some(mode) = dump_headers {
+            dump::response(&res, mode);
+        }
+        if !res.status().is_redirection() {
+            return Ok((url, res));
+        }
+
+        let location: &str = res.headers().get(LOCATION).and_then(|v| v.to_str().ok())
+            .ok_or_else(|| SpeedTestError::Redirect(format!("{} redirect from {} has no Location header", res.status(), url)))?;
+        let next: Uri = resolve_location(&url, location)?;
+        debug!("Following redirect ({}) to {}", res.status(), next);
+        url = next;
+    }
+    Err(SpeedTestError::Redirect(format!("Gave up after {} redirects", MAX_REDIRECTS)))
+}
+
+/*
+Resolve a Location header, which may be relative, against the URL that returned it
+*/
+fn resolve_location(base: &Uri, location: &str) -> Result<Uri, SpeedTestError> {
+    if let Ok(uri) = location.parse::<Uri>() {
+        if uri.scheme().is_some() {
+            return Ok(uri);
+        }
+    }
+
+    let scheme: &str = base.scheme_str().unwrap_or("http");
+    let authority: &str = base.authority().map(|a| a.as_str()).unwrap_or("");
+    let resolved: String = if let Some(rest) = location.strip_prefix("//") {
+        format!("{}://{}", scheme, rest)
+    } else if location.starts_with('/') {
+        format!("{}://{}{}", scheme, authority, location)
+    } else {
+        let dir: &str = base.path().rsplit_once('/').map(|(dir, _)| dir).unwrap_or("");
+        format!("{}://{}{}/{}", scheme, authority, dir, location)
+    };
+    resolved.parse::<Uri>().map_err(|e| SpeedTestError::InvalidUrl { url: location.to_string(), reason: format!("bad redirect location ({})", e) })
+}
+
+/*
+What the probe found out about the file at the end of any redirects
+*/
+pub(super) struct ProbedFile {
+    pub(super) url: Uri,
+    pub(super) host: String,
+    pub(super) redirected_to: Option<String>,
+    pub(super) remote_addr: Option<SocketAddr>,
+    pub(super) content_length: u64,
+    pub(super) length: u64,
+    // False when the server sent no Content-Length, leaving both lengths at zero
+    pub(super) length_known: bool,
+    pub(super) ranges_supported: bool,
+    pub(super) encoding: Option<String>,
+    pub(super) tls_version: Option<String>,
+    pub(super) edge_nodes: Vec<String>,
+    etag: Option<String>,
+}
+
+/*
+The digest to verify the download against: any from --validate-checksum, or
+with --verify-etag the one the file's ETag carries
+*/
+pub(super) fn file_checksum(file: &ProbedFile, config: &SpeedTestConfig, checksum: Option<ChecksumVerifier>) -> Result<Option<ChecksumVerifier>, SpeedTestError> {
+    if !config.verify_etag {
+        return Ok(checksum);
+    }
+    // The ETag is a digest of the whole file as stored
+    if config.offset > 0 {
+        return Err(SpeedTestError::Config("--verify-etag can't be combined with --offset, since the ETag covers the whole file".to_string()));
+    }
+    if file.encoding.is_some() {
+        return Err(SpeedTestError::Config(format!("--verify-etag can't check the {}-encoded response from {}, since the ETag is a digest of the file as stored", file.encoding.as_deref().unwrap_or_default(), file.host)));
+    }
+    let etag: &str = file.etag.as_deref().ok_or_else(|| SpeedTestError::InvalidChecksum(format!("--verify-etag was given but {} sent no ETag", file.host)))?;
+    Ok(Some(ChecksumVerifier::from_etag(etag)?))
+}
+
+/*
+Probe the file for its length, following any redirects to the server that actually
+serves it, and check the requested window against it. The test then runs against
+the final URL.
+*/
+pub(super) async fn probe_file(client: &HttpClient, url: &Uri, config: &SpeedTestConfig) -> Result<ProbedFile, SpeedTestError> {
+    // Accepting the connection but never answering would otherwise hang the run before any worker starts
+    let probe_timeout: Duration = Duration::from_secs_f64(config.probe_timeout);
+    let tls_limited: bool = config.tls_min_version.is_some() || config.tls_max_version.is_some();
+    let (final_url, res) = tokio::time::timeout(probe_timeout, probe(client, url, &RequestOptions::from_config(config), config.dump_headers)).await
+        .map_err(|_| SpeedTestError::Timeout(format!(
+            "Probe timed out: no response from {} within {:.1} s",
+            url.authority().map(|a| a.as_str()).unwrap_or_default(),
+            probe_timeout.as_secs_f64(),
+        )))?
+        .map_err(|e| match e {
+            // A connection refused over the TLS limits should say so rather than
+            // leave a bare handshake error to be puzzled over
+            SpeedTestError::Connect(e) if tls_limited => SpeedTestError::Tls(format!(
+                "Could not connect with {}: {}",
+                tls::describe_limits(config.tls_min_version, config.tls_max_version),
+                e.source().map(|source| source.to_string()).unwrap_or_else(|| e.to_string()),
+            )),
+            // hyper doesn't fall back, so a server without HTTP/2 only shows up as a
+            // broken connection
+            SpeedTestError::Connect(e) if config.http_version == Some(HttpVersion::Http2) => SpeedTestError::Http2Unsupported {
+                host: url.authority().map(|a| a.to_string()).unwrap_or_default(),
+                reason: e.source().map(|source| source.to_string()).unwrap_or_else(|| e.to_string()),
+            },
+            e => e,
+        })?;
+    let final_host: String = final_url.authority().map(|a| a.to_string()).unwrap_or_default();
+    let redirected_to: Option<String> = if final_url != *url { Some(final_host.clone()) } else { None };
+    let url: Uri = final_url;
+    let remote_addr: Option<SocketAddr> = res.extensions().get::<HttpInfo>().map(|info| info.remote_addr());
+    if !res.status().is_success() {
+        // Show exactly what was requested so URL parsing surprises are easy to spot
+        let target: &str = url.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+        let hint: &str = if matches!(res.status().as_u16(), 400 | 404) { " (check how the path was written)" } else { "" };
+        return Err(SpeedTestError::BadStatus {
+            status: res.status(),
+            message: format!("Probe request failed with {}: request target was '{}' on {}{}", res.status(), target, final_host, hint),
+        });
+    }
+    let headers: &hyper::HeaderMap = res.headers();
+    let content_length: Option<u64> = headers.get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse().ok());
+    // Mirrors behind a redirect don't necessarily support ranges just because the
+    // original host did, so this is decided from the final response
+    let mut ranges_supported: bool = headers.get(ACCEPT_RANGES).and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.split(',').any(|unit| unit.trim().eq_ignore_ascii_case("bytes")));
+    let encoding: Option<String> = content_encoding(&res);
+    let edge_nodes: Vec<String> = edge_nodes(&res, &config.pop_header);
+    let etag: Option<String> = headers.get(ETAG).and_then(|v| v.to_str().ok()).map(str::to_string);
+    drop(res);
+
+    // Without a length the file can't be split, so it is read over one plain GET
+    // until the server ends the body
+    let Some(content_length) = content_length else {
+        let needed_by: Option<&'static str> = [
+            (config.offset > 0, "--offset"),
+            (config.length.is_some(), "--length"),
+            (config.expect_size.is_some(), "--expect-size"),
+            (config.repeat_range.is_some(), "--repeat-range"),
+            (config.scale_test.is_some(), config.scale_test_flag()),
+        ].iter().find(|(given, _)| *given).map(|(_, flag)| *flag);
+        if let Some(needed_by) = needed_by {
+            return Err(SpeedTestError::MissingContentLength { host: final_host, needed_by });
+        }
+        warn!("{} did not send a Content-Length; downloading the file over one connection until the server ends it", final_host);
+        return Ok(ProbedFile { url, host: final_host, redirected_to, remote_addr, content_length: 0, length: 0, length_known: false, ranges_supported: false, encoding, tls_version: None, edge_nodes, etag });
+    };
+    // Some servers advertise ranges and then ignore them, which would have every
+    // worker download the whole file
+    if ranges_supported && !config.no_range && content_length > 1 && !ranges_honored(client, &url, config).await? {
+        warn!("{} advertises Accept-Ranges but answered a range request with the whole file; falling back to a single download", final_host);
+        ranges_supported = false;
+    }
+    match encoding.as_deref() {
+        Some("br") if !config.compressed => warn!("the server sent Brotli-encoded content despite Accept-Encoding: identity; sizes are encoded bytes and the decoded size is reported separately"),
+        Some("br") | None => {}
+        Some(other) => warn!("the server sent Content-Encoding {}, which is not decoded; sizes are encoded bytes", other),
+    }
+
+    // Make sure the server is serving the file we think it is before downloading anything
+    if let Some(expected) = config.expect_size {
+        if content_length != expected {
+            return Err(SpeedTestError::SizeMismatch { expected, actual: content_length });
+        }
+    }
+
+    // Restrict the test to the requested window of the file
+    if encoding.as_deref() == Some("br") && (config.offset > 0 || config.length.is_some()) {
+        return Err(SpeedTestError::Config("--offset and --length cannot be used with a Brotli-encoded response, which can only be decoded from its start".to_string()));
+    }
+    if !ranges_supported && (config.offset > 0 || config.length.is_some()) {
+        return Err(SpeedTestError::RangeUnsupported { host: final_host });
+    }
+    if config.offset >= content_length {
+        return Err(SpeedTestError::Config(format!("--offset {} is beyond the end of the file ({} bytes)", config.offset, content_length)));
+    }
+    let length: u64 = config.length.unwrap_or(content_length - config.offset);
+    if config.offset + length > content_length {
+        return Err(SpeedTestError::Config(format!("--offset {} with --length {} runs past the end of the file ({} bytes)", config.offset, length, content_length)));
+    }
+
+    // Any failure here is only fatal when the version limits were asked for,
+    // since otherwise the downloads don't depend on it
+    let tls_version: Option<String> = match (url.scheme_str(), remote_addr, url.host()) {
+        (Some("https"), Some(addr), Some(host)) => {
+            let host: &str = host.trim_start_matches('[').trim_end_matches(']');
+            match tls::negotiated_version(addr, host, config.tls_min_version, config.tls_max_version).await {
+                Ok(version) => Some(version),
+                Err(e) if tls_limited => return Err(e),
+                Err(e) => {
+                    warn!("{}; the TLS version will not be reported", e);
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+
+    Ok(ProbedFile { url, host: final_host, redirected_to, remote_addr, content_length, length, length_known: true, ranges_supported, encoding, tls_version, edge_nodes, etag })
+}
+
+/*
+Whether the server answers a request for just the first byte with 206 Partial
+Content. Any other answer than a full 200 is left for the downloads to deal
+with.
+*/
+async fn ranges_honored(client: &HttpClient, url: &Uri, config: &SpeedTestConfig) -> Result<bool, SpeedTestError> {
+    let mut request = Request::new(Body::empty());
+    *request.uri_mut() = url.clone();
+    request.headers_mut().insert(RANGE, HeaderValue::from_static("bytes=0-0"));
+    RequestOptions::from_config(config).apply(request.headers_mut());
+    let probe_timeout: Duration = Duration::from_secs_f64(config.probe_timeout);
+    let res: hyper::Response<Body> = tokio::time::timeout(probe_timeout, client.request(request)).await
+        .map_err(|_| SpeedTestError::Timeout(format!("Range check timed out: no response from {} within {:.1} s", url, probe_timeout.as_secs_f64())))??;
+    Ok(res.status() != StatusCode::OK)
+}
+
+/*
+Time `count` HEAD requests, sent over one kept-alive connection, and then
+`count` fresh connections including any TLS handshake. They run one at a
+time so no request queues behind another.
+*/
+pub(super) async fn measure_latency(client: &HttpClient, mut connector: HttpsConnector<Connector>, url: &Uri, config: &SpeedTestConfig, count: u32) -> Result<(Vec<Duration>, Vec<Duration>), SpeedTestError> {
+    let options: RequestOptions = RequestOptions::from_config(config);
+    let mut ttfbs: Vec<Duration> = Vec::new();
+    for _ in 0..count {
+        ttfbs.push(probe_latency(client, url, &options).await.map_err(SpeedTestError::Connect)?);
+    }
+
+    let mut connects: Vec<Duration> = Vec::new();
+    for _ in 0..count {
+        futures_util::future::poll_fn(|cx| connector.poll_ready(cx)).await
+            .map_err(|e| SpeedTestError::Unreachable { target: format!("{} for the latency test", url), reason: e.to_string() })?;
+        let started: Instant = Instant::now();
+        let stream = connector.call(url.clone()).await
+            .map_err(|e| SpeedTestError::Unreachable { target: format!("{} for the latency test", url), reason: e.to_string() })?;
+        connects.push(started.elapsed());
+        drop(stream);
+    }
+    Ok((ttfbs, connects))
+}
+
+/*
+Time one small HEAD request to the URL, reading the (empty) body so the
+connection goes back to the pool for the next probe
+*/
+pub(super) async fn probe_latency(client: &HttpClient, url: &Uri, options: &RequestOptions) -> Result<Duration, hyper::Error> {
+    let mut request = Request::new(Body::empty());
+    *request.method_mut() = Method::HEAD;
+    *request.uri_mut() = url.clone();
+    options.apply(request.headers_mut());
+    let sent: Instant = Instant::now();
+    let res: hyper::Response<Body> = client.request(request).await?;
+    let elapsed: Duration = sent.elapsed();
+    hyper::body::to_bytes(res.into_body()).await?;
+    Ok(elapsed)
+}
+
+/*
+Probe the latency every --probe-interval while the download runs, one probe at
+a time, until the future is dropped. Failed probes are counted as lost.
+*/
+pub(super) async fn probe_loaded_latency(client: &HttpClient, url: &Uri, config: &SpeedTestConfig, times: &mut Vec<Duration>, lost: &mut u64) -> Infallible {
+    let options: RequestOptions = RequestOptions::from_config(config);
+    let mut ticker: tokio::time::Interval = tokio::time::interval(Duration::from_secs_f64(config.probe_interval));
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        match probe_latency(client, url, &options).await {
+            Ok(elapsed) => times.push(elapsed),
+            Err(_) => *lost += 1,
+        }
+    }
+}
+
+/*
+Pick the --provider server with the lowest latency. Every server is probed at
+once, first to open a connection and then PROVIDER_PROBES more times, and the
+fastest of its probes counts.
+*/
+pub(super) async fn select_server(config: &SpeedTestConfig, provider: &str) -> Result<String, SpeedTestError> {
+    let servers: Vec<Server> = provider::servers(provider).map_err(SpeedTestError::Config)?;
+    let client: Arc<HttpClient> = build_client(config, &HashMap::new(), None)?;
+    let options: RequestOptions = RequestOptions::from_config(config);
+    let latencies: Vec<Option<Duration>> = if servers.len() == 1 {
+        vec![None]
+    } else {
+        futures_util::future::join_all(servers.iter().map(|server| async {
+            let url: Uri = server.url.parse().ok()?;
+            let probes = async {
+                probe_latency(&client, &url, &options).await.ok()?;
+                let mut best: Option<Duration> = None;
+                for _ in 0..PROVIDER_PROBES {
+                    let elapsed: Duration = probe_latency(&client, &url, &options).await.ok()?;
+                    best = Some(best.map_or(elapsed, |best| best.min(elapsed)));
+                }
+                best
+            };
+            tokio::time::timeout(PROVIDER_PROBE_TIMEOUT, probes).await.ok().flatten()
+        })).await
+    };
+    if servers.len() > 1 {
+        for (server, latency) in servers.iter().zip(&latencies) {
+            match latency {
+                Some(latency) => debug!("{} server {}: {:.1} ms", provider, server.name, latency.as_secs_f64() * 1000.0),
+                None => debug!("{} server {}: no answer", provider, server.name),
+            }
+        }
+    }
+
+    let (server, latency): (&Server, Option<Duration>) = if servers.len() == 1 {
+        (&servers[0], None)
+    } else {
+        servers.iter().zip(latencies)
+            .filter_map(|(server, latency)| Some((server, Some(latency?))))
+            .min_by_key(|(_, latency)| *latency)
+            .ok_or_else(|| SpeedTestError::Timeout(format!("None of the {} {} servers answered within {} s", servers.len(), provider, PROVIDER_PROBE_TIMEOUT.as_secs())))?
+    };
+    if !config.json_stream && !config.quiet {
+        match latency {
+            Some(latency) => print_line(&format!("Using {} server {} ({:.1} ms away, the closest of {})", provider, server.name, latency.as_secs_f64() * 1000.0, servers.len()))?,
+            None => print_line(&format!("Using {} server {}", provider, server.name))?,
+        }
+    }
+    Ok(server.url.clone())
+}
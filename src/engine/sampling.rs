@@ -0,0 +1,189 @@
+use chrono::Local;
+use crate::error::SpeedTestError;
+use crate::config::SpeedTestConfig;
+use crate::report::{format_speed, SpeedFormat};
+use crate::sink::{MetricsSink, SampleRecord, StreamRate};
+use crate::stats::Sample;
+use std::sync::Arc;
+use std::time::{Instant, Duration};
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+use super::{StreamRecord, emit_json_line, print_line};
+use super::download::{DownloadState, StreamCounter};
+
+// A sample in which data arrives slower than this many bytes per second counts as a stall
+const STALL_THRESHOLD_BYTES: u64 = 1024;
+
+/*
+Print the download speed every sample interval (a second by default). Runs until
+aborted, returning only if writing to stdout fails.
+*/
+pub(super) async fn print_loop(download_state: Arc<Mutex<DownloadState>>, mut sinks: Vec<Box<dyn MetricsSink>>, test_start: Instant, stop: CancellationToken) -> Result<(), SpeedTestError> {
+    let sample_interval: Duration = download_state.lock().await.sample_interval;
+    let mut stalled: bool = false;
+
+    loop {
+        // Once the downloads are told to stop nothing more is sampled, leaving
+        // what is still arriving to the final sample
+        tokio::select! {
+            _ = tokio::time::sleep(sample_interval) => {}
+            _ = stop.cancelled() => return Ok(()),
+        }
+
+        let mut state = download_state.lock().await;
+
+        // Work out the bytes received since the previous tick
+        let bytes_this_sample: u64 = state.total_bytes_downloaded - state.sampled_bytes;
+        let elapsed: Duration = state.sampled_at.elapsed();
+        let warmup: bool = state.in_warmup();
+        state.sampled_bytes = state.total_bytes_downloaded;
+        state.sampled_at = Instant::now();
+        let current_bps: u64 = (bytes_this_sample as f64 / elapsed.as_secs_f64()) as u64;
+
+        // Count near-zero samples as stalls once data has started flowing,
+        // merging consecutive stalled samples into a single event
+        if state.total_bytes_downloaded > 0 && current_bps < STALL_THRESHOLD_BYTES {
+            if !stalled {
+                state.stall_count += 1;
+                stalled = true;
+            }
+            state.stall_duration += elapsed;
+        } else {
+            stalled = false;
+        }
+
+        if let Some(memory) = &mut state.memory {
+            memory.sample();
+        }
+
+        // Keep every sampled rate for the summary statistics
+        if state.total_bytes_downloaded > 0 {
+            state.samples.push(Sample { elapsed: test_start.elapsed().as_secs_f64(), bps: current_bps, warmup });
+        }
+
+        if sinks.is_empty() {
+            continue;
+        }
+
+        // The average download speed over the --window, once it has any
+        let avg_speed: Option<u64> = state.window.rolling_bps();
+
+        // Estimate the time left from that average, or from this sample until a
+        // full interval has been averaged; there is no estimate while nothing arrives
+        // or when the server never said how much there is
+        let remaining: Option<u64> = state.expected_bytes.map(|expected| expected.saturating_sub(state.total_bytes_downloaded));
+        let eta_bps: u64 = avg_speed.filter(|bps| *bps > 0).unwrap_or(current_bps);
+        let eta: Option<Duration> = match (remaining, eta_bps) {
+            (None, _) => None,
+            (Some(0), _) => Some(Duration::ZERO),
+            (_, 0) => None,
+            (Some(remaining), bps) => Some(Duration::from_secs_f64(remaining as f64 / bps as f64)),
+        };
+
+        let sample: SampleRecord = SampleRecord {
+            elapsed: test_start.elapsed().as_secs_f64(),
+            timestamp: Local::now(),
+            bytes: bytes_this_sample,
+            cumulative: state.total_bytes_downloaded,
+            current_bps,
+            avg_bps: avg_speed,
+            connections: state.active_downloads,
+            eta,
+            total: state.expected_bytes,
+            warmup,
+            streams: if state.per_connection { stream_rates(&mut state.streams, elapsed) } else { Vec::new() },
+        };
+        drop(state);
+        for sink in sinks.iter_mut() {
+            sink.sample(&sample)?;
+        }
+    }
+}
+
+/*
+Each stream's rate since the previous sample, skipping those that had already
+finished by then
+*/
+fn stream_rates(streams: &mut [StreamCounter], elapsed: Duration) -> Vec<StreamRate> {
+    let mut rates: Vec<StreamRate> = Vec::new();
+    for (stream, counter) in streams.iter_mut().enumerate() {
+        let bytes: u64 = counter.bytes - counter.sampled_bytes;
+        counter.sampled_bytes = counter.bytes;
+        if counter.elapsed.is_none() || bytes > 0 {
+            rates.push(StreamRate { stream, bps: (bytes as f64 / elapsed.as_secs_f64()) as u64 });
+        }
+    }
+    rates
+}
+
+/*
+The live lines on stdout: one JSON object per sample with --json-stream, or
+else the average speed and ETA as text
+*/
+pub(super) struct StdoutSink {
+    json_stream: bool,
+    time_format: &'static str,
+    speed_format: SpeedFormat,
+}
+
+impl StdoutSink {
+    pub(super) fn new(config: &SpeedTestConfig) -> StdoutSink {
+        // Show milliseconds when there is more than one line per second
+        let time_format: &'static str = if config.sample_interval < 1000 { "%Y-%m-%d %H:%M:%S%.3f" } else { "%Y-%m-%d %H:%M:%S" };
+        StdoutSink { json_stream: config.json_stream, time_format, speed_format: config.speed_format() }
+    }
+}
+
+impl MetricsSink for StdoutSink {
+    fn sample(&mut self, sample: &SampleRecord) -> Result<(), SpeedTestError> {
+        if self.json_stream {
+            // Elapsed time comes from the monotonic clock so wall-clock jumps
+            // can't corrupt the timeline; the timestamp is informational
+            return emit_json_line(&StreamRecord::Sample {
+                elapsed: sample.elapsed,
+                timestamp: sample.timestamp.to_rfc3339(),
+                bytes_this_second: sample.bytes,
+                cumulative: sample.cumulative,
+                current_bps: sample.current_bps,
+                avg_bps: sample.avg_bps,
+                connections: sample.connections,
+                eta_seconds: sample.eta.map(|eta| eta.as_secs_f64()),
+                streams: Some(sample.streams.as_slice()).filter(|streams| !streams.is_empty()),
+                warmup: sample.warmup,
+            });
+        }
+        let note: &str = if sample.warmup { " (warm-up)" } else { "" };
+        print_line(&format!("[{}] Speed: {} now, {} average, ETA {}{}", sample.timestamp.format(self.time_format),
+            format_speed(sample.current_bps, self.speed_format), format_average(sample.avg_bps, self.speed_format), format_eta(sample.eta), note))?;
+        if !sample.streams.is_empty() {
+            let rates: Vec<String> = sample.streams.iter()
+                .map(|rate| format!("#{} {}", rate.stream, format_speed(rate.bps, self.speed_format)))
+                .collect();
+            print_line(&format!("    Streams: {}", rates.join(", ")))?;
+        }
+        Ok(())
+    }
+}
+
+/*
+Format the rolling average, with a dash until the window has any data
+*/
+pub(crate) fn format_average(avg_bps: Option<u64>, format: SpeedFormat) -> String {
+    avg_bps.map(|bps| format_speed(bps, format)).unwrap_or_else(|| "—".to_string())
+}
+
+/*
+Format a time remaining as mm:ss, or h:mm:ss once it reaches an hour, with a
+dash when there is no estimate
+*/
+pub(crate) fn format_eta(eta: Option<Duration>) -> String {
+    let Some(eta) = eta else {
+        return "—".to_string();
+    };
+    let seconds: u64 = eta.as_secs_f64().ceil() as u64;
+    if seconds >= 3600 {
+        format!("{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+    } else {
+        format!("{:02}:{:02}", seconds / 60, seconds % 60)
+    }
+}
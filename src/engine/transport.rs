@@ -0,0 +1,283 @@
+use crate::dns::Resolver;
+use crate::connector::{Connector, Dialer};
+use crate::config::{HttpVersion, RequestHeader, SpeedTestConfig};
+use crate::error::SpeedTestError;
+use hyper::{Body, Client, Request, Response, header::{ACCEPT_ENCODING, CONNECTION, HOST, PROXY_AUTHORIZATION, USER_AGENT}, http::HeaderValue};
+use hyper::client::{HttpConnector, ResponseFuture};
+use hyper_tls::HttpsConnector;
+use crate::tls;
+use crate::proxy::Proxy;
+use std::collections::HashMap;
+#[cfg(target_os = "linux")]
+use std::ffi::CString;
+use std::future::Future;
+use std::net::IpAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio_native_tls::native_tls::{Certificate, Identity, TlsConnector};
+
+/*
+The response to a download's request, once its headers have arrived
+*/
+pub(super) type PendingResponse = Pin<Box<dyn Future<Output = Result<Response<Body>, SpeedTestError>> + Send>>;
+
+/*
+How the downloads' requests reach the server. The downloads only ever see the
+responses, so their state tracking and reporting are the same whatever carries
+them. HttpClient is the transport for HTTP/1.1 and HTTP/2 over hyper.
+*/
+pub(super) trait Transport: Send + Sync {
+    /*
+    Send a request for a download's data
+    */
+    fn download(&self, request: Request<Body>) -> PendingResponse;
+}
+
+/*
+The client every request goes through. Over HTTP/2 hyper multiplexes all the
+requests to a host over one connection, so unless --h2-single-connection asks
+for that each download is given a connection of its own.
+*/
+pub(crate) struct HttpClient {
+    shared: Client<HttpsConnector<Connector>>,
+    builder: hyper::client::Builder,
+    connector: HttpsConnector<Connector>,
+    connection_per_download: bool,
+    // Sent with the requests an HTTP proxy forwards, but never through a tunnel
+    proxy_authorization: Option<HeaderValue>,
+}
+
+impl HttpClient {
+    pub(crate) fn request(&self, mut request: Request<Body>) -> ResponseFuture {
+        self.authorize(&mut request);
+        self.shared.request(request)
+    }
+
+    /*
+    A client with a pool of its own, so the downloads can't take its connections
+    */
+    pub(super) fn isolated(&self) -> HttpClient {
+        HttpClient {
+            shared: self.builder.build(self.connector.clone()),
+            builder: self.builder.clone(),
+            connector: self.connector.clone(),
+            connection_per_download: false,
+            proxy_authorization: self.proxy_authorization.clone(),
+        }
+    }
+
+    fn authorize(&self, request: &mut Request<Body>) {
+        if let Some(authorization) = self.proxy_authorization.as_ref().filter(|_| request.uri().scheme_str() == Some("http")) {
+            request.headers_mut().insert(PROXY_AUTHORIZATION, authorization.clone());
+        }
+    }
+}
+
+impl Transport for HttpClient {
+    /*
+    Send the request over a fresh connection when each HTTP/2 stream gets its own
+    */
+    fn download(&self, mut request: Request<Body>) -> PendingResponse {
+        self.authorize(&mut request);
+        let response: ResponseFuture = if self.connection_per_download {
+            self.builder.build::<_, Body>(self.connector.clone()).request(request)
+        } else {
+            self.shared.request(request)
+        };
+        Box::pin(async move { response.await.map_err(SpeedTestError::from) })
+    }
+}
+
+/*
+The client a --webhook or --pushgateway is sent with: hyper's own over the system's TLS, since
+--unix-socket, --interface, --resolve, --proxy, --http-version and the like only
+say how to reach the test URL
+*/
+pub(crate) type DeliveryClient = Client<HttpsConnector<HttpConnector>>;
+
+pub(crate) fn delivery_client() -> DeliveryClient {
+    Client::builder().build(HttpsConnector::new())
+}
+
+/*
+Headers the configuration adds to every request sent to the test URL
+*/
+#[derive(Clone)]
+pub(super) struct RequestOptions {
+    compressed: bool,
+    connection_close: bool,
+    host: Option<HeaderValue>,
+    user_agent: Option<HeaderValue>,
+    headers: Vec<RequestHeader>,
+}
+
+impl RequestOptions {
+    pub(super) fn from_config(config: &SpeedTestConfig) -> RequestOptions {
+        RequestOptions {
+            compressed: config.compressed,
+            connection_close: config.connection_close,
+            // Validated when the run starts
+            host: config.host_header.as_deref().and_then(|host| HeaderValue::from_str(host).ok()),
+            // Checked to be a valid header value when the arguments were parsed
+            user_agent: config.user_agent.as_deref().and_then(|agent| HeaderValue::from_str(agent).ok()),
+            headers: config.headers.clone(),
+        }
+    }
+
+    pub(super) fn apply(&self, headers: &mut hyper::HeaderMap) {
+        // Brotli when compression is allowed, otherwise only the identity
+        // encoding so byte counts are the file's real size
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_static(if self.compressed { "br" } else { "identity" }));
+        if self.connection_close {
+            headers.insert(CONNECTION, HeaderValue::from_static("close"));
+        }
+        if let Some(host) = &self.host {
+            headers.insert(HOST, host.clone());
+        }
+        if let Some(user_agent) = &self.user_agent {
+            headers.insert(USER_AGENT, user_agent.clone());
+        }
+        // The first --header of a name replaces the tester's own, and any more
+        // of the same name are sent alongside it
+        for (i, header) in self.headers.iter().enumerate() {
+            if self.headers[..i].iter().any(|earlier| earlier.name == header.name) {
+                headers.append(header.name.clone(), header.value.clone());
+            } else {
+                headers.insert(header.name.clone(), header.value.clone());
+            }
+        }
+    }
+}
+
+/*
+Build the HTTPS connector, trusting any extra root certificates on top of the system
+store, limiting the TLS versions it will negotiate, and either dialing --unix-socket
+or binding outgoing TCP connections to a local address when one is given
+*/
+pub(super) fn build_connector(config: &SpeedTestConfig, resolver: Resolver, local_address: Option<IpAddr>) -> Result<HttpsConnector<Connector>, SpeedTestError> {
+    let mut tls = TlsConnector::builder();
+    tls::limit_versions(&mut tls, config.tls_min_version, config.tls_max_version);
+    // hyper speaks HTTP/2 from the start of the connection, so the server has to
+    // have agreed to it in the handshake
+    if config.http_version == Some(HttpVersion::Http2) {
+        tls.request_alpns(&["h2"]);
+    }
+    for path in &config.cacert {
+        let pem: Vec<u8> = std::fs::read(path)
+            .map_err(|source| SpeedTestError::File { action: "read CA certificate", path: path.clone(), source })?;
+        // Certificate::from_pem only reads the first of a bundle's certificates
+        let blocks: Vec<&str> = pem_certificates(&pem);
+        if blocks.is_empty() {
+            return Err(SpeedTestError::Tls(format!("{} holds no PEM certificate", path.display())));
+        }
+        for block in blocks {
+            let cert: Certificate = Certificate::from_pem(block.as_bytes())
+                .map_err(|e| SpeedTestError::Tls(format!("Failed to parse CA certificate {}: {}", path.display(), e)))?;
+            tls.add_root_certificate(cert);
+        }
+    }
+    if config.insecure {
+        tls.danger_accept_invalid_certs(true);
+        tls.danger_accept_invalid_hostnames(true);
+    }
+    if let (Some(cert), Some(key)) = (&config.cert, &config.key) {
+        let read = |path: &Path, action: &'static str| std::fs::read(path).map_err(|source| SpeedTestError::File { action, path: path.to_path_buf(), source });
+        let identity: Identity = Identity::from_pkcs8(&read(cert, "read client certificate")?, &read(key, "read client key")?)
+            .map_err(|e| SpeedTestError::Tls(format!("Failed to load the client certificate {} with key {}: {}", cert.display(), key.display(), e)))?;
+        tls.identity(identity);
+    }
+
+    let transport: Connector = match &config.unix_socket {
+        #[cfg(unix)]
+        Some(path) => Connector::Unix(Arc::new(path.clone())),
+        #[cfg(not(unix))]
+        Some(_) => return Err(SpeedTestError::Config("--unix-socket is only supported on Unix".to_string())),
+        None => {
+            let local_address: Option<IpAddr> = local_address.or(config.source_ip);
+            let dialer: Dialer = match &config.interface {
+                #[cfg(target_os = "linux")]
+                Some(interface) => {
+                    let name: CString = CString::new(interface.as_str()).map_err(|_| SpeedTestError::Config(format!("Invalid --interface '{}'", interface)))?;
+                    if unsafe { libc::if_nametoindex(name.as_ptr()) } == 0 {
+                        return Err(SpeedTestError::Config(format!("There is no network interface named {}", interface)));
+                    }
+                    Dialer::Device { interface: interface.as_str().into(), local_address, resolver: resolver.clone() }
+                }
+                #[cfg(not(target_os = "linux"))]
+                Some(_) => return Err(SpeedTestError::Config("--interface is only supported on Linux".to_string())),
+                None => {
+                    let mut http: HttpConnector<Resolver> = HttpConnector::new_with_resolver(resolver.clone());
+                    http.enforce_http(false);
+                    http.set_local_address(local_address);
+                    Dialer::Http(http)
+                }
+            };
+            match &config.proxy {
+                Some(url) => Connector::Proxy(dialer, Arc::new(Proxy::parse(url, config.proxy_user.as_deref()).map_err(SpeedTestError::Config)?), resolver),
+                None => Connector::Tcp(dialer),
+            }
+        }
+    };
+    let tls: TlsConnector = tls.build().map_err(|e| SpeedTestError::Tls(format!("Failed to set up TLS: {}", e)))?;
+    Ok(HttpsConnector::from((transport, tls.into())))
+}
+
+/*
+Each BEGIN CERTIFICATE to END CERTIFICATE block of a PEM file, such as a CA
+bundle, skipping anything between them
+*/
+fn pem_certificates(pem: &[u8]) -> Vec<&str> {
+    const BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+    const END: &str = "-----END CERTIFICATE-----";
+    let mut text: &str = std::str::from_utf8(pem).unwrap_or_default();
+    let mut blocks: Vec<&str> = Vec::new();
+    while let Some(start) = text.find(BEGIN) {
+        let Some(end) = text[start..].find(END) else {
+            break;
+        };
+        blocks.push(&text[start..start + end + END.len()]);
+        text = &text[start + end + END.len()..];
+    }
+    blocks
+}
+
+/*
+The connector's resolver: the addresses found up front, and the -4, -6 and
+--dns-server options for any other host
+*/
+pub(super) fn resolver(config: &SpeedTestConfig, resolved: &HashMap<String, Vec<IpAddr>>) -> Resolver {
+    Resolver::new(resolved.clone(), config.address_family(), config.dns_server)
+}
+
+/*
+Create the HTTP client, connecting to the addresses resolved up front
+*/
+pub(super) fn build_client(config: &SpeedTestConfig, resolved: &HashMap<String, Vec<IpAddr>>, local_address: Option<IpAddr>) -> Result<Arc<HttpClient>, SpeedTestError> {
+    // hyper only speaks HTTP/1.1 and HTTP/2 over TCP; a QUIC client would be a
+    // Transport of its own
+    if config.http_version == Some(HttpVersion::Http3) {
+        return Err(SpeedTestError::NotBuiltIn { feature: "--http-version 3 (HTTP/3 over QUIC)" });
+    }
+    let https: HttpsConnector<Connector> = build_connector(config, resolver(config, resolved), local_address)?;
+    let mut builder = Client::builder();
+    if config.single_connection {
+        builder.pool_max_idle_per_host(1);
+    } else if config.connection_close {
+        builder.pool_max_idle_per_host(0);
+    }
+    let http2: bool = config.http_version == Some(HttpVersion::Http2);
+    if http2 {
+        builder.http2_only(true);
+    }
+    Ok(Arc::new(HttpClient {
+        shared: builder.build::<_, hyper::Body>(https.clone()),
+        builder,
+        connector: https,
+        connection_per_download: http2 && !config.h2_single_connection,
+        proxy_authorization: match &config.proxy {
+            Some(url) => Proxy::parse(url, config.proxy_user.as_deref()).map_err(SpeedTestError::Config)?.authorization(),
+            None => None,
+        },
+    }))
+}
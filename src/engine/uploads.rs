@@ -0,0 +1,221 @@
+use bytes::Bytes;
+use chrono::Local;
+use crate::error::SpeedTestError;
+use crate::config::{SpeedTestConfig, UploadMethod};
+use futures_util::StreamExt;
+use hyper::{Body, Method, Request, Uri, header::{CONTENT_LENGTH, CONTENT_TYPE}, http::HeaderValue};
+use crate::report::format_speed;
+use crate::stats::Sample;
+use crate::{shutdown, upload};
+use std::sync::Arc;
+use std::time::{Instant, Duration};
+use tokio::sync::Mutex;
+use super::{StreamRecord, emit_json_line, interrupted, print_line};
+use super::transport::{HttpClient, RequestOptions};
+
+/*
+Shared progress of the upload requests, the counterpart of DownloadState
+*/
+pub(super) struct UploadState {
+    sample_interval: Duration,
+    pub(super) total_bytes_sent: u64,
+    sampled_bytes: u64,
+    sampled_at: Instant,
+    samples: Vec<Sample>,
+    completed: u64,
+}
+
+impl UploadState {
+    pub(super) fn new(config: &SpeedTestConfig) -> UploadState {
+        UploadState {
+            sample_interval: Duration::from_millis(config.sample_interval),
+            total_bytes_sent: 0,
+            sampled_bytes: 0,
+            sampled_at: Instant::now(),
+            samples: Vec::new(),
+            completed: 0,
+        }
+    }
+
+    /*
+    Take a sample of what was sent since the previous one, returning the bytes
+    and their rate
+    */
+    fn sample(&mut self, start: Instant) -> (u64, u64) {
+        let bytes: u64 = self.total_bytes_sent - self.sampled_bytes;
+        let elapsed: Duration = self.sampled_at.elapsed();
+        self.sampled_bytes = self.total_bytes_sent;
+        self.sampled_at = Instant::now();
+        let bps: u64 = (bytes as f64 / elapsed.as_secs_f64()) as u64;
+        self.samples.push(Sample { elapsed: start.elapsed().as_secs_f64(), bps, warmup: false });
+        (bytes, bps)
+    }
+
+    /*
+    The fastest sample, or the average for a transfer over before the first
+    */
+    pub(super) fn peak_bps(&self, avg_bps: u64) -> u64 {
+        self.samples.iter().map(|s| s.bps).max().unwrap_or(avg_bps).max(avg_bps)
+    }
+}
+
+/*
+Upload --upload-size bytes of generated data to the URL, split between one
+request per connection, printing the send rate every sample interval. Bytes
+count as sent once hyper has taken them for the connection.
+*/
+pub(super) async fn upload(client: Arc<HttpClient>, url: Uri, config: &SpeedTestConfig, seed: u64) -> Result<(), SpeedTestError> {
+    let connections: u64 = config.connections.unwrap_or(1).min(config.upload_size);
+    let upload_state: Arc<Mutex<UploadState>> = Arc::new(Mutex::new(UploadState::new(config)));
+    let sample_interval: Duration = upload_state.lock().await.sample_interval;
+
+    let upload_start: Instant = Instant::now();
+    // Every connection sends an equal share, with the remainder on the last one
+    let share: u64 = config.upload_size / connections;
+    let sends = futures_util::future::try_join_all((0..connections).map(|i| {
+        let size: u64 = if i + 1 == connections { config.upload_size - share * i } else { share };
+        send_upload(&client, &url, config, size, seed.wrapping_add(i), upload_state.clone())
+    }));
+    tokio::pin!(sends);
+    // --max-runtime ends the upload by dropping the requests still in flight
+    let deadline = async {
+        match config.max_runtime {
+            Some(seconds) => tokio::time::sleep(Duration::from_secs_f64(seconds)).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(deadline);
+    let mut stopped_early: bool = false;
+    let mut ticker: tokio::time::Interval = tokio::time::interval_at((upload_start + sample_interval).into(), sample_interval);
+    loop {
+        tokio::select! {
+            sent = &mut sends => {
+                sent?;
+                break;
+            }
+            _ = &mut deadline => {
+                stopped_early = true;
+                break;
+            }
+            _ = shutdown::requested() => break,
+            _ = ticker.tick() => {
+                let mut state = upload_state.lock().await;
+                let (bytes_this_sample, current_bps) = state.sample(upload_start);
+
+                let avg_bps: u64 = (state.total_bytes_sent as f64 / upload_start.elapsed().as_secs_f64()) as u64;
+                let remaining: u64 = config.upload_size.saturating_sub(state.total_bytes_sent);
+                let eta_seconds: Option<f64> = if current_bps > 0 { Some(remaining as f64 / current_bps as f64) } else { None };
+                if config.json_stream {
+                    emit_json_line(&StreamRecord::Sample {
+                        elapsed: upload_start.elapsed().as_secs_f64(),
+                        timestamp: Local::now().to_rfc3339(),
+                        bytes_this_second: bytes_this_sample,
+                        cumulative: state.total_bytes_sent,
+                        current_bps,
+                        // The upload averages over the whole run, which always has data
+                        avg_bps: Some(avg_bps),
+                        connections: connections - state.completed,
+                        eta_seconds,
+                        streams: None,
+                        warmup: false,
+                    })?;
+                } else if !config.quiet {
+                    print_line(&format!("[{}] Upload speed: {} now, {} average ({} of {} connection(s) done)", Local::now().format("%Y-%m-%d %H:%M:%S"),
+                        format_speed(current_bps, config.speed_format()), format_speed(avg_bps, config.speed_format()), state.completed, connections))?;
+                }
+            }
+        }
+    }
+    let elapsed: Duration = upload_start.elapsed();
+
+    let state = upload_state.lock().await;
+    let bytes: u64 = state.total_bytes_sent;
+    let bps: u64 = (bytes as f64 / elapsed.as_secs_f64()) as u64;
+    let peak_bps: u64 = state.peak_bps(bps);
+    if config.json_stream {
+        emit_json_line(&StreamRecord::Upload {
+            timestamp: Local::now().to_rfc3339(),
+            bytes,
+            seconds: elapsed.as_secs_f64(),
+            bps,
+            peak_bps,
+            connections,
+            payload_pattern: config.payload_pattern,
+            stopped_early,
+            interrupted_by: shutdown::signal(),
+        })?;
+    } else {
+        let note: String = match shutdown::signal() {
+            Some(signal) => format!(", interrupted by {}", signal),
+            None if stopped_early => ", stopped by --max-runtime".to_string(),
+            None => String::new(),
+        };
+        print_line(&format!("Uploaded {} bytes over {} connection(s) in {:.3} s at {} (peak {}){}", bytes, connections, elapsed.as_secs_f64(),
+            format_speed(bps, config.speed_format()), format_speed(peak_bps, config.speed_format()), note))?;
+    }
+
+    interrupted()?;
+    Ok(())
+}
+
+/*
+Send one upload request of `size` generated bytes, counting each chunk into the
+shared state as hyper takes it
+*/
+async fn send_upload(client: &HttpClient, url: &Uri, config: &SpeedTestConfig, size: u64, seed: u64, upload_state: Arc<Mutex<UploadState>>) -> Result<(), SpeedTestError> {
+    let counter: Arc<Mutex<UploadState>> = upload_state.clone();
+    let payload = upload::payload(size, config.payload_pattern, seed).then(move |chunk: Bytes| {
+        let counter: Arc<Mutex<UploadState>> = counter.clone();
+        async move {
+            counter.lock().await.total_bytes_sent += chunk.len() as u64;
+            Ok::<Bytes, std::io::Error>(chunk)
+        }
+    });
+
+    let mut request = Request::new(Body::wrap_stream(payload));
+    *request.method_mut() = match config.upload_method {
+        UploadMethod::Post => Method::POST,
+        UploadMethod::Put => Method::PUT,
+    };
+    *request.uri_mut() = url.clone();
+    request.headers_mut().insert(CONTENT_LENGTH, HeaderValue::from(size));
+    request.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/octet-stream"));
+    RequestOptions::from_config(config).apply(request.headers_mut());
+
+    let res: hyper::Response<Body> = client.request(request).await?;
+    if !res.status().is_success() {
+        return Err(SpeedTestError::BadStatus { status: res.status(), message: format!("Upload failed with {}", res.status()) });
+    }
+    upload_state.lock().await.completed += 1;
+    Ok(())
+}
+
+/*
+Keep each of the --duplex upload connections sending one request after another,
+sampling the send rate, until the future is dropped. Returns only if an upload
+fails.
+*/
+pub(super) async fn duplex_uploads(client: &HttpClient, url: &Uri, config: &SpeedTestConfig, connections: u64, seed: u64, upload_state: Arc<Mutex<UploadState>>) -> Result<(), SpeedTestError> {
+    let sample_interval: Duration = upload_state.lock().await.sample_interval;
+    let size: u64 = (config.upload_size / connections).max(1);
+    let sends = futures_util::future::try_join_all((0..connections).map(|i| {
+        keep_uploading(client, url, config, size, seed.wrapping_add(i), upload_state.clone())
+    }));
+    tokio::pin!(sends);
+    let start: Instant = Instant::now();
+    let mut ticker: tokio::time::Interval = tokio::time::interval_at((start + sample_interval).into(), sample_interval);
+    loop {
+        tokio::select! {
+            sent = &mut sends => return sent.map(|_| ()),
+            _ = ticker.tick() => {
+                upload_state.lock().await.sample(start);
+            }
+        }
+    }
+}
+
+async fn keep_uploading(client: &HttpClient, url: &Uri, config: &SpeedTestConfig, size: u64, seed: u64, upload_state: Arc<Mutex<UploadState>>) -> Result<(), SpeedTestError> {
+    loop {
+        send_upload(client, url, config, size, seed, upload_state.clone()).await?;
+    }
+}
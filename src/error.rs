@@ -0,0 +1,185 @@
+use hyper::StatusCode;
+use std::fmt::Write;
+use std::path::PathBuf;
+use thiserror::Error;
+
+// Exit codes for failures scripts may want to tell apart; any other error exits
+// with 1 and clap uses 2 for usage errors
+const EXIT_FAILURE: u8 = 1;
+const EXIT_SIZE_MISMATCH: u8 = 3;
+const EXIT_TTFB_EXCEEDED: u8 = 4;
+const EXIT_BELOW_BASELINE: u8 = 5;
+const EXIT_THRESHOLD_FAILED: u8 = 6;
+// Match what a shell reports for a process killed by SIGINT, SIGPIPE or SIGTERM
+const EXIT_INTERRUPTED: u8 = 130;
+const EXIT_BROKEN_PIPE: u8 = 141;
+const EXIT_TERMINATED: u8 = 143;
+
+/*
+Ways a speed test can fail, so callers can tell the categories apart and each
+maps to its own exit code
+*/
+#[derive(Debug, Error)]
+pub enum SpeedTestError {
+    #[error("Invalid URL '{url}': {reason}")]
+    InvalidUrl { url: String, reason: String },
+
+    #[error(transparent)]
+    Connect(#[from] hyper::Error),
+
+    // A stalled or truncated transfer; the message says which
+    #[error("{0}")]
+    Timeout(String),
+
+    #[error("{message}")]
+    TtfbExceeded { message: String },
+
+    // Options that contradict each other, or that the host or file can't satisfy
+    #[error("{0}")]
+    Config(String),
+
+    #[error("Failed to {action} {}: {source}", .path.display())]
+    File { action: &'static str, path: PathBuf, source: std::io::Error },
+
+    #[error("Failed to {action} {address}: {reason}")]
+    Bind { action: &'static str, address: String, reason: String },
+
+    #[error("Failed to resolve {host}: {reason}")]
+    Resolve { host: String, reason: String },
+
+    // hyper doesn't fall back, so a server without HTTP/2 only shows up as a broken connection
+    #[error("Could not talk HTTP/2 to {host}, which may not support it: {reason}")]
+    Http2Unsupported { host: String, reason: String },
+
+    // A connection opened outside hyper's connector, such as for the latency test
+    #[error("Failed to connect to {target}: {reason}")]
+    Unreachable { target: String, reason: String },
+
+    // Loading the CA or client certificates, or negotiating the TLS versions asked for
+    #[error("{0}")]
+    Tls(String),
+
+    // A --proxy that refused the tunnel or didn't speak its protocol
+    #[error("{0}")]
+    Proxy(String),
+
+    #[error("{0}")]
+    Redirect(String),
+
+    // A compressed response that can't be decoded where it was cut
+    #[error("{0}")]
+    Decode(String),
+
+    #[error("{0}")]
+    InvalidTemplate(String),
+
+    // A --validate-checksum or ETag that isn't a digest this binary can check
+    #[error("{0}")]
+    InvalidChecksum(String),
+
+    #[error("{algorithm} checksum mismatch: expected {expected}, got {actual}; the data received is corrupt or not the file expected")]
+    ChecksumMismatch { algorithm: String, expected: String, actual: String },
+
+    #[error("{algorithm} checksum could not be verified: only {hashed_bytes} of {length} bytes were received in order")]
+    ChecksumIncomplete { algorithm: String, hashed_bytes: u64, length: u64 },
+
+    #[error("{message}")]
+    BadStatus { status: StatusCode, message: String },
+
+    #[error("{host} did not send a Content-Length for the file, which {needed_by} needs")]
+    MissingContentLength { host: String, needed_by: &'static str },
+
+    #[error("--offset and --length need range requests, which {host} does not accept")]
+    RangeUnsupported { host: String },
+
+    #[error("{range} was answered with {content_range}; refusing data from the wrong offset")]
+    ContentRangeMismatch { range: String, content_range: String },
+
+    #[error("Expected {expected} bytes but the server reports a Content-Length of {actual}")]
+    SizeMismatch { expected: u64, actual: u64 },
+
+    #[error("Average speed of {avg_bps} B/s is {:.1}% below the --baseline of {baseline} B/s, beyond the {tolerance}% tolerance", -.deviation)]
+    BelowBaseline { avg_bps: u64, baseline: u64, deviation: f64, tolerance: f64 },
+
+    // One line per failed --min-speed or --max-latency assertion
+    #[error("{} threshold(s) failed:{}", .failures.len(), .failures.iter().map(|f| format!("\n  {}", f)).collect::<String>())]
+    ThresholdsFailed { failures: Vec<String> },
+
+    #[error("A --duplex upload to {url} failed: {source}")]
+    DuplexUpload { url: String, source: Box<SpeedTestError> },
+
+    #[error("{}", describe_failures(.failures, *.downloads))]
+    DownloadsFailed { failures: Vec<DownloadFailure>, downloads: usize },
+
+    // Some of the --compare-interfaces addresses, IP stacks or URLs tested, each already reported
+    #[error("{failed} of {count} {what} failed")]
+    TestsFailed { failed: usize, count: usize, what: &'static str },
+
+    // An option this binary was built without the support for
+    #[error("{feature} isn't built into this binary")]
+    NotBuiltIn { feature: &'static str },
+
+    #[error("stdout was closed")]
+    BrokenPipe,
+
+    // Raised once the summary of what arrived before the signal has been printed
+    #[error("Interrupted by {signal}")]
+    Interrupted { signal: &'static str },
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    // Anything that shouldn't happen, such as a task that panicked
+    #[error("{0}")]
+    Other(String),
+}
+
+impl SpeedTestError {
+    /*
+    Whether the failure may well go away if the download is simply tried again:
+    a dropped or refused connection, a stalled body or a 5xx from the server
+    */
+    pub fn is_transient(&self) -> bool {
+        match self {
+            SpeedTestError::Connect(_) | SpeedTestError::Timeout(_) => true,
+            SpeedTestError::BadStatus { status, .. } => status.is_server_error(),
+            _ => false,
+        }
+    }
+
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            SpeedTestError::SizeMismatch { .. } => EXIT_SIZE_MISMATCH,
+            SpeedTestError::TtfbExceeded { .. } => EXIT_TTFB_EXCEEDED,
+            SpeedTestError::BelowBaseline { .. } => EXIT_BELOW_BASELINE,
+            SpeedTestError::ThresholdsFailed { .. } => EXIT_THRESHOLD_FAILED,
+            SpeedTestError::BrokenPipe => EXIT_BROKEN_PIPE,
+            SpeedTestError::Interrupted { signal: "SIGTERM" } => EXIT_TERMINATED,
+            SpeedTestError::Interrupted { .. } => EXIT_INTERRUPTED,
+            // Keep the exit code when every failed download asked for the same one
+            SpeedTestError::DownloadsFailed { failures, .. } => {
+                let first: u8 = failures.first().map(|f| f.error.exit_code()).unwrap_or(EXIT_FAILURE);
+                if failures.iter().all(|f| f.error.exit_code() == first) { first } else { EXIT_FAILURE }
+            }
+            _ => EXIT_FAILURE,
+        }
+    }
+}
+
+/*
+A download that failed, with enough context to tell which one it was
+*/
+#[derive(Debug)]
+pub struct DownloadFailure {
+    pub worker: usize,
+    pub range: String,
+    pub error: SpeedTestError,
+}
+
+fn describe_failures(failures: &[DownloadFailure], downloads: usize) -> String {
+    let mut message: String = format!("{} of {} download(s) failed:", failures.len(), downloads);
+    for failure in failures {
+        let _ = write!(message, "\n  worker {} ({}): {}", failure.worker, failure.range, failure.error);
+    }
+    message
+}
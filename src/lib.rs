@@ -0,0 +1,119 @@
+/*
+Measure HTTP download bandwidth using parallel range requests. The command-line
+tool is a thin wrapper around this crate; other programs can run the same test
+through SpeedTest::builder() and get the result back as a TestReport.
+*/
+mod checksum;
+pub mod config;
+mod config_file;
+mod connector;
+mod dns;
+mod dump;
+mod encoding;
+mod engine;
+pub mod error;
+mod logging;
+mod mem;
+mod metrics;
+mod output;
+mod progress;
+mod provider;
+mod proxy;
+mod pushgateway;
+pub mod report;
+mod serve;
+mod shutdown;
+mod sink;
+mod stats;
+mod template;
+mod throttle;
+mod tls;
+mod upload;
+mod version;
+mod webhook;
+mod window;
+
+use config::SpeedTestConfig;
+use error::SpeedTestError;
+use std::time::Duration;
+
+pub use engine::run;
+pub use report::SpeedTestResult as TestReport;
+pub use stats::{ChunkSizeSummary, LatencySummary};
+
+/*
+A download test ready to run, configured as the command line would be by the
+builder's options
+*/
+pub struct SpeedTest {
+    config: SpeedTestConfig,
+}
+
+/*
+Options for a SpeedTest. Only the URL is required; anything left unset takes
+the same default as on the command line.
+*/
+#[derive(Default)]
+pub struct SpeedTestBuilder {
+    url: Option<String>,
+    connections: Option<u64>,
+    max_runtime: Option<Duration>,
+}
+
+impl SpeedTest {
+    pub fn builder() -> SpeedTestBuilder {
+        SpeedTestBuilder::default()
+    }
+
+    /*
+    Download the file and report on the transfer. Nothing is printed; warnings are
+    emitted as tracing events, for the program's own subscriber to show.
+    */
+    pub async fn run(self) -> Result<TestReport, SpeedTestError> {
+        engine::run_report(self.config).await
+    }
+}
+
+impl SpeedTestBuilder {
+    /*
+    The file to download
+    */
+    pub fn url(mut self, url: impl Into<String>) -> SpeedTestBuilder {
+        self.url = Some(url.into());
+        self
+    }
+
+    /*
+    Number of parallel range requests to open, instead of one per CPU
+    */
+    pub fn connections(mut self, connections: u64) -> SpeedTestBuilder {
+        self.connections = Some(connections);
+        self
+    }
+
+    /*
+    Stop the test after this long and report on what was transferred so far
+    */
+    pub fn max_runtime(mut self, max_runtime: Duration) -> SpeedTestBuilder {
+        self.max_runtime = Some(max_runtime);
+        self
+    }
+
+    pub fn build(self) -> Result<SpeedTest, SpeedTestError> {
+        let url: String = self.url.ok_or_else(|| SpeedTestError::Config("No URL was given to the speed test".to_string()))?;
+        if self.connections == Some(0) {
+            return Err(SpeedTestError::Config("The connection count must be at least 1".to_string()));
+        }
+        let mut config: SpeedTestConfig = SpeedTestConfig::for_url(&url);
+        config.connections = self.connections;
+        config.max_runtime = self.max_runtime.map(|d| d.as_secs_f64());
+        Ok(SpeedTest { config })
+    }
+
+    /*
+    Build the test and run it
+    */
+    pub async fn run(self) -> Result<TestReport, SpeedTestError> {
+        self.build()?.run().await
+    }
+}
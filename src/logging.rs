@@ -0,0 +1,202 @@
+use crate::config::SpeedTestConfig;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+use tracing::field::{Field, Visit};
+use tracing::level_filters::LevelFilter;
+use tracing::span::{Attributes, Id, Record};
+use tracing::subscriber::Interest;
+use tracing::{Event, Level, Metadata, Subscriber};
+
+thread_local! {
+    // The spans entered on this thread, innermost last
+    static ENTERED: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+}
+
+/*
+Show the tool's diagnostics on stderr for the rest of the run: notices and
+warnings by default, the per-connection debug events with -v, every chunk with
+-vv, and nothing but errors with --quiet
+*/
+pub fn init(config: &SpeedTestConfig) {
+    let level: LevelFilter = match (config.quiet, config.verbose) {
+        (true, _) => LevelFilter::ERROR,
+        (false, 0) => LevelFilter::INFO,
+        (false, 1) => LevelFilter::DEBUG,
+        (false, _) => LevelFilter::TRACE,
+    };
+    // Only the first run of the process installs its logger
+    let _ = tracing::subscriber::set_global_default(Logger::new(level));
+}
+
+/*
+Writes events as plain lines: errors and warnings prefixed as such, notices as
+they are, and debug and trace events with the time since the run started and
+the spans they happened in. Events from the libraries underneath are left out.
+*/
+struct Logger {
+    level: LevelFilter,
+    started: Instant,
+    next_id: AtomicU64,
+    spans: Mutex<HashMap<u64, Span>>,
+}
+
+struct Span {
+    // The spans it is nested in, as they are shown
+    outer: Option<String>,
+    name: &'static str,
+    fields: String,
+    references: usize,
+}
+
+impl Span {
+    fn context(&self) -> String {
+        let name: String = if self.fields.is_empty() { self.name.to_string() } else { format!("{}{{{}}}", self.name, self.fields.trim_start()) };
+        match &self.outer {
+            Some(outer) => format!("{}:{}", outer, name),
+            None => name,
+        }
+    }
+}
+
+impl Logger {
+    fn new(level: LevelFilter) -> Logger {
+        Logger { level, started: Instant::now(), next_id: AtomicU64::new(1), spans: Mutex::new(HashMap::new()) }
+    }
+
+    fn ours(metadata: &Metadata<'_>) -> bool {
+        metadata.target().split("::").next() == Some(env!("CARGO_CRATE_NAME"))
+    }
+
+    fn current() -> Option<u64> {
+        ENTERED.with(|entered| entered.borrow().last().copied())
+    }
+
+    fn context(&self, parent: Option<u64>) -> Option<String> {
+        parent.and_then(|id| self.spans.lock().unwrap().get(&id).map(Span::context))
+    }
+}
+
+impl Subscriber for Logger {
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        if Logger::ours(metadata) && self.level >= *metadata.level() { Interest::always() } else { Interest::never() }
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        Logger::ours(metadata) && self.level >= *metadata.level()
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(self.level)
+    }
+
+    fn new_span(&self, attributes: &Attributes<'_>) -> Id {
+        let parent: Option<u64> = match attributes.parent() {
+            Some(parent) => Some(parent.into_u64()),
+            None if attributes.is_contextual() => Logger::current(),
+            None => None,
+        };
+        let mut fields: Fields = Fields::default();
+        attributes.record(&mut fields);
+        let span: Span = Span { outer: self.context(parent), name: attributes.metadata().name(), fields: fields.pairs, references: 1 };
+        let id: u64 = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.spans.lock().unwrap().insert(id, span);
+        Id::from_u64(id)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        let mut fields: Fields = Fields::default();
+        values.record(&mut fields);
+        if let Some(span) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            span.fields.push_str(&fields.pairs);
+        }
+    }
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut fields: Fields = Fields::default();
+        event.record(&mut fields);
+        let text: String = format!("{}{}", fields.message, fields.pairs);
+        match *event.metadata().level() {
+            Level::ERROR => eprintln!("Error: {}", text),
+            Level::WARN => eprintln!("Warning: {}", text),
+            Level::INFO => eprintln!("{}", text),
+            _ => {
+                let parent: Option<u64> = match event.parent() {
+                    Some(parent) => Some(parent.into_u64()),
+                    None if event.is_contextual() => Logger::current(),
+                    None => None,
+                };
+                let elapsed: f64 = self.started.elapsed().as_secs_f64();
+                match self.context(parent) {
+                    Some(context) => eprintln!("[{:8.3} s] {}: {}", elapsed, context, text),
+                    None => eprintln!("[{:8.3} s] {}", elapsed, text),
+                }
+            }
+        }
+    }
+
+    fn enter(&self, span: &Id) {
+        ENTERED.with(|entered| entered.borrow_mut().push(span.into_u64()));
+    }
+
+    fn exit(&self, span: &Id) {
+        ENTERED.with(|entered| {
+            let mut entered = entered.borrow_mut();
+            if let Some(position) = entered.iter().rposition(|id| *id == span.into_u64()) {
+                entered.remove(position);
+            }
+        });
+    }
+
+    fn clone_span(&self, span: &Id) -> Id {
+        if let Some(span) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            span.references += 1;
+        }
+        span.clone()
+    }
+
+    fn try_close(&self, span: Id) -> bool {
+        let mut spans = self.spans.lock().unwrap();
+        let Some(entry) = spans.get_mut(&span.into_u64()) else {
+            return false;
+        };
+        entry.references -= 1;
+        if entry.references > 0 {
+            return false;
+        }
+        spans.remove(&span.into_u64());
+        true
+    }
+}
+
+/*
+The message of an event and its other fields as " name=value" pairs
+*/
+#[derive(Default)]
+struct Fields {
+    message: String,
+    pairs: String,
+}
+
+impl Visit for Fields {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{:?}", value);
+        } else {
+            let _ = write!(self.pairs, " {}={:?}", field.name(), value);
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message.push_str(value);
+        } else {
+            let _ = write!(self.pairs, " {}={}", field.name(), value);
+        }
+    }
+}
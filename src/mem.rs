@@ -0,0 +1,45 @@
+/*
+The process's resident set size in bytes, or None where it can't be read
+*/
+#[cfg(target_os = "linux")]
+pub fn rss_bytes() -> Option<u64> {
+    // The second field of statm is the number of resident pages
+    let statm: String = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    // SAFETY: sysconf only reads a system constant
+    let page_size: i64 = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    u64::try_from(page_size).ok().map(|page_size| pages * page_size)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn rss_bytes() -> Option<u64> {
+    None
+}
+
+/*
+Running peak and average of the RSS samples taken during a run
+*/
+#[derive(Default)]
+pub struct MemoryUsage {
+    peak: u64,
+    total: u64,
+    samples: u64,
+}
+
+impl MemoryUsage {
+    pub fn sample(&mut self) {
+        if let Some(rss) = rss_bytes() {
+            self.peak = self.peak.max(rss);
+            self.total += rss;
+            self.samples += 1;
+        }
+    }
+
+    pub fn peak(&self) -> Option<u64> {
+        if self.samples > 0 { Some(self.peak) } else { None }
+    }
+
+    pub fn average(&self) -> Option<u64> {
+        self.total.checked_div(self.samples)
+    }
+}
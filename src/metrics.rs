@@ -0,0 +1,161 @@
+use crate::error::SpeedTestError;
+use crate::report::{SpeedTestResult, METRIC_PREFIX};
+use crate::sink::{MetricsSink, SampleRecord};
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tracing::{info, warn};
+
+/*
+The figures behind --metrics-listen, kept up to date by the print loop and the
+monitor loop and read by every scrape. The gauges describe the measurement in
+progress and the counters add up over the whole run.
+*/
+#[derive(Default)]
+pub struct LiveMetrics {
+    state: Mutex<MetricsState>,
+}
+
+#[derive(Default)]
+struct MetricsState {
+    current_bps: u64,
+    avg_bps: Option<u64>,
+    active_connections: u64,
+    running: bool,
+    // The bytes of finished measurements, and so far of the current one, which
+    // the final result may top up with what arrived after the last sample
+    finished_bytes: u64,
+    current_bytes: u64,
+    measurements: u64,
+    failed_measurements: u64,
+    retries: u64,
+    rate_limited: u64,
+}
+
+impl LiveMetrics {
+    /*
+    A measurement ended with this result; between measurements nothing is
+    being downloaded, so the gauges drop to zero
+    */
+    pub fn finished(&self, result: &SpeedTestResult) {
+        let mut state = self.state.lock().unwrap();
+        state.idle();
+        state.finished_bytes += result.total_bytes;
+        state.measurements += 1;
+        state.retries += result.retries.unwrap_or(0);
+        state.rate_limited += result.rate_limited;
+    }
+
+    pub fn failed(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.finished_bytes += state.current_bytes;
+        state.idle();
+        state.measurements += 1;
+        state.failed_measurements += 1;
+    }
+
+    /*
+    The Prometheus text exposition of the current figures
+    */
+    fn render(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let metrics: [(&str, &str, &str, Option<u64>); 9] = [
+            ("current_bps", "gauge", "Download speed over the latest sample, in bytes per second", Some(state.current_bps)),
+            ("average_bps", "gauge", "Download speed averaged over the --window, in bytes per second", state.avg_bps),
+            ("active_connections", "gauge", "Downloads in progress", Some(state.active_connections)),
+            ("running", "gauge", "Whether a measurement is in progress", Some(state.running as u64)),
+            ("downloaded_bytes_total", "counter", "Bytes downloaded by every measurement so far", Some(state.finished_bytes + state.current_bytes)),
+            ("measurements_total", "counter", "Measurements finished, whether or not they succeeded", Some(state.measurements)),
+            ("failed_measurements_total", "counter", "Measurements that failed", Some(state.failed_measurements)),
+            ("retries_total", "counter", "Downloads retried after a transient error", Some(state.retries)),
+            ("rate_limited_total", "counter", "Downloads the server answered with 429 Too Many Requests", Some(state.rate_limited)),
+        ];
+        // A gauge with no value yet, such as the average before the window has
+        // any data, is described but has no sample
+        metrics.iter()
+            .map(|(name, kind, help, value)| {
+                let sample: String = value.map(|v| format!("{}_{} {}\n", METRIC_PREFIX, name, v)).unwrap_or_default();
+                format!("# HELP {p}_{n} {h}\n# TYPE {p}_{n} {k}\n{s}", p = METRIC_PREFIX, n = name, h = help, k = kind, s = sample)
+            })
+            .collect()
+    }
+}
+
+impl MetricsState {
+    fn idle(&mut self) {
+        self.current_bps = 0;
+        self.avg_bps = None;
+        self.active_connections = 0;
+        self.running = false;
+        self.current_bytes = 0;
+    }
+}
+
+/*
+Feeds every sample of a measurement into the live metrics
+*/
+pub struct MetricsRecorder {
+    metrics: Arc<LiveMetrics>,
+}
+
+impl MetricsRecorder {
+    pub fn new(metrics: Arc<LiveMetrics>) -> MetricsRecorder {
+        MetricsRecorder { metrics }
+    }
+}
+
+impl MetricsSink for MetricsRecorder {
+    fn sample(&mut self, sample: &SampleRecord) -> Result<(), SpeedTestError> {
+        let mut state = self.metrics.state.lock().unwrap();
+        state.current_bps = sample.current_bps;
+        state.avg_bps = sample.avg_bps;
+        state.active_connections = sample.connections;
+        state.running = true;
+        state.current_bytes = sample.cumulative;
+        Ok(())
+    }
+}
+
+/*
+Serve the live metrics at /metrics on the given address for as long as the run
+lasts, so a long --monitor run can be scraped by Prometheus
+*/
+pub fn listen(addr: SocketAddr) -> Result<Arc<LiveMetrics>, SpeedTestError> {
+    let metrics: Arc<LiveMetrics> = Arc::new(LiveMetrics::default());
+    let shared: Arc<LiveMetrics> = metrics.clone();
+    let make_service = make_service_fn(move |_| {
+        let metrics: Arc<LiveMetrics> = shared.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                let metrics: Arc<LiveMetrics> = metrics.clone();
+                async move { Ok::<_, Infallible>(respond(&request, &metrics)) }
+            }))
+        }
+    });
+    let server = Server::try_bind(&addr)
+        .map_err(|e| SpeedTestError::Bind { action: "listen for metrics on", address: addr.to_string(), reason: e.to_string() })?
+        .serve(make_service);
+    info!("Serving metrics on http://{}/metrics", server.local_addr());
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            warn!("the metrics endpoint failed: {}", e);
+        }
+    });
+    Ok(metrics)
+}
+
+fn respond(request: &Request<Body>, metrics: &LiveMetrics) -> Response<Body> {
+    let (status, content_type, body): (StatusCode, &str, String) = match (request.method(), request.uri().path()) {
+        (&Method::GET | &Method::HEAD, "/metrics") => (StatusCode::OK, "text/plain; version=0.0.4", metrics.render()),
+        (_, "/metrics") => (StatusCode::METHOD_NOT_ALLOWED, "text/plain", "Only GET and HEAD are served\n".to_string()),
+        _ => (StatusCode::NOT_FOUND, "text/plain", "Metrics are served at /metrics\n".to_string()),
+    };
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, content_type)
+        .body(Body::from(body))
+        .expect("the response headers are valid")
+}
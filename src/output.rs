@@ -0,0 +1,61 @@
+use crate::error::SpeedTestError;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/*
+The file --output-file saves the download to. Every range writes its chunks at
+their own offset as they arrive, so the ranges can finish in any order.
+*/
+pub struct OutputFile {
+    file: File,
+    path: PathBuf,
+    // The file offset of the first byte downloaded, which lands at the start
+    base: u64,
+}
+
+impl OutputFile {
+    /*
+    Create the file, or truncate it, and size it for the download up front when
+    its length is known
+    */
+    pub fn create(path: &Path, base: u64, length: Option<u64>) -> Result<OutputFile, SpeedTestError> {
+        let failed = |source: io::Error| SpeedTestError::File { action: "create --output-file", path: path.to_path_buf(), source };
+        let file: File = File::create(path).map_err(failed)?;
+        if let Some(length) = length {
+            file.set_len(length).map_err(failed)?;
+        }
+        Ok(OutputFile { file, path: path.to_path_buf(), base })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /*
+    Write a chunk that starts at the given file offset. The writes are small
+    enough for the page cache to take without holding up the download.
+    */
+    pub fn write(&self, offset: u64, chunk: &[u8]) -> Result<(), SpeedTestError> {
+        write_at(&self.file, chunk, offset - self.base)
+            .map_err(|source| SpeedTestError::File { action: "write to --output-file", path: self.path.clone(), source })
+    }
+}
+
+#[cfg(unix)]
+fn write_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn write_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        let written: usize = std::os::windows::fs::FileExt::seek_write(file, buf, offset)?;
+        if written == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        buf = &buf[written..];
+        offset += written as u64;
+    }
+    Ok(())
+}
@@ -0,0 +1,89 @@
+use crate::config::SpeedTestConfig;
+use crate::engine::{format_average, format_eta, print_line};
+use crate::error::SpeedTestError;
+use crate::report::{format_speed, SpeedFormat};
+use crate::sink::{MetricsSink, SampleRecord};
+use std::io::{IsTerminal, Write};
+
+// The width of the bar itself, between its brackets
+const BAR_WIDTH: usize = 30;
+
+// Streams shown on each line of the per-connection status
+const STREAMS_PER_LINE: usize = 4;
+
+const MIB: f64 = 1024.0 * 1024.0;
+
+/*
+Whether the samples are drawn as a live display rather than printed line by
+line: only on a terminal, and not for --json-stream or with --no-progress
+*/
+pub fn live(config: &SpeedTestConfig) -> bool {
+    !config.no_progress && !config.json_stream && std::io::stdout().is_terminal()
+}
+
+/*
+A progress bar with the speed, ETA and each connection's rate, redrawn in place
+on every sample and cleared when the test ends so the summary follows the
+command line as it would without it
+*/
+pub struct LiveDisplay {
+    speed_format: SpeedFormat,
+    // How many lines the last frame took up, to move back over them
+    drawn: usize,
+}
+
+impl LiveDisplay {
+    pub fn new(config: &SpeedTestConfig) -> LiveDisplay {
+        LiveDisplay { speed_format: config.speed_format(), drawn: 0 }
+    }
+
+    fn lines(&self, sample: &SampleRecord) -> Vec<String> {
+        let p: usize = self.speed_format.precision;
+        let received: f64 = sample.cumulative as f64 / MIB;
+        let mut lines: Vec<String> = Vec::new();
+        lines.push(match sample.total {
+            Some(total) if total > 0 => {
+                let done: f64 = (sample.cumulative as f64 / total as f64).min(1.0);
+                let filled: usize = (done * BAR_WIDTH as f64) as usize;
+                let bar: String = if filled < BAR_WIDTH {
+                    format!("{}>{}", "=".repeat(filled), " ".repeat(BAR_WIDTH - filled - 1))
+                } else {
+                    "=".repeat(BAR_WIDTH)
+                };
+                format!("[{}] {:>3.0}%  {:.p$} of {:.p$} MiB", bar, done * 100.0, received, total as f64 / MIB)
+            }
+            // Without a size there is nothing to fill the bar towards
+            _ => format!("{:.p$} MiB received", received),
+        });
+        let note: &str = if sample.warmup { " (warm-up)" } else { "" };
+        lines.push(format!("{} now, {} average, ETA {}, {} connection(s){}",
+            format_speed(sample.current_bps, self.speed_format), format_average(sample.avg_bps, self.speed_format), format_eta(sample.eta), sample.connections, note));
+        for streams in sample.streams.chunks(STREAMS_PER_LINE) {
+            let rates: Vec<String> = streams.iter()
+                .map(|rate| format!("#{} {}", rate.stream, format_speed(rate.bps, self.speed_format)))
+                .collect();
+            lines.push(format!("  {}", rates.join("   ")));
+        }
+        lines
+    }
+}
+
+impl MetricsSink for LiveDisplay {
+    fn sample(&mut self, sample: &SampleRecord) -> Result<(), SpeedTestError> {
+        let lines: Vec<String> = self.lines(sample);
+        // Go back to the start of the previous frame and clear it, so a frame
+        // with fewer lines leaves nothing behind
+        let rewind: String = if self.drawn > 0 { format!("\x1b[{}F\x1b[J", self.drawn) } else { String::new() };
+        self.drawn = lines.len();
+        print_line(&format!("{}{}", rewind, lines.join("\n")))
+    }
+}
+
+impl Drop for LiveDisplay {
+    fn drop(&mut self) {
+        if self.drawn > 0 {
+            let mut stdout = std::io::stdout().lock();
+            let _ = write!(stdout, "\x1b[{}F\x1b[J", self.drawn).and_then(|_| stdout.flush());
+        }
+    }
+}
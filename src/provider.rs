@@ -0,0 +1,78 @@
+use serde::Deserialize;
+use std::path::Path;
+
+// What a run with no URL tests against
+pub const DEFAULT_PROVIDER: &str = "cloudflare";
+
+/*
+A test server --provider can pick, named for its location
+*/
+#[derive(Deserialize)]
+pub struct Server {
+    #[serde(default)]
+    pub name: String,
+    pub url: String,
+}
+
+// Well-known large test files, one per location; Cloudflare serves from
+// the nearest edge itself, so it needs only the one
+const CLOUDFLARE: &[(&str, &str)] = &[
+    ("anycast", "https://speed.cloudflare.com/__down?bytes=100000000"),
+];
+const HETZNER: &[(&str, &str)] = &[
+    ("fsn1", "https://fsn1-speed.hetzner.com/1GB.bin"),
+    ("nbg1", "https://nbg1-speed.hetzner.com/1GB.bin"),
+    ("hel1", "https://hel1-speed.hetzner.com/1GB.bin"),
+    ("ash", "https://ash-speed.hetzner.com/1GB.bin"),
+    ("hil", "https://hil-speed.hetzner.com/1GB.bin"),
+    ("sin", "https://sin-speed.hetzner.com/1GB.bin"),
+];
+const LINODE: &[(&str, &str)] = &[
+    ("newark", "https://speedtest.newark.linode.com/100MB-newark.bin"),
+    ("atlanta", "https://speedtest.atlanta.linode.com/100MB-atlanta.bin"),
+    ("dallas", "https://speedtest.dallas.linode.com/100MB-dallas.bin"),
+    ("fremont", "https://speedtest.fremont.linode.com/100MB-fremont.bin"),
+    ("frankfurt", "https://speedtest.frankfurt.linode.com/100MB-frankfurt.bin"),
+    ("london", "https://speedtest.london.linode.com/100MB-london.bin"),
+    ("singapore", "https://speedtest.singapore.linode.com/100MB-singapore.bin"),
+    ("tokyo2", "https://speedtest.tokyo2.linode.com/100MB-tokyo2.bin"),
+];
+const OVH: &[(&str, &str)] = &[
+    ("roubaix", "https://proof.ovh.net/files/1Gb.dat"),
+    ("beauharnois", "https://proof.ovh.ca/files/1Gb.dat"),
+];
+
+const BUILT_IN: &[(&str, &[(&str, &str)])] = &[
+    ("cloudflare", CLOUDFLARE),
+    ("hetzner", HETZNER),
+    ("linode", LINODE),
+    ("ovh", OVH),
+];
+
+/*
+The servers of a built-in provider, or those listed in a JSON file of
+{"name": ..., "url": ...} objects. A server without a name goes by its URL.
+*/
+pub fn servers(provider: &str) -> Result<Vec<Server>, String> {
+    if let Some((_, servers)) = BUILT_IN.iter().find(|(name, _)| name.eq_ignore_ascii_case(provider)) {
+        return Ok(servers.iter().map(|(name, url)| Server { name: name.to_string(), url: url.to_string() }).collect());
+    }
+    let path: &Path = Path::new(provider);
+    if !path.is_file() {
+        let names: Vec<&str> = BUILT_IN.iter().map(|(name, _)| *name).collect();
+        return Err(format!("Unknown --provider '{}': expected {} or the path of a JSON server list", provider, names.join(", ")));
+    }
+    let text: String = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let mut servers: Vec<Server> = serde_json::from_str(&text)
+        .map_err(|e| format!("Failed to parse {} as a list of {{\"name\", \"url\"}} servers: {}", path.display(), e))?;
+    if servers.is_empty() {
+        return Err(format!("{} lists no servers", path.display()));
+    }
+    for server in &mut servers {
+        if server.name.is_empty() {
+            server.name = server.url.clone();
+        }
+    }
+    Ok(servers)
+}
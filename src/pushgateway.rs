@@ -0,0 +1,49 @@
+use crate::report::SpeedTestResult;
+use crate::engine::{DeliveryClient, delivery_client};
+use crate::error::SpeedTestError;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Method, Request, Uri};
+use std::time::Duration;
+use tracing::warn;
+
+// How long to wait for the Pushgateway before giving up
+const PUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/*
+PUT the result to a Prometheus Pushgateway under the given job, replacing what
+the job pushed last time. Like the webhook, a failed push only produces a warning.
+*/
+pub async fn push(url: &str, job: &str, result: &SpeedTestResult) {
+    match tokio::time::timeout(PUSH_TIMEOUT, put(&delivery_client(), url, job, result)).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => warn!("failed to push metrics to {}: {}", url, e),
+        Err(_) => warn!("Pushgateway {} did not respond within {:.1} s", url, PUSH_TIMEOUT.as_secs_f64()),
+    }
+}
+
+async fn put(client: &DeliveryClient, url: &str, job: &str, result: &SpeedTestResult) -> Result<(), SpeedTestError> {
+    let uri: Uri = format!("{}/metrics/job/{}", url.trim_end_matches('/'), escape_path_segment(job)).parse::<Uri>()
+        .map_err(|e| SpeedTestError::InvalidUrl { url: url.to_string(), reason: e.to_string() })?;
+    let request: Request<Body> = Request::builder()
+        .method(Method::PUT)
+        .uri(uri)
+        .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(Body::from(result.to_prometheus()))
+        .map_err(|e| SpeedTestError::Other(e.to_string()))?;
+
+    let res: hyper::Response<Body> = client.request(request).await?;
+    if !res.status().is_success() {
+        return Err(SpeedTestError::BadStatus { status: res.status(), message: format!("server responded with {}", res.status()) });
+    }
+    Ok(())
+}
+
+/*
+Percent-encode everything but unreserved characters so the job name stays a single path segment
+*/
+fn escape_path_segment(segment: &str) -> String {
+    segment.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+        other => format!("%{:02X}", other),
+    }).collect()
+}
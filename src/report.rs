@@ -0,0 +1,645 @@
+use crate::config::SpeedUnits;
+use crate::stats::{ChunkSizeSummary, LatencySummary};
+use chrono::{DateTime, Local};
+use serde::{Serialize, Serializer};
+use std::net::IpAddr;
+
+/*
+Everything measured during a run, collected in one place so every output format
+renders the same data. Metrics that were not collected are left as None.
+*/
+#[derive(Debug, Default, Serialize)]
+pub struct SpeedTestResult {
+    pub workers: u64,
+    pub protocol: Option<String>,
+    pub tls_version: Option<String>,
+    pub content_encoding: Option<String>,
+    pub redirected_to: Option<String>,
+    pub edge_nodes: Option<String>,
+    pub range_requests: bool,
+    pub total_bytes: u64,
+    pub decoded_bytes: Option<u64>,
+    pub elapsed: f64,
+    pub avg_bps: u64,
+    pub min_bps: Option<u64>,
+    // The seconds into the test at which the slowest and fastest samples ended
+    pub min_at: Option<f64>,
+    pub peak_bps: Option<u64>,
+    pub peak_at: Option<f64>,
+    pub p50_bps: Option<u64>,
+    pub p90_bps: Option<u64>,
+    pub p95_bps: Option<u64>,
+    pub p99_bps: Option<u64>,
+    // How much the samples swing: their standard deviation, and that over their mean
+    pub stddev_bps: Option<u64>,
+    pub cv: Option<f64>,
+    pub ramp_percent: f64,
+    pub ramp_seconds: Option<f64>,
+    pub ttfb_ms: Option<f64>,
+    pub latency_ms: Option<LatencySummary>,
+    pub connect_ms: Option<LatencySummary>,
+    pub stall_count: u64,
+    pub stall_seconds: f64,
+    pub chunk_count: u64,
+    pub avg_chunk_bytes: u64,
+    pub chunk_sizes: Option<ChunkSizeSummary>,
+    pub header_bytes: u64,
+    pub requests: u64,
+    pub connections: Option<u64>,
+    pub reconnects: Option<u64>,
+    pub retries: Option<u64>,
+    pub rate_limited: u64,
+    pub rate_limited_seconds: f64,
+    // Only with --max-runtime, whether it cut the download short
+    pub stopped_early: Option<bool>,
+    // The signal that cut the download short, such as SIGINT for a Ctrl-C
+    pub interrupted_by: Option<String>,
+    pub seed: u64,
+    pub dns_ms: Option<f64>,
+    pub dns_server: Option<String>,
+    pub checksum_algorithm: Option<String>,
+    pub checksum_ok: Option<bool>,
+    pub baseline_bps: Option<u64>,
+    pub efficiency: Option<f64>,
+    pub fairness: Option<f64>,
+    pub baseline_deviation_percent: Option<f64>,
+    pub rss_avg_bytes: Option<u64>,
+    pub rss_peak_bytes: Option<u64>,
+    pub warmup: Option<WarmupSummary>,
+    pub duplex: Option<DuplexSummary>,
+    pub bufferbloat: Option<BufferbloatSummary>,
+    // Keyed by the stream's number so the flattened formats name each one
+    #[serde(serialize_with = "serialize_streams")]
+    pub streams: Option<Vec<StreamResult>>,
+    pub tool_version: String,
+}
+
+/*
+What one download received over the run, for --per-connection
+*/
+#[derive(Debug, Serialize)]
+pub struct StreamResult {
+    pub range: String,
+    // With --chunk-size, how many chunks the connection took
+    pub chunks: Option<u64>,
+    pub bytes: u64,
+    pub seconds: f64,
+    pub avg_bps: u64,
+}
+
+/*
+What --warmup left out of the speed figures, and those figures with it counted.
+The trimmed average covers everything after the warm-up.
+*/
+#[derive(Debug, Default, Serialize)]
+pub struct WarmupSummary {
+    pub seconds: f64,
+    pub bytes: u64,
+    pub trimmed_avg_bps: u64,
+    pub raw_avg_bps: u64,
+    pub raw_peak_bps: Option<u64>,
+    pub raw_p50_bps: Option<u64>,
+    pub raw_p95_bps: Option<u64>,
+}
+
+/*
+What --duplex uploaded while the file downloaded, and the load in both
+directions together. The averages cover the whole test.
+*/
+#[derive(Debug, Default, Serialize)]
+pub struct DuplexSummary {
+    pub upload_bytes: u64,
+    pub upload_connections: u64,
+    pub upload_avg_bps: u64,
+    pub upload_peak_bps: u64,
+    pub download_avg_bps: u64,
+    pub combined_bps: u64,
+}
+
+/*
+How long --loaded-latency probes took with the link idle and while the download
+loaded it. The increase compares their averages.
+*/
+#[derive(Debug, Default, Serialize)]
+pub struct BufferbloatSummary {
+    pub idle_ms: LatencySummary,
+    pub loaded_ms: LatencySummary,
+    pub increase_ms: f64,
+    pub grade: String,
+    // Probes during the download that failed rather than being answered
+    pub lost: u64,
+}
+
+fn serialize_streams<S: Serializer>(streams: &Option<Vec<StreamResult>>, serializer: S) -> Result<S::Ok, S::Error> {
+    match streams {
+        Some(streams) => serializer.collect_map(streams.iter().enumerate().map(|(i, stream)| (i.to_string(), stream))),
+        None => serializer.serialize_none(),
+    }
+}
+
+/*
+How the test went from one local address of an interface comparison
+*/
+#[derive(Serialize)]
+pub struct InterfaceResult {
+    pub address: IpAddr,
+    pub path: Option<String>,
+    pub avg_bps: Option<u64>,
+    pub error: Option<String>,
+}
+
+/*
+How the test went over one address family with --compare-stacks
+*/
+#[derive(Serialize)]
+pub struct StackResult {
+    pub family: String,
+    pub result: Option<SpeedTestResult>,
+    pub error: Option<String>,
+}
+
+/*
+How the test of one URL went in a run against several
+*/
+#[derive(Serialize)]
+pub struct TargetResult {
+    pub url: String,
+    pub total_bytes: Option<u64>,
+    pub elapsed: Option<f64>,
+    pub avg_bps: Option<u64>,
+    pub error: Option<String>,
+}
+
+/*
+Render an interface comparison, already ranked fastest first, as an aligned ASCII table
+*/
+pub fn comparison_table(results: &[InterfaceResult], format: SpeedFormat) -> String {
+    let mut rows: Vec<Vec<String>> = vec![vec!["Rank".to_string(), "Interface".to_string(), "Path".to_string(), "Average speed".to_string()]];
+    for (i, result) in results.iter().enumerate() {
+        rows.push(vec![
+            if result.error.is_some() { "-".to_string() } else { (i + 1).to_string() },
+            result.address.to_string(),
+            or_dash(result.path.clone(), |p| p),
+            match (&result.error, result.avg_bps) {
+                (Some(error), _) => format!("failed: {}", error),
+                (None, bps) => or_dash(bps, |bps| format_speed(bps, format)),
+            },
+        ]);
+    }
+    grid(&rows, None)
+}
+
+/*
+Render the summaries of the --compare-stacks runs next to each other, one column
+per address family, leaving out the rows that none of them measured
+*/
+pub fn stacks_table(results: &[StackResult], format: SpeedFormat) -> String {
+    let columns: Vec<Vec<(String, String)>> = results.iter()
+        .map(|stack| stack.result.as_ref().map(|result| result.rows(format)).unwrap_or_default())
+        .collect();
+    let mut keys: Vec<String> = Vec::new();
+    for (key, _) in columns.iter().flatten() {
+        if !keys.contains(key) {
+            keys.push(key.clone());
+        }
+    }
+
+    let mut rows: Vec<Vec<String>> = vec![std::iter::once(String::new()).chain(results.iter().map(|stack| stack.family.clone())).collect()];
+    if results.iter().any(|stack| stack.error.is_some()) {
+        rows.push(std::iter::once("Error".to_string())
+            .chain(results.iter().map(|stack| or_dash(stack.error.clone(), |e| e)))
+            .collect());
+    }
+    for key in keys {
+        let values: Vec<String> = columns.iter()
+            .map(|column| column.iter().find(|(k, _)| *k == key).map(|(_, v)| v.clone()).unwrap_or_else(|| "-".to_string()))
+            .collect();
+        if values.iter().any(|value| value != "-") {
+            rows.push(std::iter::once(key).chain(values).collect());
+        }
+    }
+    grid(&rows, None)
+}
+
+/*
+Render the results of a multi-target run, in the order the URLs were given, with
+the aggregate of them all in the last row
+*/
+pub fn targets_table(results: &[TargetResult], aggregate_bps: u64, format: SpeedFormat) -> String {
+    let mut rows: Vec<Vec<String>> = vec![vec!["URL".to_string(), "Total bytes".to_string(), "Elapsed".to_string(), "Average speed".to_string()]];
+    for result in results {
+        rows.push(vec![
+            result.url.clone(),
+            or_dash(result.total_bytes, |b| b.to_string()),
+            or_dash(result.elapsed, |s| format!("{:.1} s", s)),
+            match (&result.error, result.avg_bps) {
+                (Some(error), _) => format!("failed: {}", error),
+                (None, bps) => or_dash(bps, |bps| format_speed(bps, format)),
+            },
+        ]);
+    }
+    rows.push(vec![
+        "Aggregate".to_string(),
+        results.iter().filter_map(|r| r.total_bytes).sum::<u64>().to_string(),
+        "-".to_string(),
+        format_speed(aggregate_bps, format),
+    ]);
+    let footer: usize = rows.len() - 1;
+    grid(&rows, Some(footer))
+}
+
+/*
+Lay out rows of cells as an aligned ASCII table, with a border under the header
+row and above any footer row
+*/
+fn grid(rows: &[Vec<String>], footer: Option<usize>) -> String {
+    let columns: usize = rows.first().map(Vec::len).unwrap_or(0);
+    let widths: Vec<usize> = (0..columns).map(|c| rows.iter().map(|row| row[c].len()).max().unwrap_or(0)).collect();
+    let border: String = format!("+{}+", widths.iter().map(|w| "-".repeat(w + 2)).collect::<Vec<String>>().join("+"));
+
+    let mut out: String = String::new();
+    out.push_str(&border);
+    out.push('\n');
+    for (i, row) in rows.iter().enumerate() {
+        if Some(i) == footer {
+            out.push_str(&border);
+            out.push('\n');
+        }
+        let cells: Vec<String> = row.iter().zip(&widths).map(|(cell, w)| format!(" {:<w$} ", cell, w = w)).collect();
+        out.push_str(&format!("|{}|\n", cells.join("|")));
+        if i == 0 {
+            out.push_str(&border);
+            out.push('\n');
+        }
+    }
+    out.push_str(&border);
+    out
+}
+
+const MIB: f64 = 1024.0 * 1024.0;
+
+// Prefix of every exported Prometheus metric name
+pub(crate) const METRIC_PREFIX: &str = "httpbandwidthspeedtester";
+
+// Fields that identify a single run rather than measure it; exported as labels
+// they would start a new series on every run
+const UNEXPORTED_FIELDS: [&str; 1] = ["seed"];
+
+/*
+How speeds are written: in bits or bytes per second, and to how many decimal places
+*/
+#[derive(Clone, Copy)]
+pub struct SpeedFormat {
+    pub units: SpeedUnits,
+    pub precision: usize,
+}
+
+/*
+A speed given in bytes per second, written in the largest unit that keeps it at
+least 1: binary multiples of bytes, or decimal multiples of bits as links are
+quoted. Anything under the smallest multiple is a whole number.
+*/
+pub fn format_speed(bps: u64, format: SpeedFormat) -> String {
+    let (value, base, units): (f64, f64, [&str; 4]) = match format.units {
+        SpeedUnits::Bytes => (bps as f64, 1024.0, ["B/s", "KiB/s", "MiB/s", "GiB/s"]),
+        SpeedUnits::Bits => (bps as f64 * 8.0, 1000.0, ["bit/s", "kbit/s", "Mbit/s", "Gbit/s"]),
+    };
+    let mut scaled: f64 = value;
+    let mut unit: usize = 0;
+    while scaled >= base && unit < units.len() - 1 {
+        scaled /= base;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", value, units[0])
+    } else {
+        format!("{:.p$} {}", scaled, units[unit], p = format.precision)
+    }
+}
+
+fn or_dash<T>(value: Option<T>, format: impl Fn(T) -> String) -> String {
+    value.map(format).unwrap_or_else(|| "-".to_string())
+}
+
+fn describe_latency(latency: &LatencySummary) -> String {
+    format!("min {:.1}, avg {:.1}, p95 {:.1}, max {:.1} ms", latency.min, latency.avg, latency.p95, latency.max)
+}
+
+impl SpeedTestResult {
+    /*
+    The labelled metrics of the summary, leaving out those that weren't collected
+    */
+    fn rows(&self, format: SpeedFormat) -> Vec<(String, String)> {
+        let speed = |bps: u64| format_speed(bps, format);
+        // A sample's speed and when it was taken
+        let speed_at = |bps: u64, at: Option<f64>| match at {
+            Some(at) => format!("{} at {:.1} s", speed(bps), at),
+            None => speed(bps),
+        };
+        let ramp_label: String = format!("Time to {}% of peak", self.ramp_percent);
+        let rows: Vec<(&str, String)> = vec![
+            ("Workers", self.workers.to_string()),
+            ("Protocol", or_dash(self.protocol.clone(), |p| p)),
+            ("TLS version", or_dash(self.tls_version.clone(), |v| v)),
+            ("Content encoding", or_dash(self.content_encoding.clone(), |e| e)),
+            ("Redirected to", or_dash(self.redirected_to.clone(), |h| h)),
+            ("Edge nodes", or_dash(self.edge_nodes.clone(), |e| e)),
+            ("Range requests", if self.range_requests { "yes" } else { "no" }.to_string()),
+            ("Total bytes", self.total_bytes.to_string()),
+            ("Decoded bytes", or_dash(self.decoded_bytes, |b| b.to_string())),
+            ("Elapsed", format!("{:.1} s", self.elapsed)),
+            ("Average speed", speed(self.avg_bps)),
+            ("Min speed", or_dash(self.min_bps, |b| speed_at(b, self.min_at))),
+            ("Peak speed", or_dash(self.peak_bps, |b| speed_at(b, self.peak_at))),
+            ("p50 speed", or_dash(self.p50_bps, speed)),
+            ("p90 speed", or_dash(self.p90_bps, speed)),
+            ("p95 speed", or_dash(self.p95_bps, speed)),
+            ("p99 speed", or_dash(self.p99_bps, speed)),
+            ("Speed std dev", or_dash(self.stddev_bps, speed)),
+            ("Variation (CV)", or_dash(self.cv, |cv| format!("{:.1}%", cv * 100.0))),
+            (&ramp_label, or_dash(self.ramp_seconds, |s| format!("{:.1} s", s))),
+            ("TTFB", or_dash(self.ttfb_ms, |ms| format!("{:.1} ms", ms))),
+            ("Latency (HEAD TTFB)", or_dash(self.latency_ms.as_ref(), describe_latency)),
+            ("Connection setup", or_dash(self.connect_ms.as_ref(), describe_latency)),
+            ("DNS resolution", or_dash(self.dns_ms, |ms| match &self.dns_server {
+                Some(server) => format!("{:.1} ms via {}", ms, server),
+                None => format!("{:.1} ms", ms),
+            })),
+            ("Stalls", format!("{} ({:.1} s)", self.stall_count, self.stall_seconds)),
+            ("Chunks", format!("{} (avg {} bytes)", self.chunk_count, self.avg_chunk_bytes)),
+            ("Chunk sizes", or_dash(self.chunk_sizes.as_ref(), |c| format!("min {}, p50 {}, p90 {}, p99 {}, max {}", c.min, c.p50, c.p90, c.p99, c.max))),
+            ("Header bytes", self.header_bytes.to_string()),
+            ("Connections", format!("{} for {} request(s)", or_dash(self.connections, |c| c.to_string()), self.requests)),
+            ("Reconnects", or_dash(self.reconnects, |r| r.to_string())),
+            ("Retries", or_dash(self.retries, |r| r.to_string())),
+            ("Stopped by --max-runtime", or_dash(self.stopped_early, |stopped| if stopped { "yes" } else { "no" }.to_string())),
+            ("Interrupted by", or_dash(self.interrupted_by.clone(), |signal| signal)),
+            ("Rate limited (429)", or_dash(Some(self.rate_limited).filter(|n| *n > 0), |n| format!("{} download(s) ({:.1} s waiting)", n, self.rate_limited_seconds))),
+            ("Single-connection baseline", or_dash(self.baseline_bps, speed)),
+            ("Parallel efficiency", or_dash(self.efficiency, |e| format!("{:.0}%", e * 100.0))),
+            ("Worker fairness", or_dash(self.fairness, |f| format!("{:.3}", f))),
+            ("Deviation from baseline", or_dash(self.baseline_deviation_percent, |d| format!("{:+.1}%", d))),
+            ("Memory (RSS)", match (self.rss_avg_bytes, self.rss_peak_bytes) {
+                (Some(avg), Some(peak)) => format!("avg {:.1} MiB, peak {:.1} MiB", avg as f64 / MIB, peak as f64 / MIB),
+                _ => "-".to_string(),
+            }),
+            ("Warm-up excluded", or_dash(self.warmup.as_ref(), |w| format!("{:.1} s, {} bytes", w.seconds, w.bytes))),
+            ("Trimmed throughput", or_dash(self.warmup.as_ref(), |w| speed(w.trimmed_avg_bps))),
+            ("Raw throughput", or_dash(self.warmup.as_ref(), |w| speed(w.raw_avg_bps))),
+            ("Raw peak speed", or_dash(self.warmup.as_ref().and_then(|w| w.raw_peak_bps), speed)),
+            ("Raw p50 speed", or_dash(self.warmup.as_ref().and_then(|w| w.raw_p50_bps), speed)),
+            ("Raw p95 speed", or_dash(self.warmup.as_ref().and_then(|w| w.raw_p95_bps), speed)),
+            ("Duplex upload", or_dash(self.duplex.as_ref(), |d| format!("{} bytes over {} connection(s)", d.upload_bytes, d.upload_connections))),
+            ("Upload speed", or_dash(self.duplex.as_ref(), |d| speed(d.upload_avg_bps))),
+            ("Upload peak speed", or_dash(self.duplex.as_ref(), |d| speed(d.upload_peak_bps))),
+            ("Download speed", or_dash(self.duplex.as_ref(), |d| speed(d.download_avg_bps))),
+            ("Combined speed", or_dash(self.duplex.as_ref(), |d| speed(d.combined_bps))),
+            ("Idle latency", or_dash(self.bufferbloat.as_ref(), |b| describe_latency(&b.idle_ms))),
+            ("Loaded latency", or_dash(self.bufferbloat.as_ref(), |b| describe_latency(&b.loaded_ms))),
+            ("Bufferbloat", or_dash(self.bufferbloat.as_ref(), |b| {
+                let lost: String = if b.lost > 0 { format!(", {} probe(s) lost", b.lost) } else { String::new() };
+                format!("{} ({:+.1} ms under load{})", b.grade, b.increase_ms, lost)
+            })),
+            ("Checksum", match (&self.checksum_algorithm, self.checksum_ok) {
+                (Some(algorithm), Some(true)) => format!("{} OK", algorithm),
+                (Some(algorithm), _) => format!("{} mismatch", algorithm),
+                _ => "-".to_string(),
+            }),
+        ];
+        let mut rows: Vec<(String, String)> = rows.into_iter()
+            .filter(|(_, value)| value != "-")
+            .map(|(key, value)| (key.to_string(), value))
+            .collect();
+        // The slowest of several streams is the one to look at
+        let streams: &[StreamResult] = self.streams.as_deref().unwrap_or_default();
+        let slowest: Option<usize> = (0..streams.len()).min_by_key(|i| streams[*i].avg_bps).filter(|_| streams.len() > 1);
+        for (i, stream) in streams.iter().enumerate() {
+            let note: &str = if Some(i) == slowest { " (slowest)" } else { "" };
+            let took: String = match stream.chunks {
+                Some(chunks) => format!("{} chunk(s)", chunks),
+                None => stream.range.clone(),
+            };
+            rows.push((format!("Stream {}", i), format!("{} in {:.1} s at {}{}", took, stream.seconds, speed(stream.avg_bps), note)));
+        }
+        rows
+    }
+
+    /*
+    Render the result as an aligned ASCII table
+    */
+    pub fn to_table(&self, format: SpeedFormat) -> String {
+        let rows: Vec<(String, String)> = self.rows(format);
+        let key_width: usize = rows.iter().map(|(k, _)| k.len()).max().unwrap_or(0);
+        let value_width: usize = rows.iter().map(|(_, v)| v.len()).max().unwrap_or(0);
+        let border: String = format!("+-{}-+-{}-+", "-".repeat(key_width), "-".repeat(value_width));
+
+        let mut out: String = String::new();
+        out.push_str(&border);
+        out.push('\n');
+        for (key, value) in rows {
+            out.push_str(&format!("| {:<kw$} | {:<vw$} |\n", key, value, kw = key_width, vw = value_width));
+        }
+        out.push_str(&border);
+        out
+    }
+
+    /*
+    Column names matching to_csv_row
+    */
+    pub fn csv_header() -> &'static str {
+        "timestamp,workers,total_bytes,elapsed,avg_bps,peak_bps,p95_bps,ttfb_ms,stall_count,stall_seconds,checksum_ok"
+    }
+
+    /*
+    Render the headline metrics as one CSV row, leaving metrics that weren't collected empty
+    */
+    pub fn to_csv_row(&self, timestamp: &DateTime<Local>) -> String {
+        let empty = |value: Option<String>| value.unwrap_or_default();
+        [
+            timestamp.to_rfc3339(),
+            self.workers.to_string(),
+            self.total_bytes.to_string(),
+            format!("{:.3}", self.elapsed),
+            self.avg_bps.to_string(),
+            empty(self.peak_bps.map(|b| b.to_string())),
+            empty(self.p95_bps.map(|b| b.to_string())),
+            empty(self.ttfb_ms.map(|ms| format!("{:.1}", ms))),
+            self.stall_count.to_string(),
+            format!("{:.1}", self.stall_seconds),
+            empty(self.checksum_ok.map(|ok| ok.to_string())),
+        ].join(",")
+    }
+
+    /*
+    Render the result as a single line of key=value pairs, skipping metrics that weren't collected
+    */
+    pub fn to_kv(&self) -> String {
+        let value: serde_json::Value = serde_json::to_value(self).unwrap_or_default();
+        let mut fields: Vec<(String, serde_json::Value)> = Vec::new();
+        flatten(&mut fields, "", value);
+        fields.into_iter()
+            .filter_map(|(key, value)| display_value(value).map(|value| format!("{}={}", key, value)))
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
+    /*
+    Every field name a summary template may refer to, including metrics that
+    are only present in some runs
+    */
+    pub fn field_names() -> Vec<String> {
+        let example: SpeedTestResult = SpeedTestResult {
+            chunk_sizes: Some(ChunkSizeSummary::default()),
+            latency_ms: Some(LatencySummary::default()),
+            connect_ms: Some(LatencySummary::default()),
+            warmup: Some(WarmupSummary::default()),
+            duplex: Some(DuplexSummary::default()),
+            bufferbloat: Some(BufferbloatSummary::default()),
+            ..Default::default()
+        };
+        let value: serde_json::Value = serde_json::to_value(&example).unwrap_or_default();
+        let mut fields: Vec<(String, serde_json::Value)> = Vec::new();
+        flatten(&mut fields, "", value);
+        with_mbps(fields).into_iter().map(|(key, _)| key).collect()
+    }
+
+    /*
+    The flattened fields and their values for filling in a template, skipping
+    metrics that weren't collected
+    */
+    pub fn template_fields(&self) -> Vec<(String, String)> {
+        let value: serde_json::Value = serde_json::to_value(self).unwrap_or_default();
+        let mut fields: Vec<(String, serde_json::Value)> = Vec::new();
+        flatten(&mut fields, "", value);
+        with_mbps(fields).into_iter()
+            .filter_map(|(key, value)| display_value(value).map(|value| (key, value)))
+            .collect()
+    }
+
+    /*
+    Render the result in the Prometheus text format: one gauge per numeric
+    metric, and the text fields as labels of a single info metric
+    */
+    pub fn to_prometheus(&self) -> String {
+        let value: serde_json::Value = serde_json::to_value(self).unwrap_or_default();
+        let mut fields: Vec<(String, serde_json::Value)> = Vec::new();
+        flatten(&mut fields, "", value);
+
+        let mut out: String = String::new();
+        let mut labels: Vec<String> = Vec::new();
+        for (key, value) in fields {
+            let key: String = key.replace('.', "_");
+            if UNEXPORTED_FIELDS.contains(&key.as_str()) {
+                continue;
+            }
+            let sample: Option<f64> = match value {
+                serde_json::Value::Number(n) => n.as_f64(),
+                serde_json::Value::Bool(b) => Some(if b { 1.0 } else { 0.0 }),
+                serde_json::Value::Null => None,
+                other => {
+                    labels.push(format!("{}=\"{}\"", key, escape_label(&display_value(other).unwrap_or_default())));
+                    None
+                }
+            };
+            if let Some(sample) = sample {
+                out.push_str(&format!("# TYPE {p}_{k} gauge\n{p}_{k} {v}\n", p = METRIC_PREFIX, k = key, v = sample));
+            }
+        }
+        out.push_str(&format!("# TYPE {p}_info gauge\n{p}_info{{{l}}} 1\n", p = METRIC_PREFIX, l = labels.join(",")));
+        out
+    }
+}
+
+/*
+Escape a Prometheus label value
+*/
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/*
+Flatten a JSON value into (key, value) pairs, naming nested fields parent.child
+*/
+fn flatten(fields: &mut Vec<(String, serde_json::Value)>, prefix: &str, value: serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map {
+                let key: String = if prefix.is_empty() { key } else { format!("{}.{}", prefix, key) };
+                flatten(fields, &key, value);
+            }
+        }
+        other => fields.push((prefix.to_string(), other)),
+    }
+}
+
+/*
+Add a megabits-per-second companion, e.g. avg_mbps, after every *_bps field
+*/
+fn with_mbps(fields: Vec<(String, serde_json::Value)>) -> Vec<(String, serde_json::Value)> {
+    let mut out: Vec<(String, serde_json::Value)> = Vec::new();
+    for (key, value) in fields {
+        let mbps: Option<(String, serde_json::Value)> = key.strip_suffix("_bps").map(|stem| {
+            let mbps: serde_json::Value = value.as_u64()
+                .map(|bps| serde_json::json!((bps as f64 * 8.0 / 10_000.0).round() / 100.0))
+                .unwrap_or(serde_json::Value::Null);
+            (format!("{}_mbps", stem), mbps)
+        });
+        out.push((key, value));
+        out.extend(mbps);
+    }
+    out
+}
+
+fn display_value(value: serde_json::Value) -> Option<String> {
+    match value {
+        serde_json::Value::Null => None,
+        serde_json::Value::String(s) => Some(s),
+        other => Some(other.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format() -> SpeedFormat {
+        SpeedFormat { units: SpeedUnits::Bytes, precision: 2 }
+    }
+
+    #[test]
+    fn leaves_out_metrics_that_were_not_collected() {
+        let result: SpeedTestResult = SpeedTestResult { workers: 4, total_bytes: 1000, ..SpeedTestResult::default() };
+        let table: String = result.to_table(format());
+        assert!(!table.lines().any(|line| line.ends_with("| - |")), "uncollected rows are shown: {}", table);
+        assert!(!table.contains("| TLS version"), "uncollected rows are shown: {}", table);
+        assert!(table.contains("| Workers "), "collected rows are missing: {}", table);
+        assert!(table.contains("| Total bytes "), "collected rows are missing: {}", table);
+    }
+
+    #[test]
+    fn shows_metrics_once_collected() {
+        let result: SpeedTestResult = SpeedTestResult {
+            tls_version: Some("TLSv1.3".to_string()),
+            peak_bps: Some(2048),
+            peak_at: Some(1.5),
+            ..SpeedTestResult::default()
+        };
+        let rows: Vec<(String, String)> = result.rows(format());
+        let row = |key: &str| rows.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str());
+        assert_eq!(row("TLS version"), Some("TLSv1.3"));
+        assert_eq!(row("Peak speed"), Some("2.00 KiB/s at 1.5 s"));
+        assert_eq!(row("Min speed"), None);
+    }
+
+    #[test]
+    fn shows_the_max_runtime_and_429_rows_only_when_they_apply() {
+        let rows = |result: SpeedTestResult| result.rows(format()).into_iter().map(|(k, _)| k).collect::<Vec<String>>();
+        let plain: Vec<String> = rows(SpeedTestResult::default());
+        assert!(!plain.iter().any(|k| k == "Stopped by --max-runtime" || k == "Rate limited (429)"), "unexpected rows: {:?}", plain);
+
+        let bounded: Vec<(String, String)> = SpeedTestResult { stopped_early: Some(false), rate_limited: 2, rate_limited_seconds: 1.5, ..SpeedTestResult::default() }.rows(format());
+        let row = |key: &str| bounded.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str());
+        assert_eq!(row("Stopped by --max-runtime"), Some("no"));
+        assert_eq!(row("Rate limited (429)"), Some("2 download(s) (1.5 s waiting)"));
+    }
+}
@@ -0,0 +1,205 @@
+use crate::config::PayloadPattern;
+use crate::engine::print_line;
+use crate::error::SpeedTestError;
+use crate::upload;
+use bytes::Bytes;
+use futures_util::StreamExt;
+use hyper::body::HttpBody;
+use hyper::header::{ACCEPT_RANGES, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, RANGE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+
+/*
+What a request's Range header asks for out of a file of a given size
+*/
+#[derive(Debug, PartialEq)]
+enum RangeRequest {
+    // No usable header, so the whole file is sent
+    Whole,
+    Part(u64, u64),
+    Unsatisfiable,
+}
+
+/*
+Serve generated files until Ctrl-C: GET or HEAD /SIZE returns SIZE bytes of the
+payload pattern, with range support, and a POST or PUT to any path is read and
+thrown away
+*/
+pub async fn serve(listen: SocketAddr, pattern: PayloadPattern, seed: u64) -> Result<(), SpeedTestError> {
+    let make_service = make_service_fn(move |_| async move {
+        Ok::<_, Infallible>(service_fn(move |request: Request<Body>| respond(request, pattern, seed)))
+    });
+    let server = Server::try_bind(&listen)
+        .map_err(|e| SpeedTestError::Bind { action: "listen on", address: listen.to_string(), reason: e.to_string() })?
+        .serve(make_service);
+    print_line(&format!("Serving on http://{}/: GET /SIZE to download (e.g. /100M), POST or PUT to upload", server.local_addr()))?;
+    if matches!(pattern, PayloadPattern::Random) {
+        print_line(&format!("Using random seed {}", seed))?;
+    }
+    server.with_graceful_shutdown(async {
+        tokio::signal::ctrl_c().await.ok();
+    }).await?;
+    Ok(())
+}
+
+async fn respond(request: Request<Body>, pattern: PayloadPattern, seed: u64) -> Result<Response<Body>, Infallible> {
+    let response: Response<Body> = match *request.method() {
+        Method::GET | Method::HEAD => download(&request, pattern, seed),
+        Method::POST | Method::PUT => sink(request.into_body()).await,
+        _ => plain(StatusCode::METHOD_NOT_ALLOWED, "Only GET, HEAD, POST and PUT are served\n".to_string()),
+    };
+    Ok(response)
+}
+
+/*
+The requested part of a generated file, named by its size
+*/
+fn download(request: &Request<Body>, pattern: PayloadPattern, seed: u64) -> Response<Body> {
+    // Anything after a dot is ignored so the URL can look like a file, e.g. /1G.bin
+    let name: &str = request.uri().path().trim_start_matches('/');
+    let Some(size) = parse_size(name.split('.').next().unwrap_or_default()) else {
+        return plain(StatusCode::NOT_FOUND, format!("Ask for /SIZE, e.g. /100M; '{}' is not a size\n", name));
+    };
+    let range: RangeRequest = request.headers().get(RANGE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| requested_range(v, size))
+        .unwrap_or(RangeRequest::Whole);
+
+    let builder = Response::builder()
+        .header(ACCEPT_RANGES, "bytes")
+        .header(CONTENT_TYPE, "application/octet-stream")
+        // Proxies in between would otherwise be measured instead of the network
+        .header(CACHE_CONTROL, "no-store");
+    let (builder, start, length) = match range {
+        RangeRequest::Whole => (builder, 0, size),
+        RangeRequest::Part(start, end) => (
+            builder.status(StatusCode::PARTIAL_CONTENT).header(CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, size)),
+            start,
+            end - start + 1,
+        ),
+        RangeRequest::Unsatisfiable => {
+            return builder.status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(CONTENT_RANGE, format!("bytes */{}", size))
+                .body(Body::empty())
+                .expect("the response headers are valid");
+        }
+    };
+    let body: Body = if request.method() == Method::HEAD {
+        Body::empty()
+    } else {
+        Body::wrap_stream(upload::payload_range(start, length, pattern, seed).map(Ok::<Bytes, Infallible>))
+    };
+    builder.header(CONTENT_LENGTH, length).body(body).expect("the response headers are valid")
+}
+
+/*
+Read an upload to the end, keeping nothing but its size
+*/
+async fn sink(mut body: Body) -> Response<Body> {
+    let mut received: u64 = 0;
+    while let Some(chunk) = body.data().await {
+        match chunk {
+            Ok(chunk) => received += chunk.len() as u64,
+            Err(e) => return plain(StatusCode::BAD_REQUEST, format!("Failed to read the upload: {}\n", e)),
+        }
+    }
+    plain(StatusCode::OK, format!("Received {} bytes\n", received))
+}
+
+fn plain(status: StatusCode, message: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/plain")
+        .body(Body::from(message))
+        .expect("the response headers are valid")
+}
+
+/*
+A byte count with an optional K, M or G suffix for binary multiples, e.g. 512K or 10G
+*/
+fn parse_size(value: &str) -> Option<u64> {
+    let (digits, multiplier): (&str, u64) = match value.char_indices().last()? {
+        (i, 'k' | 'K') => (&value[..i], 1 << 10),
+        (i, 'm' | 'M') => (&value[..i], 1 << 20),
+        (i, 'g' | 'G') => (&value[..i], 1 << 30),
+        _ => (value, 1),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+/*
+Parse a single-range Range header, e.g. bytes=0-99, bytes=100- or bytes=-100.
+Anything else is ignored and the whole file sent, as a server may.
+*/
+fn requested_range(value: &str, size: u64) -> RangeRequest {
+    let Some((first, last)) = value.strip_prefix("bytes=").and_then(|v| v.split_once('-')) else {
+        return RangeRequest::Whole;
+    };
+    let (first, last): (&str, &str) = (first.trim(), last.trim());
+    let (start, end): (u64, u64) = match (first.parse::<u64>(), last.parse::<u64>()) {
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(size.saturating_sub(1))),
+        (Ok(start), Err(_)) if last.is_empty() => (start, size.saturating_sub(1)),
+        (Err(_), Ok(suffix)) if first.is_empty() && suffix > 0 => (size.saturating_sub(suffix), size.saturating_sub(1)),
+        _ => return RangeRequest::Whole,
+    };
+    if start < size {
+        RangeRequest::Part(start, end)
+    } else {
+        RangeRequest::Unsatisfiable
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_sizes_in_binary_multiples() {
+        assert_eq!(parse_size("0"), Some(0));
+        assert_eq!(parse_size("1000"), Some(1000));
+        assert_eq!(parse_size("512K"), Some(512 << 10));
+        assert_eq!(parse_size("4m"), Some(4 << 20));
+        assert_eq!(parse_size("10G"), Some(10 << 30));
+    }
+
+    #[test]
+    fn rejects_what_is_no_size() {
+        for value in ["", "K", "1.5M", "-1", "10T", "10 M", "size"] {
+            assert_eq!(parse_size(value), None, "{:?} was read", value);
+        }
+        // 2^34 GiB is one past u64::MAX
+        assert_eq!(parse_size("17179869184G"), None);
+        assert_eq!(parse_size("17179869183G"), Some(17179869183 << 30));
+    }
+
+    #[test]
+    fn reads_each_form_of_range() {
+        assert_eq!(requested_range("bytes=0-99", 1000), RangeRequest::Part(0, 99));
+        assert_eq!(requested_range("bytes=100-", 1000), RangeRequest::Part(100, 999));
+        assert_eq!(requested_range("bytes=-100", 1000), RangeRequest::Part(900, 999));
+        assert_eq!(requested_range("bytes= 5 - 9 ", 1000), RangeRequest::Part(5, 9));
+    }
+
+    #[test]
+    fn clamps_a_range_to_the_file() {
+        assert_eq!(requested_range("bytes=900-5000", 1000), RangeRequest::Part(900, 999));
+        assert_eq!(requested_range("bytes=-5000", 1000), RangeRequest::Part(0, 999));
+        assert_eq!(requested_range("bytes=999-999", 1000), RangeRequest::Part(999, 999));
+    }
+
+    #[test]
+    fn refuses_a_range_past_the_end() {
+        assert_eq!(requested_range("bytes=1000-", 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(requested_range("bytes=1000-2000", 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(requested_range("bytes=0-", 0), RangeRequest::Unsatisfiable);
+    }
+
+    #[test]
+    fn ignores_a_range_it_cannot_serve() {
+        for value in ["items=0-99", "bytes=0-99,200-299", "bytes=99-0", "bytes=-0", "bytes=-", "bytes=a-b", "bytes=5"] {
+            assert_eq!(requested_range(value, 1000), RangeRequest::Whole, "{:?} was read", value);
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use crate::error::SpeedTestError;
+use std::sync::OnceLock;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+/*
+The first Ctrl-C or SIGTERM of the run, broadcast to every download and print
+loop through a cancellation token they each hold a child of
+*/
+struct Shutdown {
+    token: CancellationToken,
+    signal: OnceLock<&'static str>,
+}
+
+static SHUTDOWN: OnceLock<Shutdown> = OnceLock::new();
+
+fn shutdown() -> &'static Shutdown {
+    SHUTDOWN.get_or_init(|| Shutdown { token: CancellationToken::new(), signal: OnceLock::new() })
+}
+
+/*
+A token cancelled by a shutdown, and which can be cancelled on its own, as
+--max-runtime does, without stopping anything else
+*/
+pub fn token() -> CancellationToken {
+    shutdown().token.child_token()
+}
+
+/*
+The signal that interrupted the run, if one has
+*/
+pub fn signal() -> Option<&'static str> {
+    shutdown().signal.get().copied()
+}
+
+pub async fn requested() {
+    shutdown().token.cancelled().await
+}
+
+/*
+Catch Ctrl-C and SIGTERM for the rest of the run. The first stops the downloads
+so the summary can be made from what has arrived; a second exits at once.
+*/
+pub fn listen() {
+    tokio::spawn(async {
+        let Some(signal) = next_signal().await else {
+            return;
+        };
+        info!("Interrupted by {}; stopping the downloads to report on what has arrived, press Ctrl-C again to abort", signal);
+        let _ = shutdown().signal.set(signal);
+        shutdown().token.cancel();
+        if let Some(signal) = next_signal().await {
+            std::process::exit(SpeedTestError::Interrupted { signal }.exit_code().into());
+        }
+    });
+}
+
+#[cfg(unix)]
+async fn next_signal() -> Option<&'static str> {
+    use tokio::signal::unix::{signal, Signal, SignalKind};
+    let (mut interrupt, mut terminate): (Signal, Signal) = match (signal(SignalKind::interrupt()), signal(SignalKind::terminate())) {
+        (Ok(interrupt), Ok(terminate)) => (interrupt, terminate),
+        _ => return None,
+    };
+    tokio::select! {
+        _ = interrupt.recv() => Some("SIGINT"),
+        _ = terminate.recv() => Some("SIGTERM"),
+    }
+}
+
+#[cfg(not(unix))]
+async fn next_signal() -> Option<&'static str> {
+    tokio::signal::ctrl_c().await.ok().map(|_| "Ctrl-C")
+}
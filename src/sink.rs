@@ -0,0 +1,82 @@
+use crate::error::SpeedTestError;
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/*
+One sample of the transfer, as handed to every sink
+*/
+pub struct SampleRecord {
+    pub elapsed: f64,
+    pub timestamp: DateTime<Local>,
+    pub bytes: u64,
+    pub cumulative: u64,
+    pub current_bps: u64,
+    // None until a full interval of the --window has been averaged
+    pub avg_bps: Option<u64>,
+    pub connections: u64,
+    pub eta: Option<Duration>,
+    // The size of the whole transfer, when the server said
+    pub total: Option<u64>,
+    // Taken within the --warmup, so left out of the summary's speed figures
+    pub warmup: bool,
+    // Filled in only with --per-connection
+    pub streams: Vec<StreamRate>,
+}
+
+/*
+One download's rate over a sample
+*/
+#[derive(Serialize)]
+pub struct StreamRate {
+    pub stream: usize,
+    pub bps: u64,
+}
+
+/*
+Somewhere the per-sample metrics are written. The print loop fans every sample
+out to each of the run's sinks in turn.
+*/
+pub trait MetricsSink: Send {
+    fn sample(&mut self, sample: &SampleRecord) -> Result<(), SpeedTestError>;
+}
+
+/*
+Appends one CSV row per sample to a file, for plotting in a spreadsheet or
+Grafana. The header is written when the file is new.
+*/
+pub struct CsvSink {
+    path: PathBuf,
+    file: BufWriter<File>,
+}
+
+impl CsvSink {
+    pub fn open(path: &Path) -> Result<CsvSink, SpeedTestError> {
+        let failed = |source: std::io::Error| SpeedTestError::File { action: "open", path: path.to_path_buf(), source };
+        let file: File = OpenOptions::new().create(true).append(true).open(path).map_err(failed)?;
+        let new: bool = file.metadata().map_err(failed)?.len() == 0;
+        let mut sink: CsvSink = CsvSink { path: path.to_path_buf(), file: BufWriter::new(file) };
+        if new {
+            // Elapsed first, as the time axis, like the --json-stream samples
+            sink.write("elapsed,timestamp,bytes,bps,avg_bps,connections")?;
+        }
+        Ok(sink)
+    }
+
+    fn write(&mut self, line: &str) -> Result<(), SpeedTestError> {
+        // Flushed every row so the file can be followed while the test runs
+        writeln!(self.file, "{}", line)
+            .and_then(|_| self.file.flush())
+            .map_err(|source| SpeedTestError::File { action: "write to", path: self.path.clone(), source })
+    }
+}
+
+impl MetricsSink for CsvSink {
+    fn sample(&mut self, sample: &SampleRecord) -> Result<(), SpeedTestError> {
+        self.write(&format!("{:.3},{},{},{},{},{}",
+            sample.elapsed, sample.timestamp.to_rfc3339(), sample.bytes, sample.current_bps, sample.avg_bps.map(|bps| bps.to_string()).unwrap_or_default(), sample.connections))
+    }
+}
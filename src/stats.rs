@@ -0,0 +1,208 @@
+use serde::Serialize;
+use std::time::Duration;
+
+/*
+One per-second throughput measurement, taken `elapsed` seconds into the test.
+A sample that began within the --warmup is left out of the trimmed figures.
+*/
+pub struct Sample {
+    pub elapsed: f64,
+    pub bps: u64,
+    pub warmup: bool,
+}
+
+/*
+Return how long into the test throughput first reached `pct` percent of its peak
+*/
+pub fn time_to_peak_fraction(samples: &[Sample], pct: f64) -> Option<f64> {
+    let peak: u64 = samples.iter().map(|s| s.bps).max()?;
+    let target: f64 = peak as f64 * pct / 100.0;
+    samples.iter().find(|s| s.bps as f64 >= target).map(|s| s.elapsed)
+}
+
+/*
+The first of the (connections, bps) levels to reach `pct` percent of the best
+level's throughput: the point past which adding connections stops helping much
+*/
+pub fn knee(levels: &[(u64, u64)], pct: f64) -> Option<(u64, u64)> {
+    let best: u64 = levels.iter().map(|(_, bps)| *bps).max()?;
+    let target: f64 = best as f64 * pct / 100.0;
+    levels.iter().find(|(_, bps)| *bps as f64 >= target).copied()
+}
+
+/*
+Return the value at the given percentile (0-100) using the nearest-rank method
+*/
+pub fn percentile(samples: &[u64], pct: f64) -> Option<u64> {
+    if samples.is_empty() {
+        return None;
+    }
+    let mut sorted: Vec<u64> = samples.to_vec();
+    sorted.sort_unstable();
+    let rank: usize = ((pct / 100.0) * sorted.len() as f64).ceil() as usize;
+    Some(sorted[rank.clamp(1, sorted.len()) - 1])
+}
+
+/*
+Spread of a set of timings, in milliseconds
+*/
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct LatencySummary {
+    pub min: f64,
+    pub avg: f64,
+    pub p95: f64,
+    pub max: f64,
+}
+
+pub fn latency_summary(times: &[Duration]) -> Option<LatencySummary> {
+    let micros: Vec<u64> = times.iter().map(|t| t.as_micros() as u64).collect();
+    let ms = |us: u64| us as f64 / 1000.0;
+    Some(LatencySummary {
+        min: ms(*micros.iter().min()?),
+        avg: ms(micros.iter().sum::<u64>() / micros.len() as u64),
+        p95: ms(percentile(&micros, 95.0)?),
+        max: ms(*micros.iter().max()?),
+    })
+}
+
+/*
+Grade how much latency rose under load, on the scale the Waveform bufferbloat
+test uses
+*/
+pub fn bufferbloat_grade(increase_ms: f64) -> &'static str {
+    match increase_ms {
+        ms if ms < 5.0 => "A+",
+        ms if ms < 30.0 => "A",
+        ms if ms < 60.0 => "B",
+        ms if ms < 200.0 => "C",
+        ms if ms < 400.0 => "D",
+        _ => "F",
+    }
+}
+
+/*
+The mean and population standard deviation of the samples
+*/
+pub fn mean_and_stddev(samples: &[u64]) -> Option<(f64, f64)> {
+    if samples.is_empty() {
+        return None;
+    }
+    let n: f64 = samples.len() as f64;
+    let mean: f64 = samples.iter().map(|s| *s as f64).sum::<f64>() / n;
+    let variance: f64 = samples.iter().map(|s| (*s as f64 - mean).powi(2)).sum::<f64>() / n;
+    Some((mean, variance.sqrt()))
+}
+
+/*
+Jain's fairness index of the given rates: 1.0 when they are all equal, falling
+towards 1/n as a single one dominates
+*/
+pub fn jain_fairness(rates: &[f64]) -> Option<f64> {
+    let sum: f64 = rates.iter().sum();
+    let sum_of_squares: f64 = rates.iter().map(|r| r * r).sum();
+    if rates.is_empty() || sum_of_squares == 0.0 {
+        return None;
+    }
+    Some(sum * sum / (rates.len() as f64 * sum_of_squares))
+}
+
+/*
+Histogram of body chunk sizes using power-of-two buckets, so memory stays fixed
+however many chunks arrive. Bucket i counts chunks of 2^i to 2^(i+1)-1 bytes.
+*/
+pub struct ChunkHistogram {
+    buckets: [u64; 64],
+    count: u64,
+    min: u64,
+    max: u64,
+}
+
+/*
+Chunk size distribution; percentiles are the upper bound of their bucket
+*/
+#[derive(Debug, Default, Serialize)]
+pub struct ChunkSizeSummary {
+    pub min: u64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub max: u64,
+}
+
+impl ChunkHistogram {
+    pub fn new() -> ChunkHistogram {
+        ChunkHistogram { buckets: [0; 64], count: 0, min: u64::MAX, max: 0 }
+    }
+
+    pub fn record(&mut self, len: u64) {
+        let bucket: usize = if len == 0 { 0 } else { 63 - len.leading_zeros() as usize };
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.min = self.min.min(len);
+        self.max = self.max.max(len);
+    }
+
+    fn percentile(&self, pct: f64) -> u64 {
+        let rank: u64 = ((pct / 100.0) * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen: u64 = 0;
+        for (i, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let upper: u64 = if i == 63 { u64::MAX } else { (1u64 << (i + 1)) - 1 };
+                return upper.clamp(self.min, self.max);
+            }
+        }
+        self.max
+    }
+
+    pub fn summary(&self) -> Option<ChunkSizeSummary> {
+        if self.count == 0 {
+            return None;
+        }
+        Some(ChunkSizeSummary {
+            min: self.min,
+            p50: self.percentile(50.0),
+            p90: self.percentile(90.0),
+            p99: self.percentile(99.0),
+            max: self.max,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_the_nearest_rank() {
+        let samples: Vec<u64> = (1..=10).rev().collect();
+        assert_eq!(percentile(&samples, 0.0), Some(1));
+        assert_eq!(percentile(&samples, 10.0), Some(1));
+        assert_eq!(percentile(&samples, 11.0), Some(2));
+        assert_eq!(percentile(&samples, 50.0), Some(5));
+        assert_eq!(percentile(&samples, 95.0), Some(10));
+        assert_eq!(percentile(&samples, 100.0), Some(10));
+        assert_eq!(percentile(&[7], 50.0), Some(7));
+        assert_eq!(percentile(&[], 50.0), None);
+    }
+
+    #[test]
+    fn rates_equal_shares_as_fair() {
+        assert_eq!(jain_fairness(&[5.0, 5.0, 5.0, 5.0]), Some(1.0));
+        assert_eq!(jain_fairness(&[9.0]), Some(1.0));
+        assert_eq!(jain_fairness(&[10.0, 0.0, 0.0, 0.0]), Some(0.25));
+        let fairness: f64 = jain_fairness(&[1.0, 3.0]).unwrap();
+        assert!((fairness - 0.8).abs() < 1e-12, "unexpected fairness {}", fairness);
+        assert_eq!(jain_fairness(&[]), None);
+        assert_eq!(jain_fairness(&[0.0, 0.0]), None);
+    }
+
+    #[test]
+    fn finds_where_more_connections_stop_helping() {
+        let levels: Vec<(u64, u64)> = vec![(1, 100), (2, 180), (4, 240), (8, 250), (16, 245)];
+        assert_eq!(knee(&levels, 90.0), Some((4, 240)));
+        assert_eq!(knee(&levels, 100.0), Some((8, 250)));
+        assert_eq!(knee(&levels, 0.0), Some((1, 100)));
+        assert_eq!(knee(&[], 90.0), None);
+    }
+}
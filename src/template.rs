@@ -0,0 +1,84 @@
+use crate::error::SpeedTestError;
+use crate::report::SpeedTestResult;
+
+enum Part {
+    Literal(String),
+    Field(String),
+}
+
+/*
+A user-supplied summary line such as "{avg_mbps} {peak_mbps} {elapsed}". Each
+{name} is replaced by the result field of the same name, with nested fields
+written parent.child as in the kv format; {{ and }} produce literal braces.
+*/
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    /*
+    Parse the template, rejecting unknown placeholders up front so a typo fails
+    before the test runs rather than producing a silently broken line
+    */
+    pub fn parse(spec: &str) -> Result<Template, SpeedTestError> {
+        let known: Vec<String> = SpeedTestResult::field_names();
+        let mut parts: Vec<Part> = Vec::new();
+        let mut literal: String = String::new();
+        let mut chars = spec.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name: String = String::new();
+                    let mut closed: bool = false;
+                    for c in chars.by_ref() {
+                        if c == '}' {
+                            closed = true;
+                            break;
+                        }
+                        name.push(c);
+                    }
+                    if !closed {
+                        return Err(SpeedTestError::InvalidTemplate("Unclosed '{' in template; write '{{' for a literal brace".to_string()));
+                    }
+                    if !known.contains(&name) {
+                        return Err(SpeedTestError::InvalidTemplate(format!("Unknown placeholder '{{{}}}' in template; available: {}", name, known.join(", "))));
+                    }
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Field(name));
+                }
+                '}' => return Err(SpeedTestError::InvalidTemplate("Unmatched '}' in template; write '}}' for a literal brace".to_string())),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+
+        Ok(Template { parts })
+    }
+
+    /*
+    Fill in the template, writing "-" for metrics that weren't collected
+    */
+    pub fn render(&self, result: &SpeedTestResult) -> String {
+        let fields: Vec<(String, String)> = result.template_fields();
+        self.parts.iter().map(|part| match part {
+            Part::Literal(text) => text.clone(),
+            Part::Field(name) => fields.iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.clone())
+                .unwrap_or_else(|| "-".to_string()),
+        }).collect()
+    }
+}
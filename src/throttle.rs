@@ -0,0 +1,51 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// How much unused allowance the bucket can save up, in seconds of traffic, so a
+// pause doesn't turn into a burst well above the rate
+const BURST_SECONDS: f64 = 0.1;
+
+/*
+A token bucket shared by every download of a run, holding the aggregate down to
+--limit-rate. A chunk is always taken, possibly leaving the bucket in debt, and
+whoever took it then waits until the debt is paid off before reading more, so
+the server is slowed by TCP flow control rather than data being thrown away.
+*/
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> RateLimiter {
+        let rate: f64 = bytes_per_sec as f64;
+        RateLimiter {
+            rate,
+            burst: rate * BURST_SECONDS,
+            bucket: Mutex::new(Bucket { tokens: 0.0, updated: Instant::now() }),
+        }
+    }
+
+    /*
+    Take `bytes` and wait for as long as that overdraws the bucket
+    */
+    pub async fn take(&self, bytes: u64) {
+        let wait: Duration = {
+            let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+            let now: Instant = Instant::now();
+            let refill: f64 = now.duration_since(bucket.updated).as_secs_f64() * self.rate;
+            bucket.tokens = (bucket.tokens + refill).min(self.burst) - bytes as f64;
+            bucket.updated = now;
+            if bucket.tokens < 0.0 { Duration::from_secs_f64(-bucket.tokens / self.rate) } else { Duration::ZERO }
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use crate::config::PayloadPattern;
+use bytes::Bytes;
+use futures_util::stream::{self, Stream};
+use rand::rngs::SmallRng;
+use rand::{RngCore, SeedableRng};
+
+// Size of each generated piece of the upload body
+const PAYLOAD_CHUNK_BYTES: usize = 64 * 1024;
+
+/*
+Generate `size` bytes of upload body one chunk at a time so memory stays bounded
+however large the upload is. Random data comes from the seed, so a run can be
+repeated with exactly the same payload.
+*/
+pub fn payload(size: u64, pattern: PayloadPattern, seed: u64) -> impl Stream<Item = Bytes> {
+    payload_range(0, size, pattern, seed)
+}
+
+/*
+The `length` bytes of the payload starting at `start`. Random data is generated
+a chunk at a time from the seed and the chunk's index, so any range of it comes
+out the same without generating what comes before.
+*/
+pub fn payload_range(start: u64, length: u64, pattern: PayloadPattern, seed: u64) -> impl Stream<Item = Bytes> {
+    let zeros: Bytes = Bytes::from(vec![0u8; PAYLOAD_CHUNK_BYTES]);
+    let chunk_bytes: u64 = PAYLOAD_CHUNK_BYTES as u64;
+
+    stream::unfold(start, move |position: u64| {
+        let zeros: Bytes = zeros.clone();
+        async move {
+            if position >= start + length {
+                return None;
+            }
+
+            // Generating data never waits, so give the runtime a chance to run
+            // timers and other tasks between chunks
+            tokio::task::yield_now().await;
+
+            let index: u64 = position / chunk_bytes;
+            let skip: usize = (position % chunk_bytes) as usize;
+            let len: usize = (chunk_bytes - skip as u64).min(start + length - position) as usize;
+            let chunk: Bytes = match pattern {
+                PayloadPattern::Zeros => zeros.slice(..len),
+                PayloadPattern::Random => {
+                    // A fast generator keeps the payload from being the bottleneck; it doesn't need to be cryptographic
+                    let mut rng: SmallRng = SmallRng::seed_from_u64(seed ^ index.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+                    let mut buf: Vec<u8> = vec![0u8; PAYLOAD_CHUNK_BYTES];
+                    rng.fill_bytes(&mut buf);
+                    Bytes::from(buf).slice(skip..skip + len)
+                }
+            };
+            Some((chunk, position + len as u64))
+        }
+    })
+}
@@ -0,0 +1,31 @@
+/*
+The version recorded in results, e.g. "0.1.0 (abc1234)" when built from a git checkout
+*/
+pub fn tool_version() -> String {
+    match option_env!("GIT_COMMIT") {
+        Some(commit) => format!("{} ({})", env!("CARGO_PKG_VERSION"), commit),
+        None => env!("CARGO_PKG_VERSION").to_string(),
+    }
+}
+
+/*
+The full build description printed by --version
+*/
+pub fn long_version() -> String {
+    let tls_backend: String = if cfg!(any(target_os = "macos", target_os = "ios")) {
+        "Security.framework".to_string()
+    } else if cfg!(windows) {
+        "SChannel".to_string()
+    } else {
+        openssl::version::version().to_string()
+    };
+
+    format!(
+        "{}\ncommit: {}\nhyper: {}\ntls: native-tls {} ({})",
+        env!("CARGO_PKG_VERSION"),
+        option_env!("GIT_COMMIT").unwrap_or("unknown"),
+        option_env!("HYPER_VERSION").unwrap_or("unknown"),
+        option_env!("NATIVE_TLS_VERSION").unwrap_or("unknown"),
+        tls_backend,
+    )
+}
@@ -0,0 +1,36 @@
+use crate::report::SpeedTestResult;
+use crate::engine::{DeliveryClient, delivery_client};
+use crate::error::SpeedTestError;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Method, Request, Uri};
+use std::time::Duration;
+use tracing::warn;
+
+/*
+POST the result as JSON to a webhook. Delivery problems only produce a warning,
+since the measurement itself already succeeded.
+*/
+pub async fn send(url: &str, result: &SpeedTestResult, timeout: Duration) {
+    match tokio::time::timeout(timeout, post(&delivery_client(), url, result)).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => warn!("failed to deliver result to webhook {}: {}", url, e),
+        Err(_) => warn!("webhook {} did not respond within {:.1} s", url, timeout.as_secs_f64()),
+    }
+}
+
+async fn post(client: &DeliveryClient, url: &str, result: &SpeedTestResult) -> Result<(), SpeedTestError> {
+    let uri: Uri = url.parse::<Uri>()
+        .map_err(|e| SpeedTestError::InvalidUrl { url: url.to_string(), reason: e.to_string() })?;
+    let request: Request<Body> = Request::builder()
+        .method(Method::POST)
+        .uri(uri)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(result).map_err(|e| SpeedTestError::Other(format!("Failed to serialize the result: {}", e)))?))
+        .map_err(|e| SpeedTestError::Other(e.to_string()))?;
+
+    let res: hyper::Response<Body> = client.request(request).await?;
+    if !res.status().is_success() {
+        return Err(SpeedTestError::BadStatus { status: res.status(), message: format!("server responded with {}", res.status()) });
+    }
+    Ok(())
+}
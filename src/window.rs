@@ -0,0 +1,173 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/*
+The bytes received over the most recent sample intervals, for the rolling
+average speed. Each chunk counts towards the interval it completes, and once it
+fills, an interval enters the window, pushing out any that no longer fit.
+*/
+pub struct SpeedWindow {
+    interval: Duration,
+    // How many whole intervals the average covers; None keeps them all, for an
+    // average over the whole test
+    capacity: Option<usize>,
+    intervals: VecDeque<u64>,
+    bytes: u64,
+    current: u64,
+    current_start: Instant,
+    // How far into the unfinished last interval the test got when it ended
+    partial: Duration,
+}
+
+impl SpeedWindow {
+    /*
+    A window covering about `window` of the test in intervals of `interval`,
+    or the whole test when `window` is infinite
+    */
+    pub fn new(interval: Duration, window: f64) -> SpeedWindow {
+        let capacity: Option<usize> = window.is_finite()
+            .then(|| ((window / interval.as_secs_f64()).round() as usize).max(1));
+        SpeedWindow {
+            interval,
+            capacity,
+            intervals: VecDeque::new(),
+            bytes: 0,
+            current: 0,
+            current_start: Instant::now(),
+            partial: Duration::ZERO,
+        }
+    }
+
+    /*
+    Start the first interval now, as the first data arrives
+    */
+    pub fn restart(&mut self, now: Instant) {
+        self.current_start = now;
+    }
+
+    /*
+    Forget everything so far and start the first interval again now
+    */
+    pub fn reset(&mut self, now: Instant) {
+        self.intervals.clear();
+        self.bytes = 0;
+        self.current = 0;
+        self.current_start = now;
+    }
+
+    pub fn record(&mut self, bytes: u64, now: Instant) {
+        self.current += bytes;
+        if now.duration_since(self.current_start) < self.interval {
+            return;
+        }
+        self.intervals.push_back(self.current);
+        self.bytes += self.current;
+        while self.capacity.is_some_and(|capacity| self.intervals.len() > capacity) {
+            self.bytes -= self.intervals.pop_front().unwrap_or(0);
+        }
+        self.current = 0;
+        self.current_start = now;
+    }
+
+    /*
+    Note where the test ended in the unfinished interval, so its bytes count
+    against the time they actually took
+    */
+    pub fn finish(&mut self, now: Instant) {
+        self.partial = now.duration_since(self.current_start);
+    }
+
+    /*
+    The average over the whole intervals in the window, if there are any yet
+    */
+    pub fn rolling_bps(&self) -> Option<u64> {
+        if self.intervals.is_empty() {
+            return None;
+        }
+        Some((self.bytes as f64 / (self.intervals.len() as f64 * self.interval.as_secs_f64())) as u64)
+    }
+
+    /*
+    The average at the end of the test, including the partial last interval
+    */
+    pub fn final_bps(&self) -> u64 {
+        let mut bytes: u64 = self.bytes;
+        let mut seconds: f64 = self.intervals.len() as f64 * self.interval.as_secs_f64();
+        if self.current > 0 {
+            bytes += self.current;
+            seconds += self.partial.as_secs_f64();
+        }
+        if seconds > 0.0 { (bytes as f64 / seconds) as u64 } else { 0 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    /*
+    A window started at `start` that has received `bytes` at the end of each of
+    the given seconds
+    */
+    fn filled(window: f64, start: Instant, bytes: &[u64]) -> SpeedWindow {
+        let mut speed: SpeedWindow = SpeedWindow::new(SECOND, window);
+        speed.restart(start);
+        for (i, bytes) in bytes.iter().enumerate() {
+            speed.record(*bytes, start + SECOND * (i as u32 + 1));
+        }
+        speed
+    }
+
+    #[test]
+    fn has_no_rolling_average_before_an_interval_completes() {
+        let start: Instant = Instant::now();
+        let mut speed: SpeedWindow = SpeedWindow::new(SECOND, 10.0);
+        speed.restart(start);
+        speed.record(1000, start + Duration::from_millis(500));
+        assert_eq!(speed.rolling_bps(), None);
+    }
+
+    #[test]
+    fn averages_only_the_intervals_in_the_window() {
+        let speed: SpeedWindow = filled(2.0, Instant::now(), &[100, 200, 300, 400]);
+        assert_eq!(speed.rolling_bps(), Some(350));
+    }
+
+    #[test]
+    fn one_second_window_is_the_last_interval() {
+        let speed: SpeedWindow = filled(1.0, Instant::now(), &[100, 200, 300]);
+        assert_eq!(speed.rolling_bps(), Some(300));
+    }
+
+    #[test]
+    fn infinite_window_covers_the_whole_test() {
+        let speed: SpeedWindow = filled(f64::INFINITY, Instant::now(), &[100, 200, 300, 400]);
+        assert_eq!(speed.rolling_bps(), Some(250));
+    }
+
+    #[test]
+    fn window_shorter_than_an_interval_keeps_one() {
+        let speed: SpeedWindow = filled(0.1, Instant::now(), &[100, 200]);
+        assert_eq!(speed.rolling_bps(), Some(200));
+    }
+
+    #[test]
+    fn final_average_counts_the_partial_interval() {
+        let start: Instant = Instant::now();
+        let mut speed: SpeedWindow = filled(10.0, start, &[1000, 1000]);
+        speed.record(500, start + Duration::from_millis(2500));
+        speed.finish(start + Duration::from_millis(2500));
+        assert_eq!(speed.final_bps(), 1000);
+    }
+
+    #[test]
+    fn final_average_without_data_is_zero() {
+        let start: Instant = Instant::now();
+        let mut speed: SpeedWindow = SpeedWindow::new(SECOND, 10.0);
+        speed.restart(start);
+        speed.finish(start + SECOND);
+        assert_eq!(speed.final_bps(), 0);
+    }
+}
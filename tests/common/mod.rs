@@ -0,0 +1,107 @@
+// Each test crate uses only some of the helpers
+#![allow(dead_code)]
+
+use hyper::header::{ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, RANGE};
+use hyper::http::response::Builder;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::process::Stdio;
+use std::str::FromStr;
+use tokio::io::{AsyncBufReadExt, BufReader, Lines};
+use tokio::process::{Child, ChildStdout, Command};
+
+pub const BIN: &str = env!("CARGO_BIN_EXE_httpbandwidthspeedtester");
+
+/*
+Start the test server on a free port and return it with the base URL it reports,
+and its output, which is kept open so the server can go on writing to it
+*/
+pub async fn serve() -> (Child, String, Lines<BufReader<ChildStdout>>) {
+    let mut server: Child = Command::new(BIN)
+        .args(["serve", "--listen", "127.0.0.1:0"])
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .expect("failed to start the server");
+    let stdout = server.stdout.take().expect("the server's stdout is piped");
+    let mut lines: Lines<BufReader<ChildStdout>> = BufReader::new(stdout).lines();
+    let line: String = lines.next_line().await
+        .expect("failed to read from the server")
+        .expect("the server exited before listening");
+    let url: String = line.strip_prefix("Serving on ")
+        .and_then(|rest| rest.split_once("/:"))
+        .map(|(url, _)| url.to_string())
+        .unwrap_or_else(|| panic!("unexpected server output: {}", line));
+    (server, url, lines)
+}
+
+/*
+The bytes of a file a request asks for: those of its Range, or the whole file
+*/
+pub struct Part {
+    pub start: u64,
+    pub end: u64,
+    pub ranged: bool,
+}
+
+impl Part {
+    pub fn of(request: &Request<Body>, length: u64) -> Part {
+        let range: Option<(u64, u64)> = request.headers().get(RANGE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("bytes="))
+            .and_then(|v| v.split_once('-'))
+            .and_then(|(start, end)| {
+                let end: u64 = if end.is_empty() { length - 1 } else { end.parse().ok()? };
+                Some((start.parse().ok()?, end.min(length - 1)))
+            });
+        let (start, end): (u64, u64) = range.unwrap_or((0, length - 1));
+        Part { start, end, ranged: range.is_some() }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    /*
+    The head of the answer: partial content for a range, the whole file advertising
+    range support otherwise
+    */
+    pub fn response(&self, length: u64) -> Builder {
+        let response: Builder = Response::builder()
+            .header(ACCEPT_RANGES, "bytes")
+            .header(CONTENT_LENGTH, self.len());
+        if !self.ranged {
+            return response;
+        }
+        response
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(CONTENT_RANGE, format!("bytes {}-{}/{}", self.start, self.end, length))
+    }
+}
+
+/*
+Serve a file of `length` zero bytes that honors range requests
+*/
+pub async fn serve_file(length: u64) -> SocketAddr {
+    let make_service = make_service_fn(move |_| async move {
+        Ok::<_, Infallible>(service_fn(move |request: Request<Body>| async move {
+            let part: Part = Part::of(&request, length);
+            Ok::<_, Infallible>(part.response(length).body(Body::from(vec![0u8; part.len() as usize])).unwrap())
+        }))
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+    let addr: SocketAddr = server.local_addr();
+    tokio::spawn(server);
+    addr
+}
+
+/*
+The value of a key in a `--format kv` summary
+*/
+pub fn field<T: FromStr>(stdout: &str, key: &str) -> Option<T> {
+    stdout.split_whitespace()
+        .find_map(|pair| pair.strip_prefix(key)?.strip_prefix('='))
+        .and_then(|value| value.parse().ok())
+}
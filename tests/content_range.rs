@@ -0,0 +1,74 @@
+mod common;
+
+use common::BIN;
+use hyper::header::{ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, RANGE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::process::Output;
+
+const FILE_BYTES: u64 = 256 * 1024;
+
+/*
+Serve a file that answers every range request with a Content-Range shifted
+`shift` bytes past what was asked for
+*/
+async fn serve(shift: u64) -> SocketAddr {
+    let make_service = make_service_fn(move |_| async move {
+        Ok::<_, Infallible>(service_fn(move |request: Request<Body>| async move {
+            let range: Option<u64> = request.headers().get(RANGE)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.strip_prefix("bytes="))
+                .and_then(|v| v.split('-').next())
+                .and_then(|v| v.parse().ok());
+            let response = match range {
+                Some(start) => {
+                    let first: u64 = start + shift;
+                    Response::builder()
+                        .status(StatusCode::PARTIAL_CONTENT)
+                        .header(CONTENT_RANGE, format!("bytes {}-{}/{}", first, FILE_BYTES - 1, FILE_BYTES))
+                        .header(CONTENT_LENGTH, FILE_BYTES - first)
+                        .body(Body::from(vec![0u8; (FILE_BYTES - first) as usize]))
+                }
+                None => Response::builder()
+                    .header(ACCEPT_RANGES, "bytes")
+                    .header(CONTENT_LENGTH, FILE_BYTES)
+                    .body(Body::from(vec![0u8; FILE_BYTES as usize])),
+            };
+            Ok::<_, Infallible>(response.unwrap())
+        }))
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+    let addr: SocketAddr = server.local_addr();
+    tokio::spawn(server);
+    addr
+}
+
+async fn run_against(addr: SocketAddr) -> Output {
+    let url: String = format!("http://{}/file.bin", addr);
+    tokio::process::Command::new(BIN)
+        .args([url.as_str(), "--format", "kv"])
+        .output()
+        .await
+        .expect("failed to run the speed tester")
+}
+
+#[tokio::test]
+async fn rejects_a_shifted_content_range() {
+    let output: Output = run_against(serve(10).await).await;
+    let stderr: String = String::from_utf8_lossy(&output.stderr).to_string();
+
+    assert!(!output.status.success(), "a shifted Content-Range should fail the run");
+    assert!(stderr.contains("was answered with Content-Range 'bytes 10-"), "unexpected error: {}", stderr);
+    assert!(stderr.contains("refusing data from the wrong offset"), "unexpected error: {}", stderr);
+}
+
+#[tokio::test]
+async fn accepts_a_matching_content_range() {
+    let output: Output = run_against(serve(0).await).await;
+    let stdout: String = String::from_utf8_lossy(&output.stdout).to_string();
+
+    assert!(output.status.success(), "run failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains(&format!("total_bytes={}", FILE_BYTES)), "unexpected summary: {}", stdout);
+}
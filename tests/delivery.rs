@@ -0,0 +1,96 @@
+mod common;
+
+use common::{Part, BIN};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::process::Output;
+use std::sync::{Arc, Mutex};
+
+const FILE_BYTES: u64 = 1024 * 1024;
+
+/*
+A request delivered to the collector: its method, path and body
+*/
+type Delivery = (Method, String, String);
+
+/*
+Serve a file over HTTP/2 with prior knowledge, as --http-version 2 expects
+*/
+async fn serve_http2() -> SocketAddr {
+    let make_service = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|request: Request<Body>| async move {
+            let part: Part = Part::of(&request, FILE_BYTES);
+            Ok::<_, Infallible>(part.response(FILE_BYTES).body(Body::from(vec![0u8; part.len() as usize])).unwrap())
+        }))
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).http2_only(true).serve(make_service);
+    let addr: SocketAddr = server.local_addr();
+    tokio::spawn(server);
+    addr
+}
+
+/*
+Accept results over HTTP/1.1 only, recording each request
+*/
+async fn collector(deliveries: Arc<Mutex<Vec<Delivery>>>) -> SocketAddr {
+    let make_service = make_service_fn(move |_| {
+        let deliveries: Arc<Mutex<Vec<Delivery>>> = deliveries.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                let deliveries: Arc<Mutex<Vec<Delivery>>> = deliveries.clone();
+                async move {
+                    let (method, path): (Method, String) = (request.method().clone(), request.uri().path().to_string());
+                    let body: Vec<u8> = hyper::body::to_bytes(request.into_body()).await.unwrap_or_default().to_vec();
+                    deliveries.lock().unwrap().push((method, path, String::from_utf8_lossy(&body).to_string()));
+                    Ok::<_, Infallible>(Response::new(Body::empty()))
+                }
+            }))
+        }
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).http1_only(true).serve(make_service);
+    let addr: SocketAddr = server.local_addr();
+    tokio::spawn(server);
+    addr
+}
+
+async fn run_over_http2(args: &[&str]) -> Output {
+    let url: String = format!("http://{}/file.bin", serve_http2().await);
+    tokio::process::Command::new(BIN)
+        .args([url.as_str(), "--http-version", "2", "-c", "2", "--format", "kv"])
+        .args(args)
+        .output()
+        .await
+        .expect("failed to run the speed tester")
+}
+
+#[tokio::test]
+async fn posts_the_webhook_without_the_test_transport() {
+    let deliveries: Arc<Mutex<Vec<Delivery>>> = Arc::new(Mutex::new(Vec::new()));
+    let webhook: String = format!("http://{}/hook", collector(deliveries.clone()).await);
+    let output: Output = run_over_http2(&["--webhook", &webhook]).await;
+
+    assert!(output.status.success(), "run failed: {}", String::from_utf8_lossy(&output.stderr));
+    // Sent over HTTP/1.1, which a client forced to HTTP/2 couldn't have done
+    let deliveries: Vec<Delivery> = deliveries.lock().unwrap().clone();
+    assert_eq!(deliveries.len(), 1, "unexpected deliveries: {:?}", deliveries);
+    let (method, path, body) = &deliveries[0];
+    assert_eq!((method, path.as_str()), (&Method::POST, "/hook"));
+    let result: serde_json::Value = serde_json::from_str(body).expect("the webhook body is JSON");
+    assert_eq!(result["total_bytes"], FILE_BYTES);
+}
+
+#[tokio::test]
+async fn pushes_metrics_without_the_test_transport() {
+    let deliveries: Arc<Mutex<Vec<Delivery>>> = Arc::new(Mutex::new(Vec::new()));
+    let pushgateway: String = format!("http://{}", collector(deliveries.clone()).await);
+    let output: Output = run_over_http2(&["--pushgateway", &pushgateway, "--job", "nightly"]).await;
+
+    assert!(output.status.success(), "run failed: {}", String::from_utf8_lossy(&output.stderr));
+    let deliveries: Vec<Delivery> = deliveries.lock().unwrap().clone();
+    assert_eq!(deliveries.len(), 1, "unexpected deliveries: {:?}", deliveries);
+    let (method, path, body) = &deliveries[0];
+    assert_eq!((method, path.as_str()), (&Method::PUT, "/metrics/job/nightly"));
+    assert!(body.contains(&format!("httpbandwidthspeedtester_total_bytes {}\n", FILE_BYTES)), "unexpected metrics: {}", body);
+}
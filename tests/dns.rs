@@ -0,0 +1,74 @@
+mod common;
+
+use common::{serve_file, BIN};
+use std::net::SocketAddr;
+use std::process::Output;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+
+const FILE_BYTES: u64 = 1024 * 1024;
+
+/*
+A DNS server that answers every A query with 127.0.0.1 and every other query
+with no records, counting the queries it gets
+*/
+async fn dns_server(queries: Arc<AtomicUsize>) -> SocketAddr {
+    let socket: UdpSocket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr: SocketAddr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut query: [u8; 512] = [0; 512];
+        while let Ok((len, client)) = socket.recv_from(&mut query).await {
+            queries.fetch_add(1, Ordering::SeqCst);
+            // The question ends with its type and class after the name's final zero byte
+            let name_end: usize = 12 + query[12..len].iter().position(|b| *b == 0).unwrap() + 1;
+            let a: bool = query[name_end..name_end + 2] == [0, 1];
+            let mut answer: Vec<u8> = query[..2].to_vec();
+            answer.extend_from_slice(&[0x81, 0x80, 0, 1, 0, a as u8, 0, 0, 0, 0]);
+            answer.extend_from_slice(&query[12..name_end + 4]);
+            if a {
+                // A pointer back to the question's name, then type A, class IN,
+                // a TTL and the address
+                answer.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 127, 0, 0, 1]);
+            }
+            socket.send_to(&answer, client).await.unwrap();
+        }
+    });
+    addr
+}
+
+async fn run(url: &str, args: &[&str]) -> Output {
+    tokio::process::Command::new(BIN)
+        .args([url, "-c", "2", "--format", "kv"])
+        .args(args)
+        .output()
+        .await
+        .expect("failed to run the speed tester")
+}
+
+#[tokio::test]
+async fn resolves_through_the_dns_server() {
+    let origin: SocketAddr = serve_file(FILE_BYTES).await;
+    let queries: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+    let server: String = dns_server(queries.clone()).await.to_string();
+    let output: Output = run(&format!("http://files.test:{}/file.bin", origin.port()), &["--dns-server", &server]).await;
+    let stdout: String = String::from_utf8_lossy(&output.stdout).to_string();
+
+    assert!(output.status.success(), "run failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains(&format!("total_bytes={}", FILE_BYTES)), "unexpected summary: {}", stdout);
+    assert!(stdout.contains(&format!("dns_server={}", server)), "the DNS server isn't reported: {}", stdout);
+    // One A and one AAAA query
+    assert_eq!(queries.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn connects_to_the_resolve_address() {
+    let origin: SocketAddr = serve_file(FILE_BYTES).await;
+    let resolve: String = format!("files.invalid:{}:127.0.0.1", origin.port());
+    let output: Output = run(&format!("http://files.invalid:{}/file.bin", origin.port()), &["--resolve", &resolve]).await;
+    let stdout: String = String::from_utf8_lossy(&output.stdout).to_string();
+
+    assert!(output.status.success(), "run failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains(&format!("total_bytes={}", FILE_BYTES)), "unexpected summary: {}", stdout);
+    assert!(!stdout.contains("dns_ms="), "a lookup was timed: {}", stdout);
+}
@@ -0,0 +1,82 @@
+mod common;
+
+use common::{Part, BIN};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Server};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::process::Output;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+const FILE_BYTES: u64 = 4 * 1024 * 1024;
+
+/*
+Serve a file over HTTP/2 with prior knowledge, counting the connections made
+to it
+*/
+async fn serve(connections: Arc<AtomicUsize>) -> SocketAddr {
+    let make_service = make_service_fn(move |_| {
+        connections.fetch_add(1, Ordering::SeqCst);
+        async {
+            Ok::<_, Infallible>(service_fn(|request: Request<Body>| async move {
+                let part: Part = Part::of(&request, FILE_BYTES);
+                Ok::<_, Infallible>(part.response(FILE_BYTES).body(Body::from(vec![0u8; part.len() as usize])).unwrap())
+            }))
+        }
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).http2_only(true).serve(make_service);
+    let addr: SocketAddr = server.local_addr();
+    tokio::spawn(server);
+    addr
+}
+
+async fn run_against(addr: SocketAddr, args: &[&str]) -> Output {
+    let url: String = format!("http://{}/file.bin", addr);
+    tokio::process::Command::new(BIN)
+        .args([url.as_str(), "--http-version", "2", "-c", "4", "--format", "kv"])
+        .args(args)
+        .output()
+        .await
+        .expect("failed to run the speed tester")
+}
+
+#[tokio::test]
+async fn opens_a_connection_per_stream() {
+    let connections: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+    let output: Output = run_against(serve(connections.clone()).await, &[]).await;
+    let stdout: String = String::from_utf8_lossy(&output.stdout).to_string();
+
+    assert!(output.status.success(), "run failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains("protocol=HTTP/2"), "unexpected summary: {}", stdout);
+    // The probe's connection plus one for each of the four ranges
+    assert_eq!(connections.load(Ordering::SeqCst), 5);
+}
+
+#[tokio::test]
+async fn multiplexes_over_one_connection() {
+    let connections: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+    let output: Output = run_against(serve(connections.clone()).await, &["--h2-single-connection"]).await;
+    let stdout: String = String::from_utf8_lossy(&output.stdout).to_string();
+
+    assert!(output.status.success(), "run failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains("protocol=HTTP/2") && stdout.contains("workers=4"), "unexpected summary: {}", stdout);
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn refuses_http3_without_a_quic_transport() {
+    let connections: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+    let addr: SocketAddr = serve(connections.clone()).await;
+    let output: Output = tokio::process::Command::new(BIN)
+        .args([format!("http://{}/file.bin", addr).as_str(), "--http-version", "3", "-c", "4"])
+        .output()
+        .await
+        .expect("failed to run the speed tester");
+    let stderr: String = String::from_utf8_lossy(&output.stderr).to_string();
+
+    assert_eq!(output.status.code(), Some(1), "unexpected exit: {}", stderr);
+    assert!(stderr.contains("--http-version 3 (HTTP/3 over QUIC) isn't built into this binary"), "unexpected error: {}", stderr);
+    // Nothing was sent over TCP instead
+    assert_eq!(connections.load(Ordering::SeqCst), 0);
+}
@@ -0,0 +1,28 @@
+mod common;
+
+use common::serve_file;
+use httpbandwidthspeedtester::{SpeedTest, TestReport};
+
+const FILE_BYTES: u64 = 1024 * 1024;
+
+#[tokio::test]
+async fn runs_a_test_through_the_builder() {
+    let url: String = format!("http://{}/file.bin", serve_file(FILE_BYTES).await);
+    let report: TestReport = SpeedTest::builder()
+        .url(url)
+        .connections(4)
+        .run()
+        .await
+        .expect("the speed test failed");
+
+    assert_eq!(report.workers, 4);
+    assert_eq!(report.total_bytes, FILE_BYTES);
+    assert!(report.range_requests);
+    assert!(report.avg_bps > 0);
+}
+
+#[tokio::test]
+async fn needs_a_url() {
+    let error = SpeedTest::builder().connections(2).build().err().expect("a test without a URL was built");
+    assert!(error.to_string().contains("No URL"), "unexpected error: {}", error);
+}
@@ -0,0 +1,58 @@
+mod common;
+
+use bytes::Bytes;
+use common::{field, Part, BIN};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Server};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::process::Output;
+use std::time::{Duration, Instant};
+
+const FILE_BYTES: u64 = 1024 * 1024 * 1024;
+const CHUNK_BYTES: u64 = 64 * 1024;
+
+/*
+Serve a file far too large to finish in the test, trickling each response out
+one chunk every 10 ms
+*/
+async fn serve() -> SocketAddr {
+    let make_service = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|request: Request<Body>| async move {
+            let part: Part = Part::of(&request, FILE_BYTES);
+            let chunks = futures_util::stream::unfold(part.len(), |left: u64| async move {
+                if left == 0 {
+                    return None;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                let size: u64 = left.min(CHUNK_BYTES);
+                Some((Ok::<_, Infallible>(Bytes::from(vec![0u8; size as usize])), left - size))
+            });
+            Ok::<_, Infallible>(part.response(FILE_BYTES).body(Body::wrap_stream(chunks)).unwrap())
+        }))
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+    let addr: SocketAddr = server.local_addr();
+    tokio::spawn(server);
+    addr
+}
+
+#[tokio::test]
+async fn reports_percentiles_after_a_bounded_run() {
+    let url: String = format!("http://{}/file.bin", serve().await);
+    let started: Instant = Instant::now();
+    let output: Output = tokio::process::Command::new(BIN)
+        .args([url.as_str(), "--max-runtime", "2", "--format", "kv"])
+        .output()
+        .await
+        .expect("failed to run the speed tester");
+    let stdout: String = String::from_utf8_lossy(&output.stdout).to_string();
+
+    assert!(output.status.success(), "run failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(started.elapsed() < Duration::from_secs(10), "the run wasn't stopped at --max-runtime");
+    assert!(stdout.contains("stopped_early=true"), "unexpected summary: {}", stdout);
+    for key in ["p50_bps", "peak_bps"] {
+        let value: f64 = field(&stdout, key).unwrap_or_else(|| panic!("no {} in summary: {}", key, stdout));
+        assert!(value > 0.0, "{} should be measured, got {}: {}", key, value, stdout);
+    }
+}
@@ -0,0 +1,57 @@
+mod common;
+
+use common::{serve_file, BIN};
+use hyper::body::HttpBody;
+use hyper::{Body, Client, Response, StatusCode};
+use std::net::SocketAddr;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader, Lines};
+use tokio::process::{Child, ChildStderr, Command};
+
+const FILE_BYTES: u64 = 1024 * 1024;
+
+async fn scrape(url: &str) -> String {
+    let mut response: Response<Body> = Client::new().get(url.parse().unwrap()).await.expect("failed to scrape the metrics");
+    assert_eq!(response.status(), StatusCode::OK);
+    let mut body: Vec<u8> = Vec::new();
+    while let Some(chunk) = response.body_mut().data().await {
+        body.extend_from_slice(&chunk.unwrap());
+    }
+    String::from_utf8_lossy(&body).to_string()
+}
+
+#[tokio::test]
+async fn serves_live_metrics_between_measurements() {
+    let origin: SocketAddr = serve_file(FILE_BYTES).await;
+    let mut monitor: Child = Command::new(BIN)
+        .args([format!("http://{}/file.bin", origin).as_str(), "-c", "2", "--monitor", "60", "--metrics-listen", "127.0.0.1:0", "--sample-interval", "100"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .expect("failed to run the speed tester");
+    let stderr = monitor.stderr.take().expect("stderr is piped");
+    let mut lines: Lines<BufReader<ChildStderr>> = BufReader::new(stderr).lines();
+    let line: String = lines.next_line().await
+        .expect("failed to read from the speed tester")
+        .expect("the speed tester exited before serving metrics");
+    let url: String = line.strip_prefix("Serving metrics on ")
+        .unwrap_or_else(|| panic!("unexpected output: {}", line))
+        .to_string();
+
+    // The first measurement is over once it is counted, and the monitor then
+    // waits for the next one with the endpoint still up
+    let mut metrics: String = String::new();
+    for _ in 0..100 {
+        metrics = scrape(&url).await;
+        if metrics.contains("httpbandwidthspeedtester_measurements_total 1\n") {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(metrics.contains("httpbandwidthspeedtester_measurements_total 1\n"), "the measurement was never counted: {}", metrics);
+    assert!(metrics.contains(&format!("httpbandwidthspeedtester_downloaded_bytes_total {}\n", FILE_BYTES)), "unexpected byte count: {}", metrics);
+    assert!(metrics.contains("# TYPE httpbandwidthspeedtester_current_bps gauge\nhttpbandwidthspeedtester_current_bps 0\n"), "unexpected speed: {}", metrics);
+    assert!(metrics.contains("httpbandwidthspeedtester_failed_measurements_total 0\n"), "unexpected failures: {}", metrics);
+}
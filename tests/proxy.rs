@@ -0,0 +1,137 @@
+mod common;
+
+use common::{serve_file, BIN};
+use std::net::SocketAddr;
+use std::process::Output;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+const FILE_BYTES: u64 = 1024 * 1024;
+
+/*
+A forwarding HTTP proxy that sends everything on each connection to `origin`,
+noting the request lines and Proxy-Authorization headers it sees first
+*/
+async fn http_proxy(origin: SocketAddr, seen: Arc<Mutex<Vec<String>>>) -> SocketAddr {
+    let listener: TcpListener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr: SocketAddr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut client, _)) = listener.accept().await {
+            let seen: Arc<Mutex<Vec<String>>> = seen.clone();
+            tokio::spawn(async move {
+                let mut head: Vec<u8> = Vec::new();
+                while !head.ends_with(b"\r\n\r\n") {
+                    head.push(client.read_u8().await?);
+                }
+                let text: String = String::from_utf8_lossy(&head).to_string();
+                {
+                    let mut seen = seen.lock().unwrap();
+                    seen.push(text.lines().next().unwrap_or_default().to_string());
+                    seen.extend(text.lines().filter(|line| line.to_ascii_lowercase().starts_with("proxy-authorization:")).map(str::to_string));
+                }
+                let mut upstream: TcpStream = TcpStream::connect(origin).await?;
+                upstream.write_all(&head).await?;
+                tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+                Ok::<_, std::io::Error>(())
+            });
+        }
+    });
+    addr
+}
+
+/*
+A SOCKS5 proxy that only accepts user:secret and connects wherever it is asked
+*/
+async fn socks5_proxy() -> SocketAddr {
+    let listener: TcpListener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr: SocketAddr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut client, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut greeting: [u8; 2] = [0; 2];
+                client.read_exact(&mut greeting).await?;
+                let mut methods: Vec<u8> = vec![0; greeting[1] as usize];
+                client.read_exact(&mut methods).await?;
+                if !methods.contains(&0x02) {
+                    return client.write_all(&[0x05, 0xff]).await;
+                }
+                client.write_all(&[0x05, 0x02]).await?;
+                client.read_u8().await?;
+                let (name, password): (String, String) = (read_field(&mut client).await?, read_field(&mut client).await?);
+                if (name.as_str(), password.as_str()) != ("user", "secret") {
+                    return client.write_all(&[0x01, 0x01]).await;
+                }
+                client.write_all(&[0x01, 0x00]).await?;
+
+                let mut request: [u8; 4] = [0; 4];
+                client.read_exact(&mut request).await?;
+                let host: String = match request[3] {
+                    0x01 => {
+                        let mut ip: [u8; 4] = [0; 4];
+                        client.read_exact(&mut ip).await?;
+                        std::net::Ipv4Addr::from(ip).to_string()
+                    }
+                    _ => read_field(&mut client).await?,
+                };
+                let port: u16 = client.read_u16().await?;
+                let mut upstream: TcpStream = TcpStream::connect((host.as_str(), port)).await?;
+                client.write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await?;
+                tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+                Ok(())
+            });
+        }
+    });
+    addr
+}
+
+/*
+A SOCKS5 field of a length byte and that many bytes
+*/
+async fn read_field(client: &mut TcpStream) -> std::io::Result<String> {
+    let mut value: Vec<u8> = vec![0; client.read_u8().await? as usize];
+    client.read_exact(&mut value).await?;
+    Ok(String::from_utf8_lossy(&value).to_string())
+}
+
+async fn run(url: &str, args: &[&str]) -> Output {
+    tokio::process::Command::new(BIN)
+        .args([url, "-c", "2", "--format", "kv"])
+        .args(args)
+        .output()
+        .await
+        .expect("failed to run the speed tester")
+}
+
+#[tokio::test]
+async fn forwards_through_an_http_proxy() {
+    let origin: SocketAddr = serve_file(FILE_BYTES).await;
+    let seen: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
+    let proxy: String = format!("http://user:secret@{}", http_proxy(origin, seen.clone()).await);
+    // The proxy sends everything to the origin whatever the host, so the name
+    // needn't resolve
+    let output: Output = run("http://files.invalid/file.bin", &["--proxy", &proxy]).await;
+    let stdout: String = String::from_utf8_lossy(&output.stdout).to_string();
+
+    assert!(output.status.success(), "run failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains(&format!("total_bytes={}", FILE_BYTES)), "unexpected summary: {}", stdout);
+    let seen: Vec<String> = seen.lock().unwrap().clone();
+    assert!(seen.iter().any(|line| line.starts_with("GET http://files.invalid/file.bin ")), "no absolute-form request: {:?}", seen);
+    assert!(seen.iter().any(|line| line.ends_with("Basic dXNlcjpzZWNyZXQ=")), "no proxy credentials: {:?}", seen);
+}
+
+#[tokio::test]
+async fn tunnels_through_a_socks5_proxy() {
+    let origin: SocketAddr = serve_file(FILE_BYTES).await;
+    let url: String = format!("http://{}/file.bin", origin);
+    let proxy: String = format!("socks5://{}", socks5_proxy().await);
+    let output: Output = run(&url, &["--proxy", &proxy, "--proxy-user", "user:secret"]).await;
+    let stdout: String = String::from_utf8_lossy(&output.stdout).to_string();
+
+    assert!(output.status.success(), "run failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains(&format!("total_bytes={}", FILE_BYTES)), "unexpected summary: {}", stdout);
+
+    let refused: Output = run(&url, &["--proxy", &proxy, "--proxy-user", "user:wrong"]).await;
+    assert!(!refused.status.success(), "the proxy's refusal went unnoticed");
+    assert!(String::from_utf8_lossy(&refused.stderr).contains("rejected"), "unexpected error: {}", String::from_utf8_lossy(&refused.stderr));
+}
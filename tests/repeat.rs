@@ -0,0 +1,24 @@
+mod common;
+
+use common::{serve_file, BIN};
+use std::process::Output;
+
+const FILE_BYTES: u64 = 1024 * 1024;
+
+#[tokio::test]
+async fn repeats_the_given_number_of_times() {
+    let url: String = format!("http://{}/file.bin", serve_file(FILE_BYTES).await);
+    let output: Output = tokio::process::Command::new(BIN)
+        .args([url.as_str(), "-c", "2", "--format", "kv", "--repeat", "3", "--interval", "100ms"])
+        .output()
+        .await
+        .expect("failed to run the speed tester");
+    let stdout: String = String::from_utf8_lossy(&output.stdout).to_string();
+
+    assert!(output.status.success(), "run failed: {}", String::from_utf8_lossy(&output.stderr));
+    for measurement in 1..=3 {
+        assert!(stdout.contains(&format!("] Measurement {} of 3\n", measurement)), "measurement {} is missing: {}", measurement, stdout);
+    }
+    assert!(!stdout.contains("Measurement 4"), "ran too often: {}", stdout);
+    assert_eq!(stdout.matches(&format!("total_bytes={}", FILE_BYTES)).count(), 3, "unexpected summaries: {}", stdout);
+}
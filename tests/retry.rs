@@ -0,0 +1,78 @@
+mod common;
+
+use bytes::Bytes;
+use common::{Part, BIN};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::process::Output;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+const FILE_BYTES: u64 = 4 * 1024 * 1024;
+
+/*
+Serve a file that honors range requests, but answer the first request for its
+second half with a 503 and cut the first request for its first half, past the
+probe of byte 0, off part way through the body
+*/
+async fn serve() -> SocketAddr {
+    let failed_status: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+    let failed_body: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+    let make_service = make_service_fn(move |_| {
+        let (failed_status, failed_body) = (failed_status.clone(), failed_body.clone());
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                let (failed_status, failed_body) = (failed_status.clone(), failed_body.clone());
+                async move {
+                    let part: Part = Part::of(&request, FILE_BYTES);
+                    if part.start == FILE_BYTES / 2 && !failed_status.swap(true, Ordering::SeqCst) {
+                        return Ok::<_, Infallible>(Response::builder().status(StatusCode::SERVICE_UNAVAILABLE).body(Body::empty()).unwrap());
+                    }
+                    let data: Vec<u8> = (part.start..=part.end).map(|i| (i % 251) as u8).collect();
+                    let body: Body = if part.ranged && part.start == 0 && part.end > 0 && !failed_body.swap(true, Ordering::SeqCst) {
+                        let half: Bytes = Bytes::from(data[..data.len() / 2].to_vec());
+                        Body::wrap_stream(futures_util::stream::iter([
+                            Ok(half),
+                            Err(std::io::Error::new(std::io::ErrorKind::ConnectionReset, "cut off")),
+                        ]))
+                    } else {
+                        Body::from(data)
+                    };
+                    Ok::<_, Infallible>(part.response(FILE_BYTES).body(body).unwrap())
+                }
+            }))
+        }
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+    let addr: SocketAddr = server.local_addr();
+    tokio::spawn(server);
+    addr
+}
+
+async fn run_against(addr: SocketAddr, args: &[&str]) -> Output {
+    let url: String = format!("http://{}/file.bin", addr);
+    tokio::process::Command::new(BIN)
+        .args([url.as_str(), "-c", "2", "--format", "kv"])
+        .args(args)
+        .output()
+        .await
+        .expect("failed to run the speed tester")
+}
+
+#[tokio::test]
+async fn retries_and_resumes_failed_ranges() {
+    let output: Output = run_against(serve().await, &[]).await;
+    let stdout: String = String::from_utf8_lossy(&output.stdout).to_string();
+
+    assert!(output.status.success(), "run failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains(&format!("total_bytes={}", FILE_BYTES)), "unexpected summary: {}", stdout);
+    assert!(stdout.contains("retries=2"), "unexpected summary: {}", stdout);
+}
+
+#[tokio::test]
+async fn fails_without_retries() {
+    let output: Output = run_against(serve().await, &["--max-retries", "0"]).await;
+    assert!(!output.status.success(), "the run survived a failed range without retries");
+}
@@ -0,0 +1,543 @@
+mod common;
+
+use common::{field, serve, Part, BIN};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Server};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::process::{Output, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader, Lines};
+use tokio::process::{Child, ChildStdout, Command};
+
+#[tokio::test]
+async fn downloads_and_uploads_against_the_server() {
+    let (_server, url, _output) = serve().await;
+
+    let download: Output = Command::new(BIN)
+        .args([format!("{}/4M.bin", url).as_str(), "-c", "4", "--format", "kv"])
+        .output()
+        .await
+        .expect("failed to run the download");
+    let stdout: String = String::from_utf8_lossy(&download.stdout).to_string();
+    assert!(download.status.success(), "download failed: {}", String::from_utf8_lossy(&download.stderr));
+    assert!(stdout.contains("workers=4") && stdout.contains("total_bytes=4194304"), "unexpected summary: {}", stdout);
+    assert!(stdout.contains("range_requests=true"), "ranges weren't used: {}", stdout);
+
+    let upload: Output = Command::new(BIN)
+        .args(["upload", format!("{}/upload", url).as_str(), "--upload-size", "1000000"])
+        .output()
+        .await
+        .expect("failed to run the upload");
+    let stdout: String = String::from_utf8_lossy(&upload.stdout).to_string();
+    assert!(upload.status.success(), "upload failed: {}", String::from_utf8_lossy(&upload.stderr));
+    assert!(stdout.contains("Uploaded 1000000 bytes"), "unexpected summary: {}", stdout);
+}
+
+#[tokio::test]
+async fn measures_latency_under_load() {
+    let (_server, url, _output) = serve().await;
+
+    let output: Output = Command::new(BIN)
+        .args([format!("{}/32M.bin", url).as_str(), "-c", "2", "--format", "kv", "--loaded-latency", "--probe-interval", "10ms"])
+        .output()
+        .await
+        .expect("failed to run the download");
+    let stdout: String = String::from_utf8_lossy(&output.stdout).to_string();
+    assert!(output.status.success(), "download failed: {}", String::from_utf8_lossy(&output.stderr));
+    for key in ["bufferbloat.idle_ms.avg=", "bufferbloat.loaded_ms.avg=", "bufferbloat.increase_ms=", "bufferbloat.grade="] {
+        assert!(stdout.contains(key), "{} is missing: {}", key, stdout);
+    }
+}
+
+#[tokio::test]
+async fn keeps_to_the_address_family() {
+    let (_server, url, _output) = serve().await;
+    let file: String = format!("{}/1M.bin", url);
+
+    let ipv4: Output = Command::new(BIN).args([file.as_str(), "-c", "2", "--format", "kv", "-4"]).output().await.expect("failed to run the download");
+    assert!(ipv4.status.success(), "download over IPv4 failed: {}", String::from_utf8_lossy(&ipv4.stderr));
+
+    let ipv6: Output = Command::new(BIN).args([file.as_str(), "-c", "2", "--format", "kv", "-6"]).output().await.expect("failed to run the download");
+    assert!(!ipv6.status.success(), "an IPv4 address was used with -6");
+    assert!(String::from_utf8_lossy(&ipv6.stderr).contains("is not an IPv6 address"), "unexpected error: {}", String::from_utf8_lossy(&ipv6.stderr));
+}
+
+#[tokio::test]
+async fn connects_from_the_source_address() {
+    let (_server, url, _output) = serve().await;
+    let file: String = format!("{}/1M.bin", url);
+
+    let bound: Output = Command::new(BIN).args([file.as_str(), "-c", "2", "--format", "kv", "--source-ip", "127.0.0.1"]).output().await.expect("failed to run the download");
+    assert!(bound.status.success(), "download from 127.0.0.1 failed: {}", String::from_utf8_lossy(&bound.stderr));
+
+    // A documentation address no interface here has
+    let foreign: Output = Command::new(BIN).args([file.as_str(), "-c", "2", "--format", "kv", "--source-ip", "192.0.2.1"]).output().await.expect("failed to run the download");
+    assert!(!foreign.status.success(), "an address this host doesn't have was bound");
+
+    let missing: Output = Command::new(BIN).args([file.as_str(), "-c", "2", "--format", "kv", "--interface", "nosuchif0"]).output().await.expect("failed to run the download");
+    assert!(!missing.status.success(), "a missing interface went unnoticed");
+}
+
+#[tokio::test]
+async fn shares_chunks_between_the_connections() {
+    let (_server, url, _output) = serve().await;
+
+    let output: Output = Command::new(BIN)
+        .args([format!("{}/4M.bin", url).as_str(), "-c", "2", "--format", "kv", "--chunk-size", "524288"])
+        .output()
+        .await
+        .expect("failed to run the download");
+    let stdout: String = String::from_utf8_lossy(&output.stdout).to_string();
+    assert!(output.status.success(), "download failed: {}", String::from_utf8_lossy(&output.stderr));
+    for key in ["total_bytes=4194304 ", "workers=2 ", "requests=8 ", "connections=2 "] {
+        assert!(stdout.contains(key), "{} is missing: {}", key, stdout);
+    }
+}
+
+#[tokio::test]
+async fn rates_the_fairness_of_connections_rather_than_chunks() {
+    let (_server, url, _output) = serve().await;
+
+    let output: Output = Command::new(BIN)
+        .args([format!("{}/16M.bin", url).as_str(), "-c", "4", "--format", "kv", "--chunk-size", "1048576", "--per-connection"])
+        .output()
+        .await
+        .expect("failed to run the download");
+    let stdout: String = String::from_utf8_lossy(&output.stdout).to_string();
+    assert!(output.status.success(), "download failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(field(&stdout, "workers"), Some(4), "unexpected summary: {}", stdout);
+    // One stream per connection, sharing out the 16 chunks between them
+    let chunks: Vec<u64> = (0..4).map(|i| field(&stdout, &format!("streams.{}.chunks", i)).unwrap_or(0)).collect();
+    assert_eq!(chunks.iter().sum::<u64>(), 16, "unexpected streams: {}", stdout);
+    assert_eq!(field::<u64>(&stdout, "streams.4.chunks"), None, "a stream per chunk: {}", stdout);
+    // Connections to the same loopback server get about the same share
+    let fairness: f64 = field(&stdout, "fairness").unwrap_or_else(|| panic!("no fairness: {}", stdout));
+    assert!(fairness > 0.8, "unfair connections: {}", stdout);
+}
+
+/*
+The requests each connection to a slow server answered, and which connection
+was the slow one
+*/
+#[derive(Default)]
+struct SlowServer {
+    requests: Vec<usize>,
+    slow: Option<usize>,
+}
+
+/*
+Serve a file of `length` zero bytes, making the connection that is asked for
+the first chunk of the file answer it and every later request only after `delay`
+*/
+async fn serve_slow_connection(length: u64, delay: Duration) -> (SocketAddr, Arc<Mutex<SlowServer>>) {
+    let server: Arc<Mutex<SlowServer>> = Arc::new(Mutex::new(SlowServer::default()));
+    let counts: Arc<Mutex<SlowServer>> = server.clone();
+    let make_service = make_service_fn(move |_| {
+        let server: Arc<Mutex<SlowServer>> = server.clone();
+        let connection: usize = {
+            let mut server = server.lock().unwrap();
+            server.requests.push(0);
+            server.requests.len() - 1
+        };
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                let part: Part = Part::of(&request, length);
+                let slow: bool = {
+                    let mut server = server.lock().unwrap();
+                    server.requests[connection] += 1;
+                    // Not the probe's one-byte range check, but the first chunk itself
+                    if server.slow.is_none() && part.ranged && part.start == 0 && part.len() > 1 {
+                        server.slow = Some(connection);
+                    }
+                    server.slow == Some(connection)
+                };
+                async move {
+                    if slow {
+                        tokio::time::sleep(delay).await;
+                    }
+                    Ok::<_, Infallible>(part.response(length).body(Body::from(vec![0u8; part.len() as usize])).unwrap())
+                }
+            }))
+        }
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+    let addr: SocketAddr = server.local_addr();
+    tokio::spawn(server);
+    (addr, counts)
+}
+
+#[tokio::test]
+async fn hands_the_chunks_of_a_slow_connection_to_fast_ones() {
+    let (addr, server) = serve_slow_connection(8 * 1024 * 1024, Duration::from_millis(500)).await;
+
+    let output: Output = Command::new(BIN)
+        .args([format!("http://{}/file.bin", addr).as_str(), "-c", "4", "--format", "kv", "--chunk-size", "262144", "--per-connection"])
+        .output()
+        .await
+        .expect("failed to run the download");
+    let stdout: String = String::from_utf8_lossy(&output.stdout).to_string();
+    assert!(output.status.success(), "download failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(field(&stdout, "workers"), Some(4), "unexpected summary: {}", stdout);
+    assert_eq!(field(&stdout, "total_bytes"), Some(8 * 1024 * 1024), "unexpected summary: {}", stdout);
+
+    // The slow connection answered a request or two while the others shared out
+    // the rest of the 32 chunks
+    let server = server.lock().unwrap();
+    let slow: usize = server.slow.expect("no chunk was requested");
+    let busiest: usize = server.requests.iter().enumerate().filter(|(i, _)| *i != slow).map(|(_, n)| *n).max().unwrap_or(0);
+    assert!(server.requests[slow] < busiest, "the slow connection kept its share: {:?}", server.requests);
+    let chunks: Vec<u64> = (0..4).map(|i| field(&stdout, &format!("streams.{}.chunks", i)).unwrap_or(0)).collect();
+    assert_eq!(chunks.iter().sum::<u64>(), 32, "unexpected streams: {}", stdout);
+    assert!(chunks.iter().min() < chunks.iter().max(), "the chunks were split evenly: {}", stdout);
+}
+
+#[tokio::test]
+async fn writes_the_csv_columns_in_header_order() {
+    let (_server, url, _output) = serve().await;
+    let csv: std::path::PathBuf = std::env::temp_dir().join(format!("httpbandwidthspeedtester-samples-{}.csv", std::process::id()));
+    let _ = std::fs::remove_file(&csv);
+
+    let output: Output = Command::new(BIN)
+        .args([format!("{}/4M.bin", url).as_str(), "-c", "2", "--sample-interval", "100", "--limit-rate", "8M", "--quiet", "--csv", csv.to_str().unwrap()])
+        .output()
+        .await
+        .expect("failed to run the download");
+    let text: String = std::fs::read_to_string(&csv).unwrap_or_default();
+    std::fs::remove_file(&csv).ok();
+    assert!(output.status.success(), "download failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let mut lines = text.lines();
+    assert_eq!(lines.next(), Some("elapsed,timestamp,bytes,bps,avg_bps,connections"));
+    let rows: Vec<Vec<&str>> = lines.map(|line| line.split(',').collect()).collect();
+    assert!(rows.len() > 2, "too few samples: {}", text);
+    for row in rows {
+        assert_eq!(row.len(), 6, "unexpected row: {:?}", row);
+        assert!(row[0].parse::<f64>().is_ok() && chrono::DateTime::parse_from_rfc3339(row[1]).is_ok(), "unexpected row: {:?}", row);
+    }
+}
+
+#[tokio::test]
+async fn saves_the_download() {
+    let (_server, url, _output) = serve().await;
+    let file: String = format!("{}/4M.bin", url);
+    let dir: std::path::PathBuf = std::env::temp_dir().join(format!("httpbandwidthspeedtester-output-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (ranged, whole): (std::path::PathBuf, std::path::PathBuf) = (dir.join("ranged.bin"), dir.join("whole.bin"));
+
+    // Ranges finishing in any order still land where they belong, so the file
+    // matches one fetched in a single plain GET
+    let split: Output = Command::new(BIN)
+        .args([file.as_str(), "-c", "4", "--shuffle-ranges", "--format", "kv", "--output-file", ranged.to_str().unwrap()])
+        .output()
+        .await
+        .expect("failed to run the download");
+    assert!(split.status.success(), "download failed: {}", String::from_utf8_lossy(&split.stderr));
+    let single: Output = Command::new(BIN)
+        .args([file.as_str(), "--no-range", "--format", "kv", "--output-file", whole.to_str().unwrap()])
+        .output()
+        .await
+        .expect("failed to run the download");
+    assert!(single.status.success(), "download failed: {}", String::from_utf8_lossy(&single.stderr));
+    assert_eq!(field(&String::from_utf8_lossy(&split.stdout), "range_requests"), Some(true));
+    assert_eq!(field(&String::from_utf8_lossy(&single.stdout), "range_requests"), Some(false), "--no-range used ranges");
+
+    let (ranged, whole): (Vec<u8>, Vec<u8>) = (std::fs::read(&ranged).unwrap(), std::fs::read(&whole).unwrap());
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(ranged.len(), 4 * 1024 * 1024);
+    assert!(ranged == whole, "the ranged download was saved out of order");
+}
+
+#[tokio::test]
+async fn reports_only_a_chosen_seed() {
+    let (_server, url, _output) = serve().await;
+    let file: String = format!("{}/1M.bin", url);
+    for (seed, reported) in [(None, true), (Some("7"), false)] {
+        let mut args: Vec<&str> = vec![file.as_str(), "-c", "2", "--shuffle-ranges", "--format", "kv"];
+        args.extend(seed.iter().flat_map(|seed| ["--seed", seed]));
+        let output: Output = Command::new(BIN).args(&args).output().await.expect("failed to run the download");
+        assert!(output.status.success(), "download failed: {}", String::from_utf8_lossy(&output.stderr));
+        let stdout: String = String::from_utf8_lossy(&output.stdout).to_string();
+        assert_eq!(stdout.contains("Using random seed"), reported, "unexpected seed report with --seed {:?}: {}", seed, stdout);
+    }
+}
+
+#[tokio::test]
+async fn picks_the_closest_provider_server() {
+    let (_server, url, _output) = serve().await;
+    let list: std::path::PathBuf = std::env::temp_dir().join(format!("httpbandwidthspeedtester-servers-{}.json", std::process::id()));
+    // Nothing listens on port 1, so that server never answers
+    std::fs::write(&list, format!(r#"[{{"name": "gone", "url": "http://127.0.0.1:1/1M.bin"}}, {{"name": "local", "url": "{}/1M.bin"}}]"#, url)).unwrap();
+
+    let output: Output = Command::new(BIN)
+        .args(["--provider", list.to_str().unwrap(), "-c", "2", "--format", "kv"])
+        .output()
+        .await
+        .expect("failed to run the download");
+    std::fs::remove_file(&list).unwrap();
+    let stdout: String = String::from_utf8_lossy(&output.stdout).to_string();
+    assert!(output.status.success(), "download failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains("server local ("), "the live server wasn't picked: {}", stdout);
+    assert!(stdout.contains("total_bytes=1048576 "), "unexpected summary: {}", stdout);
+}
+
+#[tokio::test]
+async fn reads_options_from_a_config_file() {
+    let (_server, url, _output) = serve().await;
+    let path: std::path::PathBuf = std::env::temp_dir().join(format!("httpbandwidthspeedtester-config-{}.toml", std::process::id()));
+    std::fs::write(&path, format!("# Every option by its long name\nurl = \"{}/1M.bin\"\nconnections = 3\nformat = \"kv\"\nshuffle_ranges = true\n\n[headers]\nX-Test = \"from the file\"\n", url)).unwrap();
+
+    let from_file: Output = Command::new(BIN).args(["--config", path.to_str().unwrap()]).output().await.expect("failed to run the download");
+    let stdout: String = String::from_utf8_lossy(&from_file.stdout).to_string();
+    assert!(from_file.status.success(), "download failed: {}", String::from_utf8_lossy(&from_file.stderr));
+    assert!(stdout.contains("workers=3 ") && stdout.contains("total_bytes=1048576 "), "the file's options weren't used: {}", stdout);
+
+    // The command line wins over the file, URLs included
+    let overridden: Output = Command::new(BIN)
+        .args(["--config", path.to_str().unwrap(), format!("{}/2M.bin", url).as_str(), "-c", "2"])
+        .output()
+        .await
+        .expect("failed to run the download");
+    std::fs::remove_file(&path).unwrap();
+    let stdout: String = String::from_utf8_lossy(&overridden.stdout).to_string();
+    assert!(overridden.status.success(), "download failed: {}", String::from_utf8_lossy(&overridden.stderr));
+    assert!(stdout.contains("workers=2 ") && stdout.contains("total_bytes=2097152 "), "the command line didn't win: {}", stdout);
+}
+
+#[tokio::test]
+async fn fails_the_threshold_assertions() {
+    let (_server, url, _output) = serve().await;
+    let file: String = format!("{}/1M.bin", url);
+
+    let passed: Output = Command::new(BIN)
+        .args([file.as_str(), "-c", "2", "--format", "kv", "--min-speed", "1Mbps", "--max-latency", "10s"])
+        .output()
+        .await
+        .expect("failed to run the download");
+    assert!(passed.status.success(), "the assertions failed: {}", String::from_utf8_lossy(&passed.stderr));
+
+    let failed: Output = Command::new(BIN)
+        .args([file.as_str(), "-c", "2", "--format", "kv", "--min-speed", "1000000Gbps"])
+        .output()
+        .await
+        .expect("failed to run the download");
+    assert_eq!(failed.status.code(), Some(6), "unexpected exit: {}", String::from_utf8_lossy(&failed.stderr));
+    assert!(String::from_utf8_lossy(&failed.stderr).contains("is below --min-speed"), "unexpected error: {}", String::from_utf8_lossy(&failed.stderr));
+}
+
+#[tokio::test]
+async fn averages_no_faster_than_the_peak_on_a_short_run() {
+    let (_server, url, _output) = serve().await;
+    for args in [&["-c", "1"][..], &["-c", "4"], &["-c", "4", "--sample-interval", "100"]] {
+        let output: Output = Command::new(BIN)
+            .args([format!("{}/4M.bin", url).as_str(), "--format", "kv"])
+            .args(args)
+            .output()
+            .await
+            .expect("failed to run the download");
+        assert!(output.status.success(), "the download failed: {}", String::from_utf8_lossy(&output.stderr));
+        let stdout: String = String::from_utf8_lossy(&output.stdout).to_string();
+        let (avg, peak): (u64, u64) = (field(&stdout, "avg_bps").unwrap_or(u64::MAX), field(&stdout, "peak_bps").unwrap_or(0));
+        assert!(avg <= peak, "the average is above the peak with {:?}: {}", args, stdout);
+    }
+}
+
+#[tokio::test]
+async fn reports_speeds_in_the_chosen_units() {
+    let (_server, url, _output) = serve().await;
+    let output: Output = Command::new(BIN)
+        .args([format!("{}/1M.bin", url).as_str(), "-c", "2", "--units", "bits"])
+        .output()
+        .await
+        .expect("failed to run the download");
+    assert!(output.status.success(), "the download failed: {}", String::from_utf8_lossy(&output.stderr));
+    let stdout: String = String::from_utf8_lossy(&output.stdout).to_string();
+    let average: &str = stdout.lines().find(|line| line.starts_with("| Average speed")).unwrap_or_else(|| panic!("no average speed: {}", stdout));
+    assert!(average.contains("bit/s"), "the average speed is not in bits: {}", average);
+    assert!(!stdout.contains("B/s"), "a speed is in bytes: {}", stdout);
+}
+
+#[tokio::test]
+async fn shows_the_current_speed_beside_the_average() {
+    let (_server, url, _output) = serve().await;
+    let output: Output = Command::new(BIN)
+        .args([format!("{}/4M.bin", url).as_str(), "-c", "2", "--sample-interval", "100", "--limit-rate", "8M"])
+        .output()
+        .await
+        .expect("failed to run the download");
+    assert!(output.status.success(), "the download failed: {}", String::from_utf8_lossy(&output.stderr));
+    let stdout: String = String::from_utf8_lossy(&output.stdout).to_string();
+    assert!(stdout.lines().any(|line| line.contains("] Speed: ") && line.contains(" now, ") && line.contains(" average, ETA ")),
+        "no sample line with both speeds: {}", stdout);
+    for row in ["| Min speed", "| Peak speed"] {
+        let line: &str = stdout.lines().find(|line| line.starts_with(row)).unwrap_or_else(|| panic!("no {} row: {}", row, stdout));
+        assert!(line.contains(" at ") && line.contains(" s "), "{} doesn't say when: {}", row, line);
+    }
+}
+
+/*
+Serve a file whose downloads only start answering after a delay
+*/
+async fn serve_late_file(length: u64, delay: Duration) -> SocketAddr {
+    let make_service = make_service_fn(move |_| async move {
+        Ok::<_, Infallible>(service_fn(move |request: Request<Body>| async move {
+            let part: Part = Part::of(&request, length);
+            if part.len() > 1 {
+                tokio::time::sleep(delay).await;
+            }
+            Ok::<_, Infallible>(part.response(length).body(Body::from(vec![0u8; part.len() as usize])).unwrap())
+        }))
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+    let addr: SocketAddr = server.local_addr();
+    tokio::spawn(server);
+    addr
+}
+
+#[tokio::test]
+async fn has_no_average_before_the_first_data() {
+    let addr: SocketAddr = serve_late_file(1024 * 1024, Duration::from_millis(600)).await;
+    let output: Output = Command::new(BIN)
+        .args([format!("http://{}/file.bin", addr).as_str(), "-c", "2", "--sample-interval", "100", "--limit-rate", "2M", "--json-stream"])
+        .output()
+        .await
+        .expect("failed to run the download");
+    assert!(output.status.success(), "the download failed: {}", String::from_utf8_lossy(&output.stderr));
+
+    let samples: Vec<serde_json::Value> = String::from_utf8_lossy(&output.stdout).lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .filter(|record| record["type"] == "sample")
+        .collect();
+    let waiting: Vec<&serde_json::Value> = samples.iter().take_while(|sample| sample["cumulative"] == 0).collect();
+    assert!(!waiting.is_empty(), "no sample before the data arrived: {:?}", samples);
+    assert!(waiting.iter().all(|sample| sample["avg_bps"].is_null()), "an average before any data: {:?}", waiting);
+    assert!(samples.iter().any(|sample| sample["avg_bps"].as_u64().is_some_and(|bps| bps > 0)), "no average once data arrived: {:?}", samples);
+}
+
+#[tokio::test]
+async fn ramps_up_to_the_connection_count() {
+    let (_server, url, _output) = serve().await;
+    let output: Output = Command::new(BIN)
+        .args([format!("{}/16M.bin", url).as_str(), "-c", "3", "--ramp", "0.3"])
+        .output()
+        .await
+        .expect("failed to run the ramp");
+    assert!(output.status.success(), "the ramp failed: {}", String::from_utf8_lossy(&output.stderr));
+    let stdout: String = String::from_utf8_lossy(&output.stdout).to_string();
+    let levels: Vec<&str> = stdout.lines().skip_while(|line| *line != "connections,bps").skip(1)
+        .filter(|line| !line.starts_with('#'))
+        .map(|line| line.split(',').next().unwrap_or_default())
+        .collect();
+    assert_eq!(levels, ["1", "2", "3"], "unexpected levels: {}", stdout);
+    assert!(stdout.contains("# knee: "), "no knee: {}", stdout);
+}
+
+#[tokio::test]
+async fn logs_each_connection_or_nothing_but_the_summary() {
+    let (_server, url, _output) = serve().await;
+    let file: String = format!("{}/1M.bin", url);
+
+    let verbose: Output = Command::new(BIN)
+        .args([file.as_str(), "-c", "2", "-v"])
+        .output()
+        .await
+        .expect("failed to run the download");
+    assert!(verbose.status.success(), "the download failed: {}", String::from_utf8_lossy(&verbose.stderr));
+    let stderr: String = String::from_utf8_lossy(&verbose.stderr).to_string();
+    for stream in ["download{stream=0 range=bytes=0-524287}", "download{stream=1 range=bytes=524288-}"] {
+        assert!(stderr.contains(&format!("{}: First byte after", stream)), "no first byte for {}: {}", stream, stderr);
+        assert!(stderr.contains(&format!("{}: Finished with 524288 bytes", stream)), "no completion for {}: {}", stream, stderr);
+    }
+    assert!(!stderr.contains("Received "), "-v logged the chunks: {}", stderr);
+
+    let quiet: Output = Command::new(BIN)
+        .args([file.as_str(), "-c", "2", "--quiet"])
+        .output()
+        .await
+        .expect("failed to run the download");
+    assert!(quiet.status.success(), "the download failed: {}", String::from_utf8_lossy(&quiet.stderr));
+    let stdout: String = String::from_utf8_lossy(&quiet.stdout).to_string();
+    assert!(stdout.starts_with("+-"), "something came before the summary: {}", stdout);
+    assert!(quiet.stderr.is_empty(), "--quiet printed to stderr: {}", String::from_utf8_lossy(&quiet.stderr));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn reports_what_arrived_before_an_interrupt() {
+    let (_server, url, _output) = serve().await;
+    let mut download: Child = Command::new(BIN)
+        .args([format!("{}/1G.bin", url).as_str(), "-c", "2", "--limit-rate", "10MB/s", "--sample-interval", "100"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .expect("failed to run the download");
+    let stdout = download.stdout.take().expect("stdout is piped");
+    let mut lines: Lines<BufReader<ChildStdout>> = BufReader::new(stdout).lines();
+    // Interrupt once the samples show data arriving
+    loop {
+        let line: String = lines.next_line().await
+            .expect("failed to read from the download")
+            .expect("the download ended before it was interrupted");
+        if line.contains(" average, ETA") && !line.contains("Speed: 0 B/s now") {
+            break;
+        }
+    }
+    let pid: String = download.id().expect("the download is running").to_string();
+    let killed: Output = Command::new("kill").args(["-INT", pid.as_str()]).output().await.expect("failed to run kill");
+    assert!(killed.status.success(), "kill failed: {}", String::from_utf8_lossy(&killed.stderr));
+
+    let mut summary: Vec<String> = Vec::new();
+    while let Some(line) = lines.next_line().await.expect("failed to read from the download") {
+        summary.push(line);
+    }
+    let status = download.wait().await.expect("failed to wait for the download");
+    assert_eq!(status.code(), Some(130), "unexpected exit: {}", summary.join("\n"));
+    let row = |name: &str| summary.iter().find(|line| line.starts_with(&format!("| {} ", name))).cloned().unwrap_or_default();
+    assert!(row("Interrupted by").contains("SIGINT"), "the summary isn't marked as interrupted: {}", summary.join("\n"));
+    let total: u64 = row("Total bytes").trim_matches(|c: char| !c.is_ascii_digit()).parse().unwrap_or(0);
+    assert!(total > 0 && total < 1024 * 1024 * 1024, "unexpected byte count: {}", summary.join("\n"));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn stops_quietly_once_stdout_is_closed() {
+    let (_server, url, _output) = serve().await;
+    let mut download: Child = Command::new(BIN)
+        .args([format!("{}/1G.bin", url).as_str(), "-c", "2", "--limit-rate", "10MB/s", "--sample-interval", "100"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .expect("failed to run the download");
+    let stdout = download.stdout.take().expect("stdout is piped");
+    let mut lines: Lines<BufReader<ChildStdout>> = BufReader::new(stdout).lines();
+    lines.next_line().await.expect("failed to read from the download").expect("the download printed nothing");
+    // As `| head -1` would
+    drop(lines);
+
+    let output: Output = download.wait_with_output().await.expect("failed to wait for the download");
+    assert_eq!(output.status.code(), Some(141), "unexpected exit: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(output.stderr.is_empty(), "an error was printed: {}", String::from_utf8_lossy(&output.stderr));
+}
+
+#[tokio::test]
+async fn uploads_while_downloading() {
+    let (_server, url, _output) = serve().await;
+
+    let output: Output = Command::new(BIN)
+        .args([format!("{}/8M.bin", url).as_str(), "-c", "2", "--format", "kv", "--duplex", "--upload-size", "1000000"])
+        .output()
+        .await
+        .expect("failed to run the duplex test");
+    let stdout: String = String::from_utf8_lossy(&output.stdout).to_string();
+    assert!(output.status.success(), "duplex test failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(field(&stdout, "total_bytes"), Some(8 * 1024 * 1024), "unexpected summary: {}", stdout);
+
+    let uploaded: u64 = field(&stdout, "duplex.upload_bytes").unwrap_or_else(|| panic!("no upload in the summary: {}", stdout));
+    let upload_bps: u64 = field(&stdout, "duplex.upload_avg_bps").unwrap_or_default();
+    let download_bps: u64 = field(&stdout, "duplex.download_avg_bps").unwrap_or_default();
+    assert!(uploaded > 0 && upload_bps > 0, "nothing was uploaded: {}", stdout);
+    assert_eq!(field(&stdout, "duplex.combined_bps"), Some(upload_bps + download_bps), "unexpected combined speed: {}", stdout);
+}
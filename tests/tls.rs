@@ -0,0 +1,81 @@
+mod common;
+
+use common::{Part, BIN};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Request};
+use native_tls::Identity;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::process::Output;
+use tokio::net::TcpListener;
+use tokio_native_tls::TlsAcceptor;
+
+const FILE_BYTES: u64 = 1024 * 1024;
+
+// An unrelated CA followed by the one that signed the server's certificate
+const CA_BUNDLE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/ca-bundle.pem");
+
+/*
+Serve a file over TLS with the fixture certificate for 127.0.0.1
+*/
+async fn serve_tls() -> SocketAddr {
+    let identity: Identity = Identity::from_pkcs8(include_bytes!("fixtures/server.pem"), include_bytes!("fixtures/server.key"))
+        .expect("the fixture identity loads");
+    let acceptor: TlsAcceptor = native_tls::TlsAcceptor::new(identity).expect("the fixture identity is usable").into();
+    let listener: TcpListener = TcpListener::bind("127.0.0.1:0").await.expect("failed to bind");
+    let addr: SocketAddr = listener.local_addr().expect("the listener has an address");
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let acceptor: TlsAcceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let Ok(stream) = acceptor.accept(stream).await else {
+                    return;
+                };
+                let service = service_fn(|request: Request<Body>| async move {
+                    let part: Part = Part::of(&request, FILE_BYTES);
+                    Ok::<_, Infallible>(part.response(FILE_BYTES).body(Body::from(vec![0u8; part.len() as usize])).unwrap())
+                });
+                let _ = Http::new().serve_connection(stream, service).await;
+            });
+        }
+    });
+    addr
+}
+
+async fn run_against(addr: SocketAddr, args: &[&str]) -> Output {
+    let url: String = format!("https://{}/file.bin", addr);
+    tokio::process::Command::new(BIN)
+        .args([url.as_str(), "-c", "2", "--format", "kv"])
+        .args(args)
+        .output()
+        .await
+        .expect("failed to run the speed tester")
+}
+
+#[tokio::test]
+async fn trusts_every_certificate_of_a_cacert_bundle() {
+    let addr: SocketAddr = serve_tls().await;
+    let output: Output = run_against(addr, &["--cacert", CA_BUNDLE]).await;
+    let stdout: String = String::from_utf8_lossy(&output.stdout).to_string();
+
+    assert!(output.status.success(), "run failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(common::field::<u64>(&stdout, "total_bytes"), Some(FILE_BYTES), "unexpected summary: {}", stdout);
+}
+
+#[tokio::test]
+async fn rejects_the_server_without_its_ca() {
+    let addr: SocketAddr = serve_tls().await;
+    let output: Output = run_against(addr, &[]).await;
+
+    assert!(!output.status.success(), "the self-signed chain was trusted");
+}
+
+#[tokio::test]
+async fn refuses_a_cacert_with_no_certificate() {
+    let output: Output = run_against(serve_tls().await, &["--cacert", concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/server.key")]).await;
+    let stderr: String = String::from_utf8_lossy(&output.stderr).to_string();
+
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr.contains("holds no PEM certificate"), "unexpected error: {}", stderr);
+}
@@ -0,0 +1,71 @@
+mod common;
+
+use common::{Part, BIN};
+use hyper::header::ETAG;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Server};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::process::Output;
+
+const FILE_BYTES: u64 = 1024 * 1024;
+// The digests of FILE_BYTES zero bytes
+const MD5_ETAG: &str = "\"b6d81b360a5672d80c27430f39153e2c\"";
+const SHA256: &str = "30e14955ebf1352266dc2ff8067e68104607e750abb9d3b36582b8af909fcb58";
+
+/*
+Serve a file of zeros that honors range requests, with the given ETag, and
+with one byte in the second half flipped when `corrupt` is set
+*/
+async fn serve(etag: &'static str, corrupt: bool) -> SocketAddr {
+    let make_service = make_service_fn(move |_| async move {
+        Ok::<_, Infallible>(service_fn(move |request: Request<Body>| async move {
+            let part: Part = Part::of(&request, FILE_BYTES);
+            let mut body: Vec<u8> = vec![0u8; part.len() as usize];
+            let flipped: u64 = FILE_BYTES * 3 / 4;
+            if corrupt && (part.start..=part.end).contains(&flipped) {
+                body[(flipped - part.start) as usize] = 1;
+            }
+            Ok::<_, Infallible>(part.response(FILE_BYTES).header(ETAG, etag).body(Body::from(body)).unwrap())
+        }))
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+    let addr: SocketAddr = server.local_addr();
+    tokio::spawn(server);
+    addr
+}
+
+async fn run(addr: SocketAddr, args: &[&str]) -> Output {
+    tokio::process::Command::new(BIN)
+        .args([format!("http://{}/file.bin", addr).as_str(), "-c", "4", "--format", "kv"])
+        .args(args)
+        .output()
+        .await
+        .expect("failed to run the speed tester")
+}
+
+#[tokio::test]
+async fn verifies_against_the_etag() {
+    let intact: Output = run(serve(MD5_ETAG, false).await, &["--verify-etag"]).await;
+    assert!(intact.status.success(), "verification failed: {}", String::from_utf8_lossy(&intact.stderr));
+    assert!(String::from_utf8_lossy(&intact.stdout).contains("checksum_ok=true"), "unexpected summary: {}", String::from_utf8_lossy(&intact.stdout));
+
+    let corrupt: Output = run(serve(MD5_ETAG, true).await, &["--verify-etag"]).await;
+    assert!(!corrupt.status.success(), "the corrupt byte went unnoticed");
+    assert!(String::from_utf8_lossy(&corrupt.stderr).contains("md5 checksum mismatch"), "unexpected error: {}", String::from_utf8_lossy(&corrupt.stderr));
+
+    let multipart: Output = run(serve("\"9b2cf535f27731c974343645a3985328-3\"", false).await, &["--verify-etag"]).await;
+    assert!(!multipart.status.success(), "an ETag that is no digest was accepted");
+    assert!(String::from_utf8_lossy(&multipart.stderr).contains("not an MD5 or SHA-256 digest"), "unexpected error: {}", String::from_utf8_lossy(&multipart.stderr));
+}
+
+#[tokio::test]
+async fn verifies_against_a_given_digest() {
+    let spec: String = format!("sha256={}", SHA256);
+    let intact: Output = run(serve("\"v1\"", false).await, &["--verify", &spec]).await;
+    assert!(intact.status.success(), "verification failed: {}", String::from_utf8_lossy(&intact.stderr));
+
+    let corrupt: Output = run(serve("\"v1\"", true).await, &["--verify", &spec]).await;
+    assert!(!corrupt.status.success(), "the corrupt byte went unnoticed");
+    assert!(String::from_utf8_lossy(&corrupt.stderr).contains("sha256 checksum mismatch"), "unexpected error: {}", String::from_utf8_lossy(&corrupt.stderr));
+}